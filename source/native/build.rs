@@ -12,6 +12,7 @@ use {
             helpers::{
                 expr_and,
                 expr_field_eq,
+                expr_field_gte,
                 expr_field_lte,
                 field_param,
                 set_field,
@@ -58,6 +59,33 @@ fn main() {
 
     // Unencrypted
     {
+        // Only used for migrations
+        let mut version0 = Version::default();
+        {
+            let table = version0.table("zQLEK3CT0", "config");
+            let unique = table.field(&mut version0, "zBI7DOV9J", "unique", field_i32().build());
+            table.field(
+                &mut version0,
+                "zLQI9HQUQ",
+                "data",
+                field_str().custom("passworth_native::config::UnlockConfig").build(),
+            );
+            table.constraint(
+                &mut version0,
+                "zA3Q776DD",
+                "config_unique",
+                ConstraintType::PrimaryKey(PrimaryKeyDef { fields: vec![unique.clone()] }),
+            );
+            let table = version0.table("zDC6NTXMT", "factor_state");
+            let id = table.field(&mut version0, "zJ18G7WED", "id", field_str().build());
+            table.field(&mut version0, "zIPCVSXVU", "state", field_bytes().build());
+            table.constraint(
+                &mut version0,
+                "z7NV6BK3R",
+                "factor_id",
+                ConstraintType::PrimaryKey(PrimaryKeyDef { fields: vec![id.clone()] }),
+            );
+        }
         let mut latest_version = Version::default();
         let mut methods = vec![];
 
@@ -104,10 +132,13 @@ fn main() {
                 ConstraintType::PrimaryKey(PrimaryKeyDef { fields: vec![id.clone()] }),
             );
             methods.push(
-                new_insert(
-                    &table,
-                    vec![set_field("id", &id), set_field("token", &state)],
-                ).build_query("factor_add", QueryResCount::None),
+                new_insert(&table, vec![set_field("id", &id), set_field("token", &state)])
+                    .on_conflict(
+                        good_ormning::sqlite::query::insert::InsertConflict::DoUpdate(
+                            vec![set_field("token", &state)],
+                        ),
+                    )
+                    .build_query("factor_add", QueryResCount::None),
             );
             methods.push(
                 new_delete(&table)
@@ -123,10 +154,47 @@ fn main() {
             );
         }
 
+        // Failed unlock attempts, for backoff
+        {
+            let table = latest_version.table("zK4RW8NFA", "unlock_failures");
+            let unique = table.field(&mut latest_version, "zP0TB6XGE", "unique", field_i32().build());
+            let count = table.field(&mut latest_version, "zY7DJ2QHS", "count", field_i32().build());
+            let last = table.field(&mut latest_version, "zC9LM5VUZ", "last", field_utctime_ms().build());
+            table.constraint(
+                &mut latest_version,
+                "zW3HE1SKO",
+                "unlock_failures_unique",
+                ConstraintType::PrimaryKey(PrimaryKeyDef { fields: vec![unique.clone()] }),
+            );
+            methods.push(
+                new_insert(
+                    &table,
+                    vec![(unique.clone(), Expr::LitI32(0)), set_field("count", &count), set_field("last", &last)],
+                )
+                    .on_conflict(
+                        good_ormning::sqlite::query::insert::InsertConflict::DoUpdate(
+                            vec![set_field("count", &count), set_field("last", &last)],
+                        ),
+                    )
+                    .build_query("unlock_failures_set", QueryResCount::None),
+            );
+            methods.push(new_select(&table).where_(Expr::BinOp {
+                left: Box::new(Expr::field(&unique)),
+                op: BinOp::Equals,
+                right: Box::new(Expr::LitI32(0)),
+            }).return_field(&count).return_field(&last).build_query("unlock_failures_get", QueryResCount::MaybeOne));
+            methods.push(new_delete(&table).where_(Expr::BinOp {
+                left: Box::new(Expr::field(&unique)),
+                op: BinOp::Equals,
+                right: Box::new(Expr::LitI32(0)),
+            }).build_query("unlock_failures_clear", QueryResCount::None));
+        }
+
         // Generate
         good_ormning::sqlite::generate(&out.join("pubdb.rs"), vec![
             // Versions
-            (0usize, latest_version)
+            (0usize, version0),
+            (1usize, latest_version)
         ], methods).unwrap();
     }
 
@@ -200,4 +268,59 @@ fn main() {
             (0usize, latest_version)
         ], queries).unwrap();
     }
+
+    // Audit log, unencrypted so it can be written while locked
+    {
+        let mut latest_version = Version::default();
+        let mut queries = vec![];
+        {
+            let table = latest_version.table("zMW3C4DSP", "audit");
+            let id = table.rowid_field(&mut latest_version, None);
+            let stamp = table.field(&mut latest_version, "zH2XW0GT1", "stamp", field_utctime_ms().build());
+            let variant = table.field(&mut latest_version, "zRKJ3A8QE", "variant", field_str().build());
+            let paths = table.field(&mut latest_version, "zB5YN6U0V", "paths", field_str().build());
+            let principal = table.field(&mut latest_version, "zTF1ZQ7LC", "principal", field_str().build());
+            let rules = table.field(&mut latest_version, "z9PA2VJHX", "rules", field_str().build());
+            let prompt = table.field(&mut latest_version, "zE8GD5MKR", "prompt", field_str().build());
+            let result = table.field(&mut latest_version, "zUC46WNBY", "result", field_str().build());
+            queries.push(
+                new_insert(
+                    &table,
+                    vec![
+                        set_field("stamp", &stamp),
+                        set_field("variant", &variant),
+                        set_field("paths", &paths),
+                        set_field("principal", &principal),
+                        set_field("rules", &rules),
+                        set_field("prompt", &prompt),
+                        set_field("result", &result)
+                    ],
+                ).build_query("audit_insert", QueryResCount::None),
+            );
+            queries.push(
+                new_select(&table)
+                    .where_(expr_and(vec![
+                        //. .
+                        expr_field_gte("start", &stamp),
+                        expr_field_lte("end", &stamp)
+                    ]))
+                    .return_named("id", Expr::field(&id))
+                    .return_field(&stamp)
+                    .return_field(&variant)
+                    .return_field(&paths)
+                    .return_field(&principal)
+                    .return_field(&rules)
+                    .return_field(&prompt)
+                    .return_field(&result)
+                    .order(Expr::field(&id), Order::Asc)
+                    .build_query("audit_list", QueryResCount::Many),
+            );
+        }
+
+        // Generate
+        good_ormning::sqlite::generate(&out.join("auditdb.rs"), vec![
+            // Versions
+            (0usize, latest_version)
+        ], queries).unwrap();
+    }
 }
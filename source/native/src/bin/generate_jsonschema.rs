@@ -21,6 +21,7 @@ fn main() {
     use passworth::ipc::*;
 
     write(root.join("proto.schema.json"), serde_json::to_vec_pretty(&schema_for!(msg::Req)).unwrap()).unwrap();
+    write(root.join("proto_error.schema.json"), serde_json::to_vec_pretty(&schema_for!(RespError)).unwrap()).unwrap();
 
     macro_rules! write_resp{
         ($r: ty) => {
@@ -32,16 +33,22 @@ fn main() {
     }
 
     write_resp!(ReqLock);
+    write_resp!(ReqFactorChange);
+    write_resp!(ReqMetaStatus);
     write_resp!(ReqMetaKeys);
     write_resp!(ReqMetaRevisions);
     write_resp!(ReqMetaPgpPubkey);
     write_resp!(ReqMetaSshPubkey);
+    write_resp!(ReqMetaAudit);
     write_resp!(ReqRead);
     write_resp!(ReqWrite);
     write_resp!(ReqWriteGenerate);
     write_resp!(ReqWriteMove);
     write_resp!(ReqWriteRevert);
+    write_resp!(ReqBatch);
     write_resp!(ReqDerivePgpSign);
     write_resp!(ReqDerivePgpDecrypt);
+    write_resp!(ReqDeriveSshSign);
     write_resp!(ReqDeriveOtp);
+    write_resp!(ReqWatchNext);
 }
@@ -66,7 +66,12 @@ async fn main() {
             let resp_body = match upstream.send_req_enum(&payload).await {
                 Ok(v) => v,
                 Err(e) => serde_json::to_vec(
-                    &glove::Resp::<()>::Err(format!("Error making request upstream: {}", e)),
+                    &glove::Resp::<()>::Err(
+                        ipc::RespError::new(
+                            ipc::ErrorCode::Internal,
+                            format!("Error making request upstream: {}", e),
+                        ).to_resp(),
+                    ),
                 ).unwrap(),
             };
 
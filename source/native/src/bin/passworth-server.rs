@@ -2,16 +2,27 @@
 
 use {
     crate::serverlib::{
-        dbutil::open_privdb,
-        factor::build_factor_tree,
+        factor::{
+            build_factor_tree,
+            find_factor,
+            FactorTreeVariant,
+        },
         fg::{
             FgState,
-            B2F,
+            GtkUi,
+        },
+        fgipc::{
+            DelegatedUi,
+            UiDelegate,
         },
+        fgtty::TtyUi,
         permission::{
             self,
             build_rule_tree,
             scan_principal,
+            ExplainPrincipal,
+            ExplainRequest,
+            PrincipalMeta,
         },
     },
     aargvark::{
@@ -19,16 +30,10 @@ use {
         vark,
         Aargvark,
     },
-    chrono::Utc,
-    flowcontrol::{
-        exenum,
-        shed,
-    },
     gtk4::{
         glib::LogLevels,
         prelude::ApplicationExtManual,
     },
-    libc::c_void,
     loga::{
         conversion::ResultIgnore,
         ea,
@@ -41,16 +46,25 @@ use {
     passworth::{
         datapath::SpecificPath,
         ipc,
-        utils::to_b32,
     },
     passworth_native::{
-        config,
+        config::{
+            self,
+            latest::{
+                ConfigFrontend,
+                UnlockFactorsConfig,
+            },
+        },
         crypto::pgp_from_armor,
-        generate,
+        otp::{
+            parse_otp_url,
+            set_hotp_counter,
+            Otp,
+        },
+        tty::DEFAULT_TTY,
     },
     passworth_shared_native::proto::ipc_path,
     sequoia_openpgp::{
-        cert::CertBuilder,
         packet::{
             key::{
                 SecretParts,
@@ -74,22 +88,36 @@ use {
     },
     serde_json::json,
     serverlib::{
-        dbutil::tx,
-        factor::{
-            FactorTree,
-            FactorTreeVariant,
-        },
-        fg::{
+        audit::{
             self,
-            B2FInitialize,
-            B2FUnlock,
+            AuditDecision,
+            AuditResult,
         },
+        auditdb,
+        dbutil::{
+            bury,
+            set,
+            tx,
+            tx_res,
+        },
+        fg,
+        grants::Grants,
+        ops,
         pidfd::{
             pidfd,
             Inode,
         },
         privdb,
         pubdb,
+        sshagent,
+        state::{
+            self,
+            get_privdb,
+            State,
+            TokenState,
+        },
+        unlockconfig::apply_unlock_config,
+        watch::Watch,
     },
     std::{
         cell::RefCell,
@@ -107,7 +135,10 @@ use {
             fd::OwnedFd,
             unix::fs::PermissionsExt,
         },
-        path::PathBuf,
+        path::{
+            Path,
+            PathBuf,
+        },
         str::FromStr,
         sync::{
             Arc,
@@ -121,11 +152,18 @@ use {
             self,
             create_dir_all,
         },
-        io::unix::AsyncFdReadyGuard,
+        io::{
+            unix::AsyncFdReadyGuard,
+            Interest,
+        },
+        net::UnixStream,
         select,
+        signal::unix::{
+            signal,
+            SignalKind,
+        },
         spawn,
         sync::{
-            broadcast,
             oneshot,
             Notify,
         },
@@ -147,22 +185,64 @@ struct Args {
     debug: Option<()>,
     /// Validate config then exit.
     validate: Option<()>,
+    /// Evaluate the access rules for a principal (pid or synthetic process chain) and
+    /// paths, print a report of how each rule matched, then exit. Nothing is
+    /// unlocked and no prompts are shown. Tags aren't known when scanning a pid, so tag
+    /// clauses are reported as unknown.
+    explain: Option<AargvarkJson<ExplainRequest>>,
 }
 
-fn bury(root: &mut serde_json::Value, path: &SpecificPath, value: serde_json::Value) {
-    let mut at = root;
-    for seg in &path.0 {
-        match &at {
-            serde_json::Value::Object(_) => (),
-            _ => {
-                *at = serde_json::Value::Object(serde_json::Map::new());
-            },
+/// Re-read the config and replace the access rules. Remembered prompt decisions are
+/// forgotten since they refer to rules by position. Credentials are only
+/// re-initialized if the unlock config changed, otherwise the database stays
+/// unlocked. Other settings (data path, lock timeout, ssh agent) require a restart.
+async fn reload_config(
+    state: &State,
+    config_path: &Path,
+    applied_unlock_config: &mut UnlockFactorsConfig,
+) -> Result<(), loga::Error> {
+    let config =
+        serde_json::from_slice::<config::latest::Config>(
+            &fs::read(config_path).await.context_with("Error reading config", ea!(path = config_path.dbg_str()))?,
+        ).context("Error parsing config")?;
+    let users = unsafe {
+        UsersCache::with_all_users()
+    };
+    let rules = build_rule_tree(&users, &config.access)?;
+    if serde_json::to_value(&config.unlock_config).unwrap() != serde_json::to_value(&*applied_unlock_config).unwrap() {
+        let root_factor =
+            build_factor_tree(
+                &HashSet::new(),
+                &config.unlock_config.auth_factors.iter().map(|f| (f.id.clone(), f)).collect(),
+                &mut HashMap::new(),
+                &config.unlock_config.root_factor,
+            )?;
+        let new_token = apply_unlock_config(state, &root_factor, config.unlock_config.clone()).await?;
+        *state.root_factor.lock().unwrap() = root_factor;
+        if let Some(new_token) = new_token {
+            // Database was rekeyed; previous token no longer works
+            if state.token_state.lock().unwrap().token.replace(new_token).is_none() {
+                state.watch.unlocked();
+            }
         }
-        let o = exenum!(at, serde_json:: Value:: Object(o) => o).unwrap();
-        let next = o.entry(seg).or_insert(serde_json::Value::Null);
-        at = next;
+        *applied_unlock_config = config.unlock_config;
     }
-    *at = value;
+    *state.rules.lock().unwrap() = rules;
+    *state.grants.lock().unwrap() = Grants::default();
+    return Ok(());
+}
+
+/// The audit result and client error for a request the rules refused. A user
+/// rejecting the prompt (now or earlier, remembered) is reported separately from
+/// no rule granting access.
+fn refusal(audit: &AuditDecision) -> (AuditResult, ipc::RespError) {
+    if audit.prompt == Some(false) {
+        return (
+            AuditResult::Rejected,
+            ipc::RespError::new(ipc::ErrorCode::UserRejectedPrompt, "Rejected at prompt"),
+        );
+    }
+    return (AuditResult::Unauthorized, ipc::RespError::new(ipc::ErrorCode::Unauthorized, "Unauthorized"));
 }
 
 async fn main2() -> Result<(), loga::Error> {
@@ -197,6 +277,50 @@ async fn main2() -> Result<(), loga::Error> {
         UsersCache::with_all_users()
     };
     let rules = build_rule_tree(&users, &config.access)?;
+    if let Some(explain) = args.explain {
+        let explain = explain.value;
+        let (principal, tags_known) = match explain.principal {
+            ExplainPrincipal::Pid(pid) => {
+                let tags = Arc::new(Mutex::new(HashMap::new()));
+                let binary_hashes = Arc::new(Mutex::new(HashMap::new()));
+                (
+                    scan_principal(&log, &tags, &binary_hashes, rules.binary_sha256_walk_ancestors, pid).await?,
+                    false,
+                )
+            },
+            ExplainPrincipal::Chain(chain) => (PrincipalMeta { chain: chain }, true),
+        };
+        let mut evaluation =
+            permission::evaluate(&log, &rules.tree, &principal, &explain.paths, &mut AuditDecision::default());
+        if !tags_known {
+            // Tags are only held by the running server
+            for path in &mut evaluation.paths {
+                for rule in &mut path.rules {
+                    for clause in &mut rule.clauses {
+                        if clause.clause == "match_tag" {
+                            clause.unknown = true;
+                        }
+                    }
+                }
+            }
+        }
+        println!("{}", serde_json::to_string_pretty(&json!({
+            "principal": principal,
+            "paths": evaluation.paths,
+            "perms": evaluation.perms,
+            "prompt_rules": evaluation
+                .prompt_rules
+                .into_iter()
+                .map(|(id, (description, remember_seconds))| json!({
+                    "rule": id,
+                    "description": description,
+                    "remember_seconds": remember_seconds,
+                }))
+                .collect::<Vec<_>>(),
+        })).unwrap());
+        return Ok(());
+    }
+    let ssh_agent_config = config.ssh_agent.clone();
     let root_factor =
         build_factor_tree(
             &HashSet::new(),
@@ -207,68 +331,91 @@ async fn main2() -> Result<(), loga::Error> {
 
     // Start fg thread
     let (fg_tx, fg_rx) = tokio::sync::mpsc::channel(100);
+    let ui_delegate = Arc::new(UiDelegate::default());
     tm.critical_task("Foreground interactions", {
-        let fg_state = Arc::new(FgState {
-            log: log.fork(ea!(sys = "human")),
-            last_prompts: Mutex::new(HashMap::new()),
-        });
-        let work = spawn_blocking({
-            let fg_state = fg_state.clone();
-            let tm = RefCell::new(Some(tm.clone()));
-            let fg_rx = RefCell::new(Some(fg_rx));
-            move || {
-                let app =
-                    gtk4::Application::builder()
-                        .application_id("x.passworth")
-                        .flags(gtk4::gio::ApplicationFlags::FLAGS_NONE)
-                        .build();
-                gtk4::gio::prelude::ApplicationExt::connect_activate(&app, {
-                    let fg_state = fg_state.clone();
-                    move |app| {
-                        let app = app.clone();
-
-                        // Hack to work around `connect_activate` being `Fn` instead of `FnOnce`
+        let fg_state = Arc::new(FgState { log: log.fork(ea!(sys = "human")) });
+        let ui_delegate = ui_delegate.clone();
+        let work = match config.frontend.clone() {
+            ConfigFrontend::Gtk => spawn_blocking({
+                let fg_state = fg_state.clone();
+                let tm = RefCell::new(Some(tm.clone()));
+                let fg_rx = RefCell::new(Some(fg_rx));
+                let ui_delegate = ui_delegate.clone();
+                move || {
+                    let app =
+                        gtk4::Application::builder()
+                            .application_id("x.passworth")
+                            .flags(gtk4::gio::ApplicationFlags::FLAGS_NONE)
+                            .build();
+                    gtk4::gio::prelude::ApplicationExt::connect_activate(&app, {
                         let fg_state = fg_state.clone();
-                        let tm = tm.borrow_mut().take().unwrap();
-                        let mut fg_rx = fg_rx.borrow_mut().take().unwrap();
+                        let ui_delegate = ui_delegate.clone();
+                        move |app| {
+                            let app = app.clone();
 
-                        // Start current-thread async task in gtk thread to read queue
-                        gtk4::glib::spawn_future_local({
-                            let hold = app.hold();
-                            let work = async move {
-                                let _hold = hold;
-                                while let Some(req) = fg_rx.recv().await {
-                                    match req {
-                                        B2F::Unlock(req, resp) => {
-                                            resp
-                                                .send(fg::do_unlock(fg_state.clone(), &app, Arc::new(req)).await)
-                                                .ignore();
-                                        },
-                                        B2F::Initialize(req, resp) => {
-                                            resp
-                                                .send(fg::do_initialize(fg_state.clone(), &app, Arc::new(req)).await)
-                                                .ignore();
-                                        },
-                                        B2F::Prompt(req, resp) => {
-                                            resp
-                                                .send(fg::do_prompt(fg_state.clone(), &app, Arc::new(req)).await)
-                                                .ignore();
-                                        },
-                                    }
-                                }
-                            };
-                            async move {
-                                select!{
-                                    _ = work =>(),
-                                    _ = tm.until_terminate() =>()
+                            // Hack to work around `connect_activate` being `Fn` instead of `FnOnce`
+                            let fg_state = fg_state.clone();
+                            let tm = tm.borrow_mut().take().unwrap();
+                            let fg_rx = fg_rx.borrow_mut().take().unwrap();
+
+                            // Start current-thread async task in gtk thread to read queue
+                            gtk4::glib::spawn_future_local({
+                                let hold = app.hold();
+                                let ui = DelegatedUi {
+                                    log: fg_state.log.clone(),
+                                    delegate: ui_delegate.clone(),
+                                    inner: GtkUi {
+                                        log: fg_state.log.clone(),
+                                        app: app,
+                                    },
                                 };
-                            }
-                        });
-                    }
-                });
-                gtk4::prelude::ApplicationExtManual::run_with_args(&app, &[] as &[String]);
-            }
-        });
+                                let work = async move {
+                                    let _hold = hold;
+                                    fg::run(fg_state, ui, fg_rx).await;
+                                };
+                                async move {
+                                    select!{
+                                        _ = work =>(),
+                                        _ = tm.until_terminate() =>()
+                                    };
+                                }
+                            });
+                        }
+                    });
+                    gtk4::prelude::ApplicationExtManual::run_with_args(&app, &[] as &[String]);
+                }
+            }),
+            ConfigFrontend::Tty(tty_config) => spawn_blocking({
+                let fg_state = fg_state.clone();
+                let tm = tm.clone();
+                move || {
+                    let ui = DelegatedUi {
+                        log: fg_state.log.clone(),
+                        delegate: ui_delegate,
+                        inner: TtyUi {
+                            log: fg_state.log.clone(),
+                            path: tty_config.path.unwrap_or_else(|| PathBuf::from(DEFAULT_TTY)),
+                        },
+                    };
+
+                    // Terminal io happens on blocking threads, so a current-thread runtime is
+                    // enough
+                    let runtime = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+                        Ok(r) => r,
+                        Err(e) => {
+                            fg_state.log.log_err(loga::WARN, e.context("Error starting terminal frontend runtime"));
+                            return;
+                        },
+                    };
+                    runtime.block_on(async move {
+                        select!{
+                            _ = fg::run(fg_state, ui, fg_rx) =>(),
+                            _ = tm.until_terminate() =>()
+                        };
+                    });
+                }
+            }),
+        };
         let tm = tm.clone();
         async move {
             select!{
@@ -283,21 +430,7 @@ async fn main2() -> Result<(), loga::Error> {
     });
 
     // Prep state for everything else
-    struct TokenState {
-        token: Option<String>,
-        wait_sub: Option<broadcast::Sender<String>>,
-    }
-
-    struct State {
-        pubdb_path: PathBuf,
-        privdb_path: PathBuf,
-        root_factor: Arc<FactorTree>,
-        token_state: Mutex<TokenState>,
-        fg_tx: tokio::sync::mpsc::Sender<B2F>,
-        lock_timeout: u64,
-    }
-
-    let data_path = match args.config.source {
+    let data_path = match &args.config.source {
         aargvark::traits_impls::Source::Stdin => {
             env::current_dir().context("Couldn't determine working directory")?
         },
@@ -309,16 +442,24 @@ async fn main2() -> Result<(), loga::Error> {
         .await
         .context_with("Error creating data path", ea!(path = data_path.to_string_lossy()))?;
     let pubdb_path = data_path.join("pub.sqlite");
+    let auditdb_path = data_path.join("audit.sqlite");
     let state = Arc::new(State {
+        auditdb_path: auditdb_path.clone(),
         privdb_path: data_path.join("priv.sqlcipher"),
         pubdb_path: pubdb_path.clone(),
-        root_factor: root_factor.clone(),
+        root_factor: Mutex::new(root_factor.clone()),
+        rules: Mutex::new(rules),
         fg_tx: fg_tx,
         token_state: Mutex::new(TokenState {
             token: None,
             wait_sub: None,
         }),
+        grants: Mutex::new(Grants::default()),
+        ui_delegate: ui_delegate,
         lock_timeout: config.lock_timeout,
+        lock_at: Mutex::new(None),
+        watch: Watch::new(),
+        binary_hashes: Arc::new(Mutex::new(HashMap::new())),
     });
 
     // Initialize db, process config changes
@@ -326,231 +467,15 @@ async fn main2() -> Result<(), loga::Error> {
     pubdb::migrate(
         &mut pubdbc,
     ).context_with("Error setting up pub database", ea!(path = pubdb_path.to_string_lossy()))?;
-    shed!{
-        'priv_init_done _;
-        let mut prev_state = HashMap::new();
-        for t in pubdb::factor_list(&mut pubdbc)? {
-            prev_state.insert(t.id, t.state);
-        }
-        if let Some(previous_config) =
-            pubdb::config_get(&mut pubdbc).context("Error reading previous config for config migrations")? {
-            let previous_unlock_config = match previous_config {
-                config::UnlockConfig::V1(config) => config,
-            };
-
-            // Previous config existed
-            let prev_root_factor =
-                build_factor_tree(
-                    &HashSet::new(),
-                    &previous_unlock_config.auth_factors.iter().map(|f| (f.id.clone(), f)).collect(),
-                    &mut HashMap::new(),
-                    &previous_unlock_config.root_factor,
-                )?;
-
-            // Diff with old to check what tokens/state changed, get list of still-active
-            // factors
-            let factor_active: HashSet<String>;
-            let mut factor_token_changed = HashSet::new();
-            let mut factor_state_changed = HashSet::new();
-            {
-                let mut stack = vec![(&root_factor, Some(&prev_root_factor), true)];
-                let mut seen = HashSet::new();
-                while let Some((new, old, descending)) = stack.pop() {
-                    if !seen.insert(new.id.clone()) {
-                        continue;
-                    }
-                    let old_variant = old.map(|x| &x.variant);
-                    match &new.variant {
-                        FactorTreeVariant::And(children) => {
-                            if descending {
-                                stack.push((new, old, false));
-                                let mut old_lookup = HashMap::new();
-                                if let Some(FactorTreeVariant::Or(old_children)) = old_variant {
-                                    for old_child in old_children {
-                                        old_lookup.insert(old_child.id.clone(), old_child);
-                                    }
-                                }
-                                for child in children {
-                                    stack.push((child, old_lookup.get(&child.id).map(|x| *x), true));
-                                }
-                            } else {
-                                let mut changed = false;
-                                for child in children {
-                                    if factor_token_changed.contains(&child.id) {
-                                        changed = true;
-                                    }
-                                }
-                                if changed {
-                                    factor_token_changed.insert(new.id.clone());
-                                }
-                            }
-                        },
-                        FactorTreeVariant::Or(children) => {
-                            if descending {
-                                stack.push((new, old, false));
-                                let mut old_lookup = HashMap::new();
-                                if let Some(FactorTreeVariant::Or(old_children)) = old_variant {
-                                    for old_child in old_children {
-                                        old_lookup.insert(old_child.id.clone(), old_child);
-                                    }
-                                }
-                                for child in children {
-                                    stack.push((child, old_lookup.get(&child.id).map(|x| *x), true));
-                                }
-                            } else {
-                                if matches!(old_variant, Some(FactorTreeVariant::Or(_))) {
-                                    // nop
-                                } else {
-                                    factor_token_changed.insert(new.id.clone());
-                                }
-                                for child in children {
-                                    if factor_token_changed.contains(&child.id) {
-                                        factor_state_changed.insert(new.id.clone());
-                                        break;
-                                    }
-                                }
-                            }
-                        },
-                        FactorTreeVariant::Password => {
-                            if matches!(old_variant, Some(FactorTreeVariant::Password)) {
-                                // nop
-                            } else {
-                                factor_token_changed.insert(new.id.clone());
-                            }
-                        },
-                        FactorTreeVariant::Smartcards(config) => {
-                            let mut lookup_old_children = HashSet::new();
-                            if let Some(FactorTreeVariant::Smartcards(old_config)) = old_variant {
-                                for child in &old_config.smartcards {
-                                    lookup_old_children.insert(child.fingerprint.clone());
-                                }
-                            } else {
-                                factor_token_changed.insert(new.id.clone());
-                            }
-                            for child in &config.smartcards {
-                                if !lookup_old_children.contains(&child.fingerprint) {
-                                    factor_state_changed.insert(new.id.clone());
-                                    break;
-                                }
-                            }
-                        },
-                        FactorTreeVariant::RecoveryPhrase => {
-                            if matches!(old_variant, Some(FactorTreeVariant::RecoveryPhrase)) {
-                                // nop
-                            } else {
-                                factor_token_changed.insert(new.id.clone());
-                            }
-                        },
-                    }
-                }
-                factor_active = seen;
-            }
-
-            // Skip if nothing changed
-            let root_token_changed = factor_token_changed.contains(&root_factor.id);
-            if factor_state_changed.is_empty() && !root_token_changed {
-                break 'priv_init_done;
-            }
-            let mut remove_state = prev_state.keys().cloned().collect::<Vec<_>>();
-            remove_state.retain(|x| !factor_active.contains(x));
-
-            // Otherwise first unlock
-            let unlock_result;
-            {
-                let (resp_tx, resp_rx) = oneshot::channel();
-                state.fg_tx.send(B2F::Unlock(B2FUnlock {
-                    privdb_path: state.privdb_path.clone(),
-                    root_factor: prev_root_factor.clone(),
-                    state: prev_state.clone(),
-                }, resp_tx)).await.ignore();
-                unlock_result =
-                    resp_rx
-                        .await?
-                        .context("Error doing fg unlock")?
-                        .context("Config update unlock aborted by user")?;
-            }
-
-            // Continue with init
-            let (resp_tx, resp_rx) = oneshot::channel();
-            state.fg_tx.send(B2F::Initialize(B2FInitialize {
-                privdbc: None,
-                root_factor: root_factor.clone(),
-                tokens_changed: factor_token_changed,
-                prev_tokens: unlock_result.tokens,
-                state_changed: factor_state_changed,
-                prev_state: prev_state,
-                prev_root_factor: Some(prev_root_factor),
-            }, resp_tx)).await.ignore();
-            let init_result =
-                resp_rx
-                    .await?
-                    .context("Error doing fg initialize")?
-                    .context("Credential initialization aborted by user")?;
-
-            // Store the new config and tokens
-            tx(pubdbc, move |txn| {
-                for k in &remove_state {
-                    if !pubdb::factor_delete(txn, k)
-                        .context_with("Error removing obsolete factor data", ea!(factor = k))?
-                        .is_some() {
-                        panic!();
-                    }
-                }
-                for (k, v) in &init_result.store_state {
-                    pubdb::factor_add(txn, &k, &v).context_with("Error storing new factor data", ea!(factor = k))?;
-                }
-                pubdb::config_set(txn, &config::UnlockConfig::V1(config.unlock_config))?;
-                if root_token_changed {
-                    let Some(root_token) = init_result.root_token else {
-                        panic!();
-                    };
-                    let root_token = root_token.as_bytes();
-                    let res = unsafe {
-                        libsqlite3_sys::sqlite3_rekey(
-                            unlock_result.privdbc.handle(),
-                            root_token.as_ptr() as *const c_void,
-                            root_token.len() as i32,
-                        )
-                    };
-                    if res != 0 {
-                        return Err(loga::err_with("Sqlcipher rekey operation exited with code", ea!(code = res)));
-                    }
-                }
-                return Ok(());
-            }).await.context("Error committing new unlock credentials")?;
-        }
-        else {
-            let all_factors =
-                config.unlock_config.auth_factors.iter().map(|x| x.id.clone()).collect::<HashSet<_>>();
-            let (resp_tx, resp_rx) = oneshot::channel();
-            state.fg_tx.send(B2F::Initialize(B2FInitialize {
-                privdbc: None,
-                root_factor: root_factor.clone(),
-                tokens_changed: all_factors.clone(),
-                prev_tokens: HashMap::new(),
-                state_changed: all_factors.clone(),
-                prev_state: HashMap::new(),
-                prev_root_factor: None,
-            }, resp_tx)).await.ignore();
-            let init_result =
-                resp_rx
-                    .await?
-                    .context("Error doing fg initialize")?
-                    .context("Credential initialization aborted by user")?;
-
-            // Store the new config and tokens
-            tx(pubdbc, move |txn| {
-                for (k, v) in &init_result.store_state {
-                    pubdb::factor_add(txn, &k, &v).context_with("Error storing new factor data", ea!(factor = k))?;
-                }
-                pubdb::config_set(txn, &config::UnlockConfig::V1(config.unlock_config))?;
-                return Ok(());
-            }).await.context("Error committing new unlock credentials")?;
-        }
-    };
+    auditdb::migrate(
+        &mut rusqlite::Connection::open(&auditdb_path).unwrap(),
+    ).context_with("Error setting up audit database", ea!(path = auditdb_path.to_string_lossy()))?;
+    let mut applied_unlock_config = config.unlock_config.clone();
+    apply_unlock_config(&state, &root_factor, config.unlock_config).await?;
 
     // Start command server
     let activity = Arc::new(Notify::new());
+    let tags = Arc::new(Mutex::new(HashMap::<Inode, HashSet<String>>::new()));
     let ipc_path = ipc_path();
     let mut ipc_server = ipc::msg::Server::new(&ipc_path).await.map_err(loga::err)?;
     fs::set_permissions(&ipc_path, Permissions::from_mode(0o777))
@@ -558,75 +483,9 @@ async fn main2() -> Result<(), loga::Error> {
         .map_err(loga::err)
         .context("Error setting mode on socket")?;
     tm.critical_task("Command processing", {
-        async fn get_privdb(state: &State) -> Result<rusqlite::Connection, loga::Error> {
-            enum Invert {
-                Ready {
-                    token: String,
-                },
-                Waiting {
-                    token_rx: broadcast::Receiver<String>,
-                },
-                Missing {
-                    token_tx: broadcast::Sender<String>,
-                },
-            }
-
-            let privdbc = match {
-                let mut token_state = state.token_state.lock().unwrap();
-                match &token_state.token {
-                    Some(t) => Invert::Ready { token: t.clone() },
-                    None => {
-                        match &token_state.wait_sub {
-                            Some(s) => {
-                                let token_rx = s.subscribe();
-                                Invert::Waiting { token_rx: token_rx }
-                            },
-                            None => {
-                                let (token_tx, _) = broadcast::channel(1);
-                                token_state.wait_sub = Some(token_tx.clone());
-                                Invert::Missing { token_tx: token_tx }
-                            },
-                        }
-                    },
-                }
-            } {
-                Invert::Ready { token } => {
-                    open_privdb(&state.privdb_path, &token)?
-                },
-                Invert::Waiting { mut token_rx } => {
-                    open_privdb(&state.privdb_path, &token_rx.recv().await?)?
-                },
-                Invert::Missing { token_tx } => {
-                    // Unlock
-                    let mut pubdbc = rusqlite::Connection::open(&state.pubdb_path).unwrap();
-                    let mut factor_state = HashMap::new();
-                    for t in pubdb::factor_list(&mut pubdbc)? {
-                        factor_state.insert(t.id, t.state);
-                    }
-                    let (fg_result_tx, fg_result_rx) = oneshot::channel();
-                    state.fg_tx.send(B2F::Unlock(B2FUnlock {
-                        privdb_path: state.privdb_path.clone(),
-                        root_factor: state.root_factor.clone(),
-                        state: factor_state,
-                    }, fg_result_tx)).await.ignore();
-                    let res = fg_result_rx.await;
-
-                    // Handle result
-                    let mut token = state.token_state.lock().unwrap();
-                    token.wait_sub = None;
-                    let res = res?.context("Error during fg unlock")?.context("User closed unlock window")?;
-                    token.token = Some(res.root_token.clone());
-                    token_tx.send(res.root_token.clone()).ignore();
-                    res.privdbc
-                },
-            };
-            return Ok(privdbc);
-        }
-
         let tm = tm.clone();
         let log = log.clone();
-        let tags = Arc::new(Mutex::new(HashMap::<Inode, HashSet<String>>::new()));
-        let rules = rules.clone();
+        let tags = tags.clone();
         let state = state.clone();
         let activity = activity.clone();
         async move {
@@ -638,7 +497,6 @@ async fn main2() -> Result<(), loga::Error> {
                     }
                 };
                 let log = log.clone();
-                let rules = rules.clone();
                 let state = state.clone();
                 let activity = activity.clone();
                 let mut conn = match conn.map_err(loga::err) {
@@ -653,97 +511,21 @@ async fn main2() -> Result<(), loga::Error> {
                     match async {
                         let peer = conn.0.peer_cred()?;
                         let pid = peer.pid().context("OS didn't provide PID for peer")?;
-                        let principal = scan_principal(&log, &tags, pid).await?;
-
-                        // Helpers for command processing
-                        fn set(txn: &mut rusqlite::Transaction, pairs: Vec<(SpecificPath, serde_json::Value)>) -> Result<(), loga::Error> {
-                            let now = Utc::now();
-                            for (mut path, value) in pairs {
-                                // Clear out everything above and below that would be shaded by this
-                                if !path.0.is_empty() {
-                                    for i in 0 .. path.0.len() - 1 {
-                                        // If setting at `/a/b/c = { .. }` and a value exists at e.g. `/a = 4`
-                                        let parent_path = SpecificPath(path.0[..i].iter().cloned().collect()).to_string();
-                                        if privdb::values_get_exact(txn, &parent_path, i64::MAX)?
-                                            .filter(
-                                                |x| serde_json::from_str::<serde_json::Value>(&x.data).unwrap() !=
-                                                    serde_json::Value::Null,
-                                            )
-                                            .is_some() {
-                                            privdb::values_insert(
-                                                txn,
-                                                now,
-                                                &parent_path,
-                                                &serde_json::to_string(&serde_json::Value::Null).unwrap(),
-                                            )?;
-                                        }
-                                    }
-                                }
-                                for row in privdb::values_get(txn, &path.to_string(), i64::MAX)? {
-                                    // If setting at `/a/b/c` and a value exists at e.g. `/a/b/c/d`
-                                    privdb::values_insert(txn, now, &row.path, &serde_json::to_string(&serde_json::Value::Null).unwrap())?;
-                                }
-
-                                // Add the new data
-                                let mut stack = vec![(None as Option<String>, value, true)];
-                                while let Some((seg, at, descending)) = stack.pop() {
-                                    if descending {
-                                        if let Some(seg) = &seg {
-                                            path.0.push(seg.clone());
-                                        }
-                                        stack.push((seg, at.clone(), false));
-                                        match &at {
-                                            serde_json::Value::Object(o) => {
-                                                for (k, v) in o {
-                                                    stack.push((Some(k.to_string()), v.clone(), true));
-                                                }
-                                            },
-                                            _ => {
-                                                privdb::values_insert(
-                                                    txn,
-                                                    now,
-                                                    &path.to_string(),
-                                                    &serde_json::to_string(&at).unwrap(),
-                                                )?;
-                                            },
-                                        }
-                                    } else {
-                                        if seg.is_some() {
-                                            path.0.pop();
-                                        }
-                                    }
-                                }
-                            }
-                            return Ok(());
-                        }
-
-                        fn get(
-                            txn: &mut rusqlite::Transaction,
-                            path: &SpecificPath,
-                            at: Option<i64>,
-                        ) -> Result<serde_json::Value, loga::Error> {
-                            let mut root = serde_json::Value::Null;
-                            for row in privdb::values_get(txn, &path.to_string(), at.unwrap_or(i64::MAX))? {
-                                let data = serde_json::from_str::<serde_json::Value>(&row.data).unwrap();
-                                if data == serde_json::Value::Null {
-                                    continue;
-                                }
-                                bury(
-                                    &mut root,
-                                    &SpecificPath(
-                                        SpecificPath::from_str(&row.path).unwrap().0.split_off(path.0.len()),
-                                    ),
-                                    data,
-                                );
-                            }
-                            return Ok(root);
-                        }
+                        let hash_walk_ancestors = state.rules.lock().unwrap().binary_sha256_walk_ancestors;
+                        let principal =
+                            scan_principal(&log, &tags, &state.binary_hashes, hash_walk_ancestors, pid).await?;
+                        let mut ui_handler = None;
+                        let mut watcher = None;
+                        let mut no_unlock = false;
 
                         // Process request
                         while let Some(req) = conn.recv_req().await.map_err(loga::err)? {
                             enum AuthErr {
                                 Err(loga::Error),
                                 Unauthorized,
+                                Locked,
+                                /// Caused by the request or the stored data, reported to the client as is.
+                                Resp(ipc::RespError),
                             }
 
                             impl From<loga::Error> for AuthErr {
@@ -752,12 +534,66 @@ async fn main2() -> Result<(), loga::Error> {
                                 }
                             }
 
+                            impl From<ops::OpErr> for AuthErr {
+                                fn from(value: ops::OpErr) -> Self {
+                                    match value {
+                                        ops::OpErr::Resp(e) => return Self::Resp(e),
+                                        ops::OpErr::Err(e) => return Self::Err(e),
+                                    }
+                                }
+                            }
+
                             fn resp_unauthorized() -> Result<ipc::msg::ServerResp, AuthErr> {
                                 return Err(AuthErr::Unauthorized);
                             }
 
-                            let resp;
-                            match async {
+                            fn req_err(code: ipc::ErrorCode, message: impl ToString) -> AuthErr {
+                                return AuthErr::Resp(ipc::RespError::new(code, message));
+                            }
+
+                            /// For `map_err` when parsing a stored value.
+                            fn wrong_type<E>(message: &'static str) -> impl FnOnce(E) -> AuthErr {
+                                return move |_| req_err(ipc::ErrorCode::WrongType, message);
+                            }
+
+                            /// Secret factors from before secrets were wrapped use the secret as the token, so
+                            /// a new secret wouldn't replace it.
+                            fn legacy_token() -> AuthErr {
+                                return req_err(
+                                    ipc::ErrorCode::InvalidArgument,
+                                    "Factor predates secret wrapping, replace it (ex: change its id) instead",
+                                );
+                            }
+
+                            /// Unlocks if necessary, unless the connection asked not to.
+                            async fn req_privdb(
+                                log: &Log,
+                                state: &State,
+                                no_unlock: bool,
+                            ) -> Result<rusqlite::Connection, AuthErr> {
+                                if no_unlock {
+                                    return state::try_get_privdb(state)?.ok_or(AuthErr::Locked);
+                                }
+                                return get_privdb(log, state).await?.ok_or(AuthErr::Locked);
+                            }
+
+                            /// Resolves when the client closes the connection, for requests that wait
+                            /// indefinitely (the client doesn't send anything while waiting).
+                            async fn conn_closed(conn: &UnixStream) {
+                                match conn.ready(Interest::READABLE).await {
+                                    Ok(ready) if !ready.is_read_closed() => {
+                                        // Pipelined request, read after this one finishes
+                                        std::future::pending::<()>().await;
+                                    },
+                                    _ => { },
+                                }
+                            }
+
+                            let rules = state.rules.lock().unwrap().clone();
+                            let variant = audit::req_variant(&req);
+                            let mut audit = AuditDecision::default();
+                            let mut disconnected = false;
+                            let res = async {
                                 let resp;
                                 match req {
                                     ipc::msg::ServerReq::Tag(rr, req) => {
@@ -795,10 +631,12 @@ async fn main2() -> Result<(), loga::Error> {
                                     ipc::msg::ServerReq::Lock(rr, req) => {
                                         if !permission::permit(
                                             &log,
-                                            state.fg_tx.clone(),
+                                            &state,
+                                            variant,
                                             &rules.tree,
                                             &principal,
                                             &[SpecificPath(vec!["".to_string()])],
+                                            &mut audit,
                                         )
                                             .await?
                                             .lock {
@@ -806,28 +644,191 @@ async fn main2() -> Result<(), loga::Error> {
                                         }
                                         match req.0 {
                                             ipc::LockAction::Lock => {
-                                                state.token_state.lock().unwrap().token = None;
+                                                state::lock(&state);
                                             },
                                             ipc::LockAction::Unlock => {
-                                                get_privdb(&state).await?;
+                                                get_privdb(&log, &state).await?.ok_or(AuthErr::Locked)?;
                                                 activity.notify_one();
                                             },
                                         }
                                         resp = rr(());
                                     },
+                                    ipc::msg::ServerReq::NoUnlock(rr, _) => {
+                                        no_unlock = true;
+                                        resp = rr(());
+                                    },
+                                    ipc::msg::ServerReq::MetaStatus(rr, _) => {
+                                        if !permission::permit(
+                                            &log,
+                                            &state,
+                                            variant,
+                                            &rules.tree,
+                                            &principal,
+                                            &[SpecificPath(vec!["".to_string()])],
+                                            &mut audit,
+                                        )
+                                            .await?
+                                            .meta {
+                                            return resp_unauthorized();
+                                        }
+                                        let (locked, unlocking) = {
+                                            let token_state = state.token_state.lock().unwrap();
+                                            (token_state.token.is_none(), token_state.wait_sub.is_some())
+                                        };
+                                        let lock_timeout_seconds = if locked {
+                                            None
+                                        } else {
+                                            state
+                                                .lock_at
+                                                .lock()
+                                                .unwrap()
+                                                .map(|x| x.saturating_duration_since(Instant::now()).as_secs())
+                                        };
+                                        resp = rr(ipc::RespMetaStatus {
+                                            locked: locked,
+                                            unlocking: unlocking,
+                                            lock_timeout_seconds: lock_timeout_seconds,
+                                        });
+                                    },
+                                    ipc::msg::ServerReq::FactorChange(rr, req) => {
+                                        if !permission::permit(
+                                            &log,
+                                            &state,
+                                            variant,
+                                            &rules.tree,
+                                            &principal,
+                                            &[SpecificPath(vec!["".to_string()])],
+                                            &mut audit,
+                                        )
+                                            .await?
+                                            .lock {
+                                            return resp_unauthorized();
+                                        }
+                                        let root_factor = state.root_factor.lock().unwrap().clone();
+                                        let Some(factor) = find_factor(&root_factor, &req.factor) else {
+                                            return Err(
+                                                req_err(ipc::ErrorCode::NotFound, "No factor with this id is in use"),
+                                            );
+                                        };
+                                        let mut pubdbc =
+                                            rusqlite::Connection::open(
+                                                &state.pubdb_path,
+                                            ).context("Error opening pub database")?;
+                                        let mut factor_state = HashMap::new();
+                                        for t in pubdb::factor_list(&mut pubdbc)? {
+                                            factor_state.insert(t.id, t.state);
+                                        }
+                                        let smartcards_state = || {
+                                            return serde_json::from_slice::<HashMap<String, Vec<u8>>>(
+                                                factor_state
+                                                    .get(&factor.id)
+                                                    .context("State for factor missing in database")?,
+                                            ).context("Error parsing stored factor state");
+                                        };
+                                        match (&factor.variant, &req.change) {
+                                            (FactorTreeVariant::Password, ipc::FactorChange::Password) |
+                                            (
+                                                FactorTreeVariant::RecoveryPhrase,
+                                                ipc::FactorChange::RecoveryPhrase,
+                                            ) => {
+                                                // Factors from before secrets were wrapped use the secret as the
+                                                // token, see below
+                                                if !factor_state.contains_key(&factor.id) {
+                                                    return Err(legacy_token());
+                                                }
+                                            },
+                                            (
+                                                FactorTreeVariant::Smartcards(_),
+                                                ipc::FactorChange::AddSmartcard(fingerprint),
+                                            ) => {
+                                                if smartcards_state()?.contains_key(fingerprint) {
+                                                    return Err(
+                                                        req_err(
+                                                            ipc::ErrorCode::Conflict,
+                                                            "Smartcard is already enrolled",
+                                                        ),
+                                                    );
+                                                }
+                                            },
+                                            (
+                                                FactorTreeVariant::Smartcards(config),
+                                                ipc::FactorChange::RemoveSmartcard(fingerprint),
+                                            ) => {
+                                                // Config cards would be re-added when the config changes
+                                                if config.smartcards.iter().any(|c| &c.fingerprint == fingerprint) {
+                                                    return Err(
+                                                        req_err(
+                                                            ipc::ErrorCode::InvalidArgument,
+                                                            "Smartcard is listed in the config, remove it from the config instead",
+                                                        ),
+                                                    );
+                                                }
+                                                let cards = smartcards_state()?;
+                                                if !cards.contains_key(fingerprint) {
+                                                    return Err(
+                                                        req_err(ipc::ErrorCode::NotFound, "Smartcard isn't enrolled"),
+                                                    );
+                                                }
+                                                if cards.len() <= 1 {
+                                                    return Err(
+                                                        req_err(
+                                                            ipc::ErrorCode::InvalidArgument,
+                                                            "Can't remove the last smartcard",
+                                                        ),
+                                                    );
+                                                }
+                                            },
+                                            _ => {
+                                                return Err(
+                                                    req_err(
+                                                        ipc::ErrorCode::InvalidArgument,
+                                                        "Change doesn't apply to this type of factor",
+                                                    ),
+                                                );
+                                            },
+                                        }
+                                        let (fg_result_tx, fg_result_rx) = oneshot::channel();
+                                        state.fg_tx.send(fg::B2F::FactorChange(fg::B2FFactorChange {
+                                            pubdb_path: state.pubdb_path.clone(),
+                                            factor: factor.clone(),
+                                            change: req.change,
+                                            state: factor_state,
+                                        }, fg_result_tx)).await.ignore();
+                                        let res =
+                                            fg_result_rx
+                                                .await
+                                                .context("Error waiting for fg factor change")?
+                                                .context("Error during fg factor change")?
+                                                .context("User closed factor change window")?;
+                                        match res {
+                                            fg::B2FFactorChangeResult::Changed(new_state) => {
+                                                pubdb::factor_add(
+                                                    &mut pubdbc,
+                                                    &factor.id,
+                                                    &new_state,
+                                                ).context("Error storing changed factor state")?;
+                                            },
+                                            fg::B2FFactorChangeResult::LegacyToken => {
+                                                return Err(legacy_token());
+                                            },
+                                        }
+                                        resp = rr(());
+                                    },
                                     ipc::msg::ServerReq::MetaKeys(rr, req) => {
                                         if !permission::permit(
                                             &log,
-                                            state.fg_tx.clone(),
+                                            &state,
+                                            variant,
                                             &rules.tree,
                                             &principal,
                                             &req.paths,
+                                            &mut audit,
                                         )
                                             .await?
                                             .meta {
                                             return resp_unauthorized();
                                         }
-                                        let tree = tx(get_privdb(&state).await?, move |txn| {
+                                        let tree = tx(req_privdb(&log, &state, no_unlock).await?, move |txn| {
                                             let mut root0 = serde_json::Value::Null;
                                             for path in &req.paths {
                                                 let mut root = serde_json::Value::Null;
@@ -864,16 +865,18 @@ async fn main2() -> Result<(), loga::Error> {
                                     ipc::msg::ServerReq::MetaRevisions(rr, req) => {
                                         if !permission::permit(
                                             &log,
-                                            state.fg_tx.clone(),
+                                            &state,
+                                            variant,
                                             &rules.tree,
                                             &principal,
                                             &req.paths,
+                                            &mut audit,
                                         )
                                             .await?
                                             .meta {
                                             return resp_unauthorized();
                                         }
-                                        let mut privdbc = get_privdb(&state).await?;
+                                        let mut privdbc = req_privdb(&log, &state, no_unlock).await?;
                                         let db_resp = spawn_blocking(move || {
                                             let mut root = serde_json::Value::Null;
                                             for path in req.paths {
@@ -912,27 +915,25 @@ async fn main2() -> Result<(), loga::Error> {
                                     ipc::msg::ServerReq::MetaPgpPubkey(rr, req) => {
                                         if !permission::permit(
                                             &log,
-                                            state.fg_tx.clone(),
+                                            &state,
+                                            variant,
                                             &rules.tree,
                                             &principal,
                                             &vec![req.path.clone()],
+                                            &mut audit,
                                         )
                                             .await?
                                             .meta {
                                             return resp_unauthorized();
                                         }
-                                        let db_key = tx(get_privdb(&state).await?, move |txn| {
-                                            return Ok(get(txn, &req.path, None)?);
-                                        }).await?;
-                                        let serde_json::Value::String(key) = db_key else {
-                                            return Err(
-                                                loga::err("No value at path or value is not a string").into(),
-                                            );
-                                        };
+                                        let key = tx_res(req_privdb(&log, &state, no_unlock).await?, move |txn| {
+                                            return ops::get_string(txn, &req.path);
+                                        }).await??;
                                         resp =
                                             rr(
                                                 String::from_utf8(
-                                                    pgp_from_armor(&key)?
+                                                    pgp_from_armor(&key)
+                                                        .map_err(wrong_type("Value at path is not a pgp key"))?
                                                         .armored()
                                                         .to_vec()
                                                         .map_err(loga::err)
@@ -944,265 +945,224 @@ async fn main2() -> Result<(), loga::Error> {
                                     ipc::msg::ServerReq::MetaSshPubkey(rr, req) => {
                                         if !permission::permit(
                                             &log,
-                                            state.fg_tx.clone(),
+                                            &state,
+                                            variant,
                                             &rules.tree,
                                             &principal,
                                             &vec![req.path.clone()],
+                                            &mut audit,
                                         )
                                             .await?
                                             .meta {
                                             return resp_unauthorized();
                                         }
-                                        let db_key = tx(get_privdb(&state).await?, move |txn| {
-                                            return Ok(get(txn, &req.path, None)?);
-                                        }).await?;
-                                        let serde_json::Value::String(key) = db_key else {
-                                            return Err(
-                                                loga::err("No value at path or value is not a string").into(),
-                                            );
-                                        };
+                                        let key = tx_res(req_privdb(&log, &state, no_unlock).await?, move |txn| {
+                                            return ops::get_string(txn, &req.path);
+                                        }).await??;
                                         resp =
                                             rr(
                                                 ssh_key::PrivateKey::from_openssh(&key)
-                                                    .context("Error reading data at path as openssh private key PEM")?
+                                                    .map_err(wrong_type("Value at path is not an openssh private key"))?
                                                     .public_key()
                                                     .to_openssh()
                                                     .context("Error encoding ssh public key as openssh PEM")?,
                                             );
                                         activity.notify_one();
                                     },
+                                    ipc::msg::ServerReq::MetaAudit(rr, req) => {
+                                        let paths = if req.paths.is_empty() {
+                                            vec![SpecificPath(vec!["".to_string()])]
+                                        } else {
+                                            req.paths.clone()
+                                        };
+                                        if !permission::permit(
+                                            &log,
+                                            &state,
+                                            variant,
+                                            &rules.tree,
+                                            &principal,
+                                            &paths,
+                                            &mut audit,
+                                        )
+                                            .await?
+                                            .meta {
+                                            return resp_unauthorized();
+                                        }
+                                        resp = rr(audit::query(&state, req, |path| {
+                                            return permission::has_meta(&log, &state, &rules.tree, &principal, path);
+                                        }).await?);
+                                    },
                                     ipc::msg::ServerReq::Read(rr, req) => {
                                         if !permission::permit(
                                             &log,
-                                            state.fg_tx.clone(),
+                                            &state,
+                                            variant,
                                             &rules.tree,
                                             &principal,
                                             &req.paths,
+                                            &mut audit,
                                         )
                                             .await?
                                             .read {
                                             return resp_unauthorized();
                                         }
-                                        let tree = tx(get_privdb(&state).await?, move |txn| {
-                                            let mut root = serde_json::Value::Null;
-                                            for path in &req.paths {
-                                                bury(&mut root, &path, get(txn, path, req.at)?);
-                                            }
-                                            return Ok(root);
+                                        let tree = tx(req_privdb(&log, &state, no_unlock).await?, move |txn| {
+                                            return ops::read(txn, &req);
                                         }).await?;
                                         activity.notify_one();
                                         resp = rr(serde_json::to_value(&tree).unwrap());
                                     },
                                     ipc::msg::ServerReq::Write(rr, req) => {
+                                        let paths = req.values.iter().map(|(path, _)| path.clone()).collect::<Vec<_>>();
                                         if !permission::permit(
                                             &log,
-                                            state.fg_tx.clone(),
+                                            &state,
+                                            variant,
                                             &rules.tree,
                                             &principal,
-                                            &req.0.iter().map(|(path, _)| path.clone()).collect::<Vec<_>>(),
+                                            &ops::write_paths(paths.iter().cloned(), &req.preconditions),
+                                            &mut audit,
                                         )
                                             .await?
                                             .write {
                                             return resp_unauthorized();
                                         }
-                                        tx(get_privdb(&state).await?, move |txn| {
-                                            set(txn, req.0)?;
-                                            return Ok(());
-                                        }).await?;
+                                        tx_res(req_privdb(&log, &state, no_unlock).await?, move |txn| {
+                                            return ops::write(txn, req);
+                                        }).await??;
+                                        state.watch.changed(paths);
                                         activity.notify_one();
                                         resp = rr(());
                                     },
                                     ipc::msg::ServerReq::WriteMove(rr, req) => {
+                                        let paths = vec![req.from.clone(), req.to.clone()];
                                         if !permission::permit(
                                             &log,
-                                            state.fg_tx.clone(),
+                                            &state,
+                                            variant,
                                             &rules.tree,
                                             &principal,
-                                            &[req.from.clone(), req.to.clone()],
+                                            &ops::write_paths(paths.iter().cloned(), &req.preconditions),
+                                            &mut audit,
                                         )
                                             .await?
                                             .write {
                                             return resp_unauthorized();
                                         }
-                                        tx(get_privdb(&state).await?, move |txn| {
-                                            if get(txn, &req.to, None)? != serde_json::Value::Null && !req.overwrite {
-                                                return Err(
-                                                    loga::err(
-                                                        "Attempt to move over existing value with overwrite off",
-                                                    ),
-                                                );
-                                            }
-                                            let data = get(txn, &req.from, None)?;
-                                            set(txn, vec![(req.from, serde_json::Value::Null), (req.to, data)])?;
-                                            return Ok(());
-                                        }).await?;
+                                        tx_res(req_privdb(&log, &state, no_unlock).await?, move |txn| {
+                                            return ops::write_move(txn, req);
+                                        }).await??;
+                                        state.watch.changed(paths);
                                         activity.notify_one();
                                         resp = rr(());
                                     },
                                     ipc::msg::ServerReq::WriteGenerate(rr, req) => {
+                                        let paths = vec![req.path.clone()];
                                         if !permission::permit(
                                             &log,
-                                            state.fg_tx.clone(),
+                                            &state,
+                                            variant,
                                             &rules.tree,
                                             &principal,
-                                            &[req.path.clone()],
+                                            &ops::write_paths(paths.iter().cloned(), &req.preconditions),
+                                            &mut audit,
                                         )
                                             .await?
                                             .write {
                                             return resp_unauthorized();
                                         }
-                                        tx(get_privdb(&state).await?, move |txn| {
-                                            if get(txn, &req.path, None)? != serde_json::Value::Null &&
-                                                !req.overwrite {
-                                                return Err(
-                                                    loga::err("Destructive command but flag to allow not specified"),
-                                                );
-                                            }
-                                            let data;
-                                            match req.variant {
-                                                ipc::C2SGenerateVariant::Bytes(args) => {
-                                                    data =
-                                                        serde_json::Value::String(
-                                                            to_b32(&generate::gen_bytes(args.length)),
-                                                        );
-                                                },
-                                                ipc::C2SGenerateVariant::SafeAlphanumeric(args) => {
-                                                    data =
-                                                        serde_json::Value::String(
-                                                            generate::gen_safe_alphanum(args.length),
-                                                        );
-                                                },
-                                                ipc::C2SGenerateVariant::Alphanumeric(args) => {
-                                                    data =
-                                                        serde_json::Value::String(
-                                                            generate::gen_alphanum(args.length),
-                                                        );
-                                                },
-                                                ipc::C2SGenerateVariant::AlphanumericSymbols(args) => {
-                                                    data =
-                                                        serde_json::Value::String(
-                                                            generate::gen_alphanum_symbols(args.length),
-                                                        );
-                                                },
-                                                ipc::C2SGenerateVariant::Pgp => {
-                                                    let (cert, _) =
-                                                        CertBuilder::new()
-                                                            .set_cipher_suite(
-                                                                sequoia_openpgp::cert::CipherSuite::Cv25519,
-                                                            )
-                                                            .add_signing_subkey()
-                                                            .add_subkey(
-                                                                sequoia_openpgp::types::KeyFlags::empty()
-                                                                    .set_transport_encryption()
-                                                                    .set_storage_encryption(),
-                                                                None,
-                                                                None,
-                                                            )
-                                                            .generate()
-                                                            .map_err(|e| loga::err(e.to_string()))
-                                                            .context("Error generating pgp cert")?;
-                                                    {
-                                                        let mut bytes = Vec::new();
-                                                        sequoia_openpgp::serialize::Serialize::serialize(
-                                                            &cert.armored(),
-                                                            &mut bytes,
-                                                        )
-                                                            .map_err(|e| loga::err(e.to_string()))
-                                                            .context("Error serializing public key")?;
-                                                    }
-                                                    {
-                                                        let mut w =
-                                                            sequoia_openpgp::armor::Writer::with_headers(
-                                                                vec![],
-                                                                sequoia_openpgp::armor::Kind::SecretKey,
-                                                                cert
-                                                                    .armor_headers()
-                                                                    .iter()
-                                                                    .map(|value| ("Comment", value.as_str()))
-                                                                    .collect::<Vec<_>>(),
-                                                            )?;
-                                                        sequoia_openpgp::serialize::Serialize::serialize(
-                                                            &cert.as_tsk().armored(),
-                                                            &mut w,
-                                                        )
-                                                            .map_err(|e| loga::err(e.to_string()))
-                                                            .context("Error serializing private key")?;
-                                                        data =
-                                                            serde_json::Value::String(
-                                                                String::from_utf8(w.finalize()?).unwrap(),
-                                                            );
-                                                    }
-                                                },
-                                                ipc::C2SGenerateVariant::Ssh => {
-                                                    let key =
-                                                        ssh_key::PrivateKey::random(
-                                                            &mut ssh_key::rand_core::OsRng,
-                                                            ssh_key::Algorithm::default(),
-                                                        ).context("Error generating ssh key")?;
-                                                    data =
-                                                        serde_json::Value::String(
-                                                            key
-                                                                .to_openssh(Default::default())
-                                                                .context(
-                                                                    "Error encoding ssh key in openssh PEM format",
-                                                                )?
-                                                                .to_string(),
-                                                        );
-                                                },
-                                            };
-                                            set(txn, vec![(req.path, data)])?;
-                                            return Ok(());
-                                        }).await?;
+                                        tx_res(req_privdb(&log, &state, no_unlock).await?, move |txn| {
+                                            return ops::write_generate(txn, req);
+                                        }).await??;
+                                        state.watch.changed(paths);
                                         activity.notify_one();
                                         resp = rr(());
                                     },
                                     ipc::msg::ServerReq::WriteRevert(rr, req) => {
                                         if !permission::permit(
                                             &log,
-                                            state.fg_tx.clone(),
+                                            &state,
+                                            variant,
                                             &rules.tree,
                                             &principal,
                                             &req.paths,
+                                            &mut audit,
                                         )
                                             .await?
                                             .write {
                                             return resp_unauthorized();
                                         }
-                                        tx(get_privdb(&state).await?, move |txn| {
-                                            for path in req.paths {
-                                                let data = get(txn, &path, Some(req.at))?;
-                                                set(txn, vec![(path, data)])?;
-                                            }
-                                            return Ok(()) as Result<_, loga::Error>;
+                                        let paths = req.paths.clone();
+                                        tx(req_privdb(&log, &state, no_unlock).await?, move |txn| {
+                                            return ops::write_revert(txn, req);
                                         }).await?;
+                                        state.watch.changed(paths);
                                         activity.notify_one();
                                         resp = rr(());
                                     },
+                                    ipc::msg::ServerReq::Batch(rr, req) => {
+                                        let mut paths = vec![];
+                                        let mut written = vec![];
+                                        for op in &req.0 {
+                                            let (op_paths, op_written) = ops::batch_op_paths(op);
+                                            paths.extend(op_paths);
+                                            if let Some(op_written) = op_written {
+                                                written.extend(op_written);
+                                            }
+                                        }
+                                        let perms = permission::permit(
+                                            &log,
+                                            &state,
+                                            variant,
+                                            &rules.tree,
+                                            &principal,
+                                            &paths,
+                                            &mut audit,
+                                        ).await?;
+                                        let permitted = if written.is_empty() {
+                                            perms.read
+                                        } else {
+                                            perms.write
+                                        };
+                                        if !permitted {
+                                            return resp_unauthorized();
+                                        }
+                                        let results = tx_res(req_privdb(&log, &state, no_unlock).await?, move |txn| {
+                                            let mut results = vec![];
+                                            for op in req.0 {
+                                                results.push(ops::batch_op(txn, op)?);
+                                            }
+                                            return Ok(results) as Result<_, ops::OpErr>;
+                                        }).await??;
+                                        if !written.is_empty() {
+                                            state.watch.changed(written);
+                                        }
+                                        activity.notify_one();
+                                        resp = rr(results);
+                                    },
                                     ipc::msg::ServerReq::DerivePgpSign(rr, req) => {
                                         if !permission::permit(
                                             &log,
-                                            state.fg_tx.clone(),
+                                            &state,
+                                            variant,
                                             &rules.tree,
                                             &principal,
                                             &[req.key.clone()],
+                                            &mut audit,
                                         )
                                             .await?
                                             .derive {
                                             return resp_unauthorized();
                                         }
-                                        let db_key = tx(get_privdb(&state).await?, move |txn| {
-                                            return Ok(get(txn, &req.key, None)?);
-                                        }).await?;
-                                        let serde_json::Value::String(key) = db_key else {
-                                            return Err(
-                                                loga::err("No value at path or value is not a string").into(),
-                                            );
-                                        };
+                                        let key = tx_res(req_privdb(&log, &state, no_unlock).await?, move |txn| {
+                                            return ops::get_string(txn, &req.key);
+                                        }).await??;
                                         let mut signed = vec![];
                                         let mut signer =
                                             Signer::with_template(
                                                 Message::new(&mut signed),
-                                                pgp_from_armor(&key)?
+                                                pgp_from_armor(&key)
+                                                    .map_err(wrong_type("Value at path is not a pgp key"))?
                                                     .keys()
                                                     .secret()
                                                     .with_policy(&StandardPolicy::new(), None)
@@ -1250,23 +1210,20 @@ async fn main2() -> Result<(), loga::Error> {
                                     ipc::msg::ServerReq::DerivePgpDecrypt(rr, req) => {
                                         if !permission::permit(
                                             &log,
-                                            state.fg_tx.clone(),
+                                            &state,
+                                            variant,
                                             &rules.tree,
                                             &principal,
                                             &[req.key.clone()],
+                                            &mut audit,
                                         )
                                             .await?
                                             .derive {
                                             return resp_unauthorized();
                                         }
-                                        let db_key = tx(get_privdb(&state).await?, move |txn| {
-                                            return Ok(get(txn, &req.key, None)?);
-                                        }).await?;
-                                        let serde_json::Value::String(key) = db_key else {
-                                            return Err(
-                                                loga::err("No value at path or value is not a string").into(),
-                                            );
-                                        };
+                                        let key = tx_res(req_privdb(&log, &state, no_unlock).await?, move |txn| {
+                                            return ops::get_string(txn, &req.key);
+                                        }).await??;
                                         let mut decrypted = vec![];
 
                                         struct Helper(Cert);
@@ -1339,12 +1296,14 @@ async fn main2() -> Result<(), loga::Error> {
                                             }
                                         }
 
+                                        let cert =
+                                            pgp_from_armor(&key).map_err(wrong_type("Value at path is not a pgp key"))?;
                                         let policy = StandardPolicy::new();
                                         let mut decryptor =
                                             DecryptorBuilder::from_bytes(&req.data)
                                                 .map_err(loga::err)
                                                 .context("Error creating decryptor from data to decrypt")?
-                                                .with_policy(&policy, None, Helper(pgp_from_armor(&key)?))
+                                                .with_policy(&policy, None, Helper(cert))
                                                 .map_err(loga::err)
                                                 .context("Error matching cert to data to decrypt")?;
                                         std::io::copy(&mut decryptor, &mut Cursor::new(&mut decrypted))
@@ -1353,49 +1312,245 @@ async fn main2() -> Result<(), loga::Error> {
                                         activity.notify_one();
                                         resp = rr(decrypted);
                                     },
+                                    ipc::msg::ServerReq::DeriveSshSign(rr, req) => {
+                                        if !permission::permit(
+                                            &log,
+                                            &state,
+                                            variant,
+                                            &rules.tree,
+                                            &principal,
+                                            &[req.key.clone()],
+                                            &mut audit,
+                                        )
+                                            .await?
+                                            .derive {
+                                            return resp_unauthorized();
+                                        }
+                                        let key = tx_res(req_privdb(&log, &state, no_unlock).await?, move |txn| {
+                                            return ops::get_string(txn, &req.key);
+                                        }).await??;
+                                        resp =
+                                            rr(
+                                                ssh_key::PrivateKey::from_openssh(&key)
+                                                    .map_err(wrong_type("Value at path is not an openssh private key"))?
+                                                    .sign(&req.namespace, ssh_key::HashAlg::default(), &req.data)
+                                                    .context("Error signing data")?
+                                                    .to_pem(ssh_key::LineEnding::LF)
+                                                    .context("Error encoding ssh signature as PEM")?,
+                                            );
+                                        activity.notify_one();
+                                    },
                                     ipc::msg::ServerReq::DeriveOtp(rr, req) => {
                                         if !permission::permit(
                                             &log,
-                                            state.fg_tx.clone(),
+                                            &state,
+                                            variant,
                                             &rules.tree,
                                             &principal,
                                             &[req.key.clone()],
+                                            &mut audit,
                                         )
                                             .await?
                                             .derive {
                                             return resp_unauthorized();
                                         }
-                                        let otp_url = tx(get_privdb(&state).await?, move |txn| {
-                                            return Ok(get(txn, &req.key, None)?);
-                                        }).await?;
-                                        let serde_json::Value::String(otp_url) = otp_url else {
+                                        let key = req.key.clone();
+                                        let privdbc = req_privdb(&log, &state, no_unlock).await?;
+                                        let otp = tx_res(privdbc, move |txn| -> Result<_, ops::OpErr> {
+                                            let otp_url = ops::get_string(txn, &req.key)?;
+                                            let Ok(otp) = parse_otp_url(&otp_url) else {
+                                                return Err(
+                                                    ops::OpErr::resp(
+                                                        ipc::ErrorCode::WrongType,
+                                                        "Value at path is not an OTP url",
+                                                    ),
+                                                );
+                                            };
+                                            match otp {
+                                                Otp::Totp(otp) => {
+                                                    return Ok(ipc::RespDeriveOtp {
+                                                        token: otp
+                                                            .generate_current()
+                                                            .context("Error generating OTP token")?,
+                                                        remaining_seconds: Some(
+                                                            otp.ttl().context("Error determining OTP token ttl")?,
+                                                        ),
+                                                        counter: None,
+                                                    });
+                                                },
+                                                Otp::Hotp { otp, counter } => {
+                                                    // Persist the next counter in the same transaction so concurrent
+                                                    // requests never reuse a counter
+                                                    set(
+                                                        txn,
+                                                        vec![
+                                                            (
+                                                                req.key.clone(),
+                                                                serde_json::Value::String(
+                                                                    set_hotp_counter(&otp_url, counter + 1)?,
+                                                                ),
+                                                            )
+                                                        ],
+                                                    )?;
+                                                    return Ok(ipc::RespDeriveOtp {
+                                                        token: otp.generate(counter),
+                                                        remaining_seconds: None,
+                                                        counter: Some(counter),
+                                                    });
+                                                },
+                                            }
+                                        }).await??;
+                                        if otp.counter.is_some() {
+                                            // Stored counter was incremented
+                                            state.watch.changed(vec![key]);
+                                        }
+                                        activity.notify_one();
+                                        resp = rr(otp);
+                                    },
+                                    ipc::msg::ServerReq::Watch(rr, req) => {
+                                        let paths = if req.paths.is_empty() {
+                                            vec![SpecificPath(vec!["".to_string()])]
+                                        } else {
+                                            req.paths.clone()
+                                        };
+                                        if !permission::permit(
+                                            &log,
+                                            &state,
+                                            variant,
+                                            &rules.tree,
+                                            &principal,
+                                            &paths,
+                                            &mut audit,
+                                        )
+                                            .await?
+                                            .meta {
+                                            return resp_unauthorized();
+                                        }
+                                        watcher = Some(state.watch.subscribe(req.paths));
+                                        resp = rr(());
+                                    },
+                                    ipc::msg::ServerReq::WatchNext(rr, _) => {
+                                        let Some(watcher) = &mut watcher else {
+                                            return Err(
+                                                req_err(
+                                                    ipc::ErrorCode::InvalidArgument,
+                                                    "Connection isn't watching anything",
+                                                ),
+                                            );
+                                        };
+                                        select!{
+                                            changed = watcher.next() => {
+                                                resp = rr(changed);
+                                            },
+                                            _ = conn_closed(&conn.0) => {
+                                                disconnected = true;
+                                                return Err(loga::err("Client disconnected").into());
+                                            },
+                                        }
+                                    },
+                                    ipc::msg::ServerReq::UiRegister(rr, _) => {
+                                        // The handler answers prompts, so it must be allowed outright rather
+                                        // than at a prompt it might end up answering itself
+                                        let evaluation =
+                                            permission::evaluate(
+                                                &log,
+                                                &rules.tree,
+                                                &principal,
+                                                &[SpecificPath(vec!["".to_string()])],
+                                                &mut audit,
+                                            );
+                                        if !evaluation.perms.lock || !evaluation.prompt_rules.is_empty() {
+                                            return resp_unauthorized();
+                                        }
+                                        let Some(process) =
+                                            principal.chain.first().and_then(|x| x.pidfd_inode) else {
+                                                return Err(
+                                                    loga::err("Unable to identify the process registering as handler")
+                                                        .into(),
+                                                );
+                                            };
+                                        if ui_handler.is_none() {
+                                            ui_handler = Some(state.ui_delegate.register(process)?);
+                                        }
+                                        resp = rr(());
+                                    },
+                                    ipc::msg::ServerReq::UiNext(rr, _) => {
+                                        let Some(ui_handler) = &ui_handler else {
                                             return Err(
-                                                loga::err("No value at path or value is not a string").into(),
+                                                req_err(
+                                                    ipc::ErrorCode::InvalidArgument,
+                                                    "Connection isn't registered to handle interactions",
+                                                ),
                                             );
                                         };
-                                        let otp =
-                                            totp_rs::TOTP::from_url_unchecked(
-                                                &otp_url,
-                                            ).context("Failed to parse OTP url at path")?;
-                                        let token = otp.generate_current().context("Error generating OTP token")?;
-                                        resp = rr(token);
+                                        select!{
+                                            question = ui_handler.next() => {
+                                                resp = rr(question);
+                                            },
+                                            _ = conn_closed(&conn.0) => {
+                                                disconnected = true;
+                                                return Err(loga::err("Client disconnected").into());
+                                            },
+                                        }
+                                    },
+                                    ipc::msg::ServerReq::UiAnswer(rr, req) => {
+                                        let Some(ui_handler) = &ui_handler else {
+                                            return Err(
+                                                req_err(
+                                                    ipc::ErrorCode::InvalidArgument,
+                                                    "Connection isn't registered to handle interactions",
+                                                ),
+                                            );
+                                        };
+                                        ui_handler.answer(req)?;
+                                        resp = rr(());
                                     },
                                 }
                                 return Ok(resp);
-                            }.await {
+                            }.await;
+                            if disconnected {
+                                break;
+                            }
+                            let (refused_result, refused_err) = refusal(&audit);
+                            match audit::record(&state, variant, &principal, audit, match &res {
+                                Ok(_) => AuditResult::Ok,
+                                Err(AuthErr::Unauthorized) => refused_result,
+                                Err(AuthErr::Locked) => AuditResult::Locked,
+                                Err(AuthErr::Resp(e)) if e.code == ipc::ErrorCode::Conflict => AuditResult::Conflict,
+                                Err(AuthErr::Resp(_)) | Err(AuthErr::Err(_)) => AuditResult::Error,
+                            }).await {
+                                Ok(_) => { },
+                                Err(e) => {
+                                    log.log_err(loga::WARN, e.context("Error writing audit log entry"));
+                                },
+                            }
+                            let resp;
+                            match res {
                                 Ok(r) => {
                                     resp = r;
                                 },
                                 Err(e) => {
+                                    let resp_err;
                                     match e {
                                         AuthErr::Err(e) => {
                                             log.log_err(loga::WARN, e.context("Error processing request"));
-                                            resp = ipc::msg::ServerResp::err("Encountered error processing request");
+                                            resp_err =
+                                                ipc::RespError::new(
+                                                    ipc::ErrorCode::Internal,
+                                                    "Encountered error processing request",
+                                                );
                                         },
                                         AuthErr::Unauthorized => {
-                                            resp = ipc::msg::ServerResp::err("Unauthorized");
+                                            resp_err = refused_err;
+                                        },
+                                        AuthErr::Locked => {
+                                            resp_err = ipc::RespError::new(ipc::ErrorCode::Locked, "Locked");
+                                        },
+                                        AuthErr::Resp(e) => {
+                                            resp_err = e;
                                         },
                                     }
+                                    resp = ipc::msg::ServerResp::err(&resp_err.to_resp());
                                 },
                             };
                             conn.send_resp(resp).await.map_err(loga::err)?;
@@ -1413,6 +1568,11 @@ async fn main2() -> Result<(), loga::Error> {
         }
     });
 
+    // Ssh agent
+    if let Some(ssh_agent_config) = &ssh_agent_config {
+        sshagent::start(&tm, &log, state.clone(), tags.clone(), activity.clone(), ssh_agent_config).await?;
+    }
+
     // Timeout-locks
     tm.critical_task("Timeouts", {
         let tm = tm.clone();
@@ -1427,19 +1587,56 @@ async fn main2() -> Result<(), loga::Error> {
                     }
                     _ = activity.notified() => {
                         next = Some(Instant::now() + Duration::from_secs(state.lock_timeout));
+                        *state.lock_at.lock().unwrap() = next;
                     }
                     _ = async {
                         sleep_until(next.take().unwrap()).await
                     },
                     if next.is_some() => {
                         log.log(loga::DEBUG, "Activity timeout, locking");
-                        state.token_state.lock().unwrap().token = None;
+                        *state.lock_at.lock().unwrap() = None;
+                        state::lock(&state);
                     }
                 }
             }
         }
     });
 
+    // Config reload
+    let mut hangup = signal(SignalKind::hangup()).context("Error registering SIGHUP handler")?;
+    tm.critical_task("Config reload", {
+        let tm = tm.clone();
+        let state = state.clone();
+        let log = log.fork(ea!(sys = "reload"));
+        let config_path = match args.config.source {
+            aargvark::traits_impls::Source::Stdin => None,
+            aargvark::traits_impls::Source::File(f) => Some(f),
+        };
+        async move {
+            loop {
+                select!{
+                    _ = tm.until_terminate() => {
+                        return Ok(());
+                    }
+                    _ = hangup.recv() => {
+                    }
+                }
+                let Some(config_path) = &config_path else {
+                    log.log(loga::WARN, "Received SIGHUP but config was read from stdin, can't reload");
+                    continue;
+                };
+                match reload_config(&state, config_path, &mut applied_unlock_config).await {
+                    Ok(_) => {
+                        log.log(loga::INFO, "Reloaded config");
+                    },
+                    Err(e) => {
+                        log.log_err(loga::WARN, e.context("Error reloading config, keeping previous config"));
+                    },
+                }
+            }
+        }
+    });
+
     // Start bg tasks (timeouts mainly) Wait forever
     tm.join(&log).await?;
     return Ok(());
@@ -1454,3 +1651,89 @@ async fn main() {
         },
     }
 }
+
+#[tokio::test]
+async fn test_rejection_reported() {
+    let log = Log::new_root(loga::INFO);
+    let (fg_tx, mut fg_rx) = tokio::sync::mpsc::channel(1);
+    let rules = permission::RuleTree {
+        rules: vec![Arc::new(permission::Rule {
+            id: 0,
+            match_tag: None,
+            match_user: None,
+            match_binary: None,
+            match_cgroup: None,
+            match_systemd_unit: None,
+            match_namespace: None,
+            permit: config::latest::PermitLevel::Read,
+            deny: false,
+            prompt: Some(config::latest::ConfigPrompt {
+                description: "Test".to_string(),
+                remember_seconds: 0,
+            }),
+        })],
+        wildcard: None,
+        children: HashMap::new(),
+    };
+    let state = State {
+        auditdb_path: PathBuf::new(),
+        privdb_path: PathBuf::new(),
+        pubdb_path: PathBuf::new(),
+        root_factor: Mutex::new(Arc::new(serverlib::factor::FactorTree {
+            id: "root".to_string(),
+            desc: "Root".to_string(),
+            variant: FactorTreeVariant::Password,
+        })),
+        rules: Mutex::new(permission::RuleTreeRoot {
+            tree: Arc::new(permission::RuleTree::default()),
+            any_match_binary: false,
+            binary_sha256_walk_ancestors: None,
+        }),
+        fg_tx: fg_tx,
+        token_state: Mutex::new(TokenState {
+            token: None,
+            wait_sub: None,
+        }),
+        grants: Mutex::new(Grants::default()),
+        ui_delegate: Arc::new(UiDelegate::default()),
+        lock_timeout: 0,
+        lock_at: Mutex::new(None),
+        watch: Watch::new(),
+        binary_hashes: Arc::new(Mutex::new(HashMap::new())),
+    };
+    let principal = PrincipalMeta { chain: vec![] };
+    let paths = [SpecificPath(vec!["a".to_string()])];
+
+    // Rejected at the prompt, remembered
+    let mut audit = AuditDecision::default();
+    let (perms, _) = tokio::join!(
+        permission::permit(&log, &state, "read", &rules, &principal, &paths, &mut audit),
+        async {
+            match fg_rx.recv().await {
+                Some(fg::B2F::Prompt(_, resp)) => {
+                    let decision = serverlib::grants::PromptDecision::DenyFor(Duration::from_secs(60));
+                    resp.send(Ok(Some(decision))).ignore();
+                },
+                _ => panic!("Expected a prompt"),
+            }
+        },
+    );
+    assert!(!perms.unwrap().read);
+    let (result, err) = refusal(&audit);
+    assert!(matches!(result, AuditResult::Rejected));
+    assert_eq!(err.code, ipc::ErrorCode::UserRejectedPrompt);
+
+    // Remembered rejection, no prompt
+    drop(fg_rx);
+    let mut audit = AuditDecision::default();
+    let perms = permission::permit(&log, &state, "read", &rules, &principal, &paths, &mut audit).await.unwrap();
+    assert!(!perms.read);
+    let (result, err) = refusal(&audit);
+    assert!(matches!(result, AuditResult::Rejected));
+    assert_eq!(err.code, ipc::ErrorCode::UserRejectedPrompt);
+
+    // No prompt involved
+    let (result, err) = refusal(&AuditDecision::default());
+    assert!(matches!(result, AuditResult::Unauthorized));
+    assert_eq!(err.code, ipc::ErrorCode::Unauthorized);
+}
@@ -35,9 +35,14 @@ use {
             get_card_pubkey,
             CardStream,
         },
+        tty::{
+            tty_io,
+            DEFAULT_TTY,
+        },
     },
     passworth_shared_native::{
         proto::{
+            client_req,
             ipc_path,
             req,
         },
@@ -56,6 +61,7 @@ use {
             AsyncReadExt,
             AsyncWriteExt,
         },
+        select,
         task::spawn_blocking,
     },
 };
@@ -75,6 +81,21 @@ fn remove_prefix(value: serde_json::Value, path: &SpecificPath) -> serde_json::V
     return value;
 }
 
+fn preconditions(
+    path: &SpecificPath,
+    if_absent: bool,
+    if_rev_stamp: Option<String>,
+) -> Vec<(SpecificPath, ipc::WritePrecondition)> {
+    let mut out = vec![];
+    if if_absent {
+        out.push((path.clone(), ipc::WritePrecondition::Absent));
+    }
+    if let Some(stamp) = if_rev_stamp {
+        out.push((path.clone(), ipc::WritePrecondition::RevStamp(stamp)));
+    }
+    return out;
+}
+
 struct AargvarkSpecificPath(SpecificPath);
 
 impl AargvarkFromStr for AargvarkSpecificPath {
@@ -176,6 +197,38 @@ enum GenerateVariant {
     Ssh,
 }
 
+#[derive(Aargvark)]
+struct UnlockCommand {
+    /// Answer the unlock questions (passwords, recovery phrase, etc.) and any access
+    /// prompts in this terminal instead of the server's frontend, until unlocked.
+    /// Requires `write` permission on the root.
+    interactive: Option<()>,
+}
+
+#[derive(Aargvark)]
+enum FactorChangeVariant {
+    /// Replace the password of a `password` factor.
+    Password,
+    /// Generate a new phrase for a `recovery_code` factor.
+    RecoveryPhrase,
+    /// Allow another smartcard to unlock a `smartcards` factor, by fingerprint (see
+    /// `scan-cards`).
+    AddSmartcard(String),
+    /// Stop allowing a smartcard added with `add-smartcard` to unlock a `smartcards`
+    /// factor.
+    RemoveSmartcard(String),
+}
+
+#[derive(Aargvark)]
+struct FactorChangeCommand {
+    /// The id of the factor in the config.
+    factor: String,
+    change: FactorChangeVariant,
+    /// Answer the unlock and change questions in this terminal instead of the server's
+    /// frontend. Requires `write` permission on the root.
+    interactive: Option<()>,
+}
+
 #[derive(Aargvark)]
 struct ReadCommand {
     /// A path to get data for, in `/path/to/data` format.
@@ -211,6 +264,28 @@ struct MetaSshPubkeyCommand {
     revision: Option<i64>,
 }
 
+#[derive(Aargvark)]
+struct AuditCommand {
+    /// Only show entries involving these paths or their descendants.
+    paths: Vec<AargvarkSpecificPath>,
+    /// Only show entries at or after this time (RFC 3339).
+    #[vark(flag = "--start")]
+    start: Option<String>,
+    /// Only show entries at or before this time (RFC 3339).
+    #[vark(flag = "--end")]
+    end: Option<String>,
+    /// Only show entries where the requesting process or one of its ancestors was
+    /// running this binary.
+    #[vark(flag = "--binary")]
+    binary: Option<String>,
+}
+
+#[derive(Aargvark)]
+struct WatchCommand {
+    /// Paths to watch for changes. If none, only lock and unlock events are shown.
+    paths: Vec<AargvarkSpecificPath>,
+}
+
 #[derive(Aargvark)]
 struct WriteCommand {
     /// Path to create/overwrite
@@ -219,6 +294,12 @@ struct WriteCommand {
     json: Option<()>,
     /// Input is binary, store as a B64 JSON string
     binary: Option<()>,
+    /// Fail with a conflict if anything is already stored at (or under, or above)
+    /// the path.
+    if_absent: Option<()>,
+    /// Fail with a conflict unless the latest revision under the path has this
+    /// `rev_stamp` (see `read-revisions`).
+    if_rev_stamp: Option<String>,
 }
 
 #[derive(Aargvark)]
@@ -245,6 +326,9 @@ struct WriteGenerateCommand {
     /// Write the generated data even if data already exists at the path (overwrites
     /// path).
     overwrite: Option<()>,
+    /// Fail with a conflict unless the latest revision under the path has this
+    /// `rev_stamp` (see `read-revisions`).
+    if_rev_stamp: Option<String>,
 }
 
 #[derive(Aargvark)]
@@ -263,6 +347,16 @@ struct DerivePgpDecryptCommand {
     data: AargvarkFile,
 }
 
+#[derive(Aargvark)]
+struct DeriveSshSignCommand {
+    /// Path of key (in OpenSSH PEM format) to sign with
+    key: AargvarkSpecificPath,
+    /// Signature namespace, ex: `git` or `file`.
+    namespace: String,
+    /// Data to sign.
+    data: AargvarkFile,
+}
+
 #[derive(Aargvark)]
 struct DeriveOtpCommand {
     /// Path of key (in `otpauth://` format) to decrypt with
@@ -293,9 +387,16 @@ enum Command {
     /// Execute a JSON IPC command directly (see ipc jsonschema).
     Json(AargvarkJson<ipc::msg::Req>),
     /// Trigger unlock and wait for it to complete.
-    Unlock,
+    Unlock(UnlockCommand),
     /// Trigger lock and wait for it to complete.
     Lock,
+    /// Change the credentials of an unlock factor (password, recovery phrase,
+    /// smartcards) without editing the config. The factor must be unlocked with its
+    /// current credentials first.
+    FactorChange(FactorChangeCommand),
+    /// Show whether the server is locked, whether an unlock is waiting for the user,
+    /// and the seconds until the inactivity timeout locks it. Doesn't unlock.
+    MetaStatus,
     /// Unlock if locked, and retrieve the keys at the following paths (merged into one
     /// JSON tree). The tree is basically the same as in "get" but where all leaf
     /// values are `null`.
@@ -306,6 +407,12 @@ enum Command {
     /// Unlock if locked and retrieve the public key for the PEM SSH key at the
     /// specified path.
     MetaSshPubkey(MetaSshPubkeyCommand),
+    /// Show the audit log of permission decisions: who requested what, which rules
+    /// matched, whether they were prompted, and the outcome.
+    Audit(AuditCommand),
+    /// Print an event (as a JSON line) each time data under the paths changes or the
+    /// server locks or unlocks, until interrupted.
+    Watch(WatchCommand),
     /// Unlock if locked, and retrieve the data at the following paths (merged into one
     /// JSON tree). Errors if no data found (null output) unless the `--json` flag is
     /// used.
@@ -334,7 +441,11 @@ enum Command {
     DerivePgpSign(DerivePgpSignCommand),
     /// Do pgp decryption on data using a stored key.
     DerivePgpDecrypt(DerivePgpDecryptCommand),
-    /// Generate an otp token from a stored `otpauth://` url.
+    /// Produce an armored ssh signature (`SSHSIG`, compatible with `ssh-keygen -Y
+    /// verify`) on data using a stored key.
+    DeriveSshSign(DeriveSshSignCommand),
+    /// Generate an otp token from a stored `otpauth://` url (totp, hotp, or steam). For
+    /// hotp, the stored counter is incremented.
     DeriveOtp(DeriveOtpCommand),
     /// Listen for smartcards (usb and nfc) and show their fingerprints in a format
     /// that can be used for config.
@@ -367,6 +478,164 @@ fn output(data: impl AsRef<[u8]>) -> Result<(), loga::Error> {
     return Ok(());
 }
 
+enum TtyAnswer {
+    /// Informational question, don't answer
+    Notice,
+    /// `None` to cancel
+    Answer(Option<ipc::UiAnswer>),
+}
+
+fn tty_answer(question: ipc::UiQuestion) -> Result<TtyAnswer, loga::Error> {
+    let tty = Path::new(DEFAULT_TTY);
+    let mut output = format!("\n== Passworth - {} ==\n", question.title);
+    if let Some(warning) = &question.warning {
+        output.push_str(&format!("! {}\n", warning));
+    }
+    match question.variant {
+        ipc::UiQuestionVariant::Choose(choices) => {
+            output.push_str("Choose an unlock method.\n");
+            for (i, desc) in choices.iter().enumerate() {
+                output.push_str(&format!("  {}. {}\n", i + 1, desc));
+            }
+            loop {
+                let Some(line) = tty_io(tty, &format!("{}Number: ", output), Some(false))? else {
+                    return Ok(TtyAnswer::Answer(None));
+                };
+                if let Ok(i) = usize::from_str(line.trim()) {
+                    if i >= 1 && i <= choices.len() {
+                        return Ok(TtyAnswer::Answer(Some(ipc::UiAnswer::Choice(i - 1))));
+                    }
+                }
+                output = "Invalid choice.\n".to_string();
+            }
+        },
+        ipc::UiQuestionVariant::Password(desc) => {
+            output.push_str(&format!("Enter password for {}.\nPassword: ", desc));
+            return Ok(TtyAnswer::Answer(tty_io(tty, &output, Some(true))?.map(ipc::UiAnswer::Secret)));
+        },
+        ipc::UiQuestionVariant::NewPassword(desc) => {
+            output.push_str(&format!("Enter new password for [{}].\n", desc));
+            loop {
+                let Some(password) = tty_io(tty, &format!("{}Password: ", output), Some(true))? else {
+                    return Ok(TtyAnswer::Answer(None));
+                };
+                let Some(confirm) = tty_io(tty, "Confirm: ", Some(true))? else {
+                    return Ok(TtyAnswer::Answer(None));
+                };
+                if password == confirm {
+                    return Ok(TtyAnswer::Answer(Some(ipc::UiAnswer::Secret(password))));
+                }
+                output = "Password mismatch\n".to_string();
+            }
+        },
+        ipc::UiQuestionVariant::ShowRecoveryPhrase(phrase) => {
+            output.push_str("Write down this phrase list, with numbers, and store it somewhere secure.\n");
+            for (i, word) in phrase.iter().enumerate() {
+                output.push_str(&format!("  {}. {}\n", i + 1, word));
+            }
+            output.push_str("Press enter when done.");
+            if tty_io(tty, &output, Some(false))?.is_none() {
+                return Ok(TtyAnswer::Answer(None));
+            }
+
+            // Scroll the phrase out of view
+            tty_io(tty, &"\n".repeat(100), None)?;
+            return Ok(TtyAnswer::Answer(Some(ipc::UiAnswer::Done)));
+        },
+        ipc::UiQuestionVariant::RecoveryPhrase(message) => {
+            output.push_str(&format!("{}\nEnter the words separated by spaces.\nPhrase: ", message));
+            return Ok(TtyAnswer::Answer(tty_io(tty, &output, Some(true))?.map(ipc::UiAnswer::Secret)));
+        },
+        ipc::UiQuestionVariant::SmartcardPin => {
+            output.push_str("Enter card PIN.\nPIN: ");
+            return Ok(TtyAnswer::Answer(tty_io(tty, &output, Some(true))?.map(ipc::UiAnswer::Secret)));
+        },
+        ipc::UiQuestionVariant::GetSmartcard(fingerprints) => {
+            output.push_str("Insert or swipe smartcard with GPG fingerprint:\n");
+            for fingerprint in fingerprints {
+                output.push_str(&format!("  • {}\n", fingerprint));
+            }
+            tty_io(tty, &output, None)?;
+            return Ok(TtyAnswer::Notice);
+        },
+        ipc::UiQuestionVariant::SmartcardTouch(fingerprint) => {
+            output.push_str(&format!("Confirm the action on the smartcard {}\n", fingerprint));
+            tty_io(tty, &output, None)?;
+            return Ok(TtyAnswer::Notice);
+        },
+        ipc::UiQuestionVariant::Fido2Touch(desc) => {
+            output.push_str(&format!("Touch the security key for {}\n", desc));
+            tty_io(tty, &output, None)?;
+            return Ok(TtyAnswer::Notice);
+        },
+        ipc::UiQuestionVariant::GetKeyfile(path) => {
+            output.push_str(&format!("Waiting for keyfile {}\n", path));
+            tty_io(tty, &output, None)?;
+            return Ok(TtyAnswer::Notice);
+        },
+        ipc::UiQuestionVariant::Backoff(seconds) => {
+            output.push_str(&format!("Unlocking is delayed {} seconds due to repeated failed attempts\n", seconds));
+            tty_io(tty, &output, None)?;
+            return Ok(TtyAnswer::Notice);
+        },
+        ipc::UiQuestionVariant::Prompt(prompt) => {
+            let grant_minutes = prompt.grant_seconds / 60;
+            output.push_str("Confirm request by application matching rule?\n");
+            for desc in &prompt.rules {
+                output.push_str(&format!("  {}\n", desc));
+            }
+            output.push_str(&format!("Request: {}\n", prompt.variant));
+            output.push_str("Paths:\n");
+            for path in &prompt.paths {
+                output.push_str(&format!("  {}\n", path.to_string()));
+            }
+            output.push_str("Requester (process, then parents):\n");
+            for line in prompt.principal.lines() {
+                output.push_str(&format!("  {}\n", line));
+            }
+            output.push_str("[o] Allow once\n");
+            output.push_str(&format!("[m] Allow for {} minutes\n", grant_minutes));
+            output.push_str("[p] Allow until process exits\n");
+            output.push_str("[d] Deny\n");
+            output.push_str(&format!("[D] Deny for {} minutes\n", grant_minutes));
+            loop {
+                let Some(line) = tty_io(tty, &format!("{}Choice: ", output), Some(false))? else {
+                    return Ok(TtyAnswer::Answer(None));
+                };
+                let decision = match line.trim() {
+                    "o" => ipc::UiPromptDecision::AllowOnce,
+                    "m" => ipc::UiPromptDecision::AllowFor,
+                    "p" => ipc::UiPromptDecision::AllowProcess,
+                    "d" => ipc::UiPromptDecision::Deny,
+                    "D" => ipc::UiPromptDecision::DenyFor,
+                    _ => {
+                        output = "Invalid choice.\n".to_string();
+                        continue;
+                    },
+                };
+                return Ok(TtyAnswer::Answer(Some(ipc::UiAnswer::Prompt(decision))));
+            }
+        },
+    }
+}
+
+/// Answer interactions from the server in the terminal, forever.
+async fn handle_interactions(handler: &mut ipc::msg::Client) -> Result<(), loga::Error> {
+    loop {
+        let question = client_req(handler, ipc::ReqUiNext).await?;
+        let id = question.id;
+        match spawn_blocking(move || tty_answer(question)).await.unwrap()? {
+            TtyAnswer::Notice => { },
+            TtyAnswer::Answer(answer) => {
+                client_req(handler, ipc::ReqUiAnswer {
+                    id: id,
+                    answer: answer,
+                }).await?;
+            },
+        }
+    }
+}
+
 async fn main2() -> Result<(), loga::Error> {
     let log = Log::new_root(loga::INFO);
     match spawn_blocking(|| vark::<Command>()).await.unwrap() {
@@ -386,12 +655,58 @@ async fn main2() -> Result<(), loga::Error> {
                 ).unwrap(),
             )?;
         },
-        Command::Unlock => {
-            req(ipc::ReqLock(ipc::LockAction::Unlock)).await?;
+        Command::Unlock(args) => {
+            if args.interactive.is_some() {
+                let mut handler = ipc::msg::Client::new(ipc_path()).await.map_err(loga::err)?;
+                client_req(&mut handler, ipc::ReqUiRegister)
+                    .await
+                    .context("Error registering to handle interactions")?;
+                select!{
+                    r = req(ipc::ReqLock(ipc::LockAction::Unlock)) => {
+                        r?;
+                    },
+                    r = handle_interactions(&mut handler) => {
+                        r?;
+                    }
+                }
+            } else {
+                req(ipc::ReqLock(ipc::LockAction::Unlock)).await?;
+            }
         },
         Command::Lock => {
             req(ipc::ReqLock(ipc::LockAction::Lock)).await?;
         },
+        Command::FactorChange(args) => {
+            let change_req = ipc::ReqFactorChange {
+                factor: args.factor,
+                change: match args.change {
+                    FactorChangeVariant::Password => ipc::FactorChange::Password,
+                    FactorChangeVariant::RecoveryPhrase => ipc::FactorChange::RecoveryPhrase,
+                    FactorChangeVariant::AddSmartcard(f) => ipc::FactorChange::AddSmartcard(f),
+                    FactorChangeVariant::RemoveSmartcard(f) => ipc::FactorChange::RemoveSmartcard(f),
+                },
+            };
+            if args.interactive.is_some() {
+                let mut handler = ipc::msg::Client::new(ipc_path()).await.map_err(loga::err)?;
+                client_req(&mut handler, ipc::ReqUiRegister)
+                    .await
+                    .context("Error registering to handle interactions")?;
+                select!{
+                    r = req(change_req) => {
+                        r?;
+                    },
+                    r = handle_interactions(&mut handler) => {
+                        r?;
+                    }
+                }
+            } else {
+                req(change_req).await?;
+            }
+        },
+        Command::MetaStatus => {
+            let res = req(ipc::ReqMetaStatus).await?;
+            output(serde_json::to_string_pretty(&res).unwrap())?;
+        },
         Command::MetaKeys(args) => {
             let mut res = req(ipc::ReqMetaKeys {
                 paths: vec![args.path.0.clone()],
@@ -418,6 +733,28 @@ async fn main2() -> Result<(), loga::Error> {
             }).await?;
             output(res)?;
         },
+        Command::Audit(args) => {
+            let res = req(ipc::ReqMetaAudit {
+                paths: args.paths.into_iter().map(|x| x.0).collect(),
+                start: args.start,
+                end: args.end,
+                binary: args.binary,
+            }).await?;
+            output(serde_json::to_string_pretty(&res).unwrap())?;
+        },
+        Command::Watch(args) => {
+            let mut client = ipc::msg::Client::new(ipc_path()).await.map_err(loga::err)?;
+            client_req(&mut client, ipc::ReqWatch { paths: args.paths.into_iter().map(|x| x.0).collect() }).await?;
+            let mut stdout = std::io::stdout();
+            loop {
+                let event = client_req(&mut client, ipc::ReqWatchNext).await?;
+                let line = serde_json::to_string(&event).unwrap();
+                if writeln!(stdout, "{}", line).and_then(|_| stdout.flush()).is_err() {
+                    // Reader went away
+                    break;
+                }
+            }
+        },
         Command::Read(args) => {
             let mut res = req(ipc::ReqRead {
                 paths: vec![args.path.0.clone()],
@@ -457,7 +794,10 @@ async fn main2() -> Result<(), loga::Error> {
             } else {
                 serde_json::Value::String(String::from_utf8(data).context("Error parsing value as UTF-8")?)
             };
-            req(ipc::ReqWrite(vec![(args.path.0, data)])).await?;
+            req(ipc::ReqWrite {
+                preconditions: preconditions(&args.path.0, args.if_absent.is_some(), args.if_rev_stamp),
+                values: vec![(args.path.0, data)],
+            }).await?;
         },
         Command::WriteEdit(args) => {
             const ENV_EDITOR: &str = "SECURE_EDITOR";
@@ -531,13 +871,17 @@ async fn main2() -> Result<(), loga::Error> {
                     }
                 },
             };
-            req(ipc::ReqWrite(vec![(args.path.0, data)])).await?;
+            req(ipc::ReqWrite {
+                values: vec![(args.path.0, data)],
+                preconditions: vec![],
+            }).await?;
         },
         Command::WriteMove(args) => {
             req(ipc::ReqWriteMove {
                 from: args.from.0,
                 to: args.to.0,
                 overwrite: args.overwrite.is_some(),
+                preconditions: vec![],
             }).await?;
         },
         Command::WriteRevert(args) => {
@@ -548,6 +892,7 @@ async fn main2() -> Result<(), loga::Error> {
         },
         Command::WriteGenerate(args) => {
             req(ipc::ReqWriteGenerate {
+                preconditions: preconditions(&args.path.0, false, args.if_rev_stamp),
                 path: args.path.0,
                 variant: match args.variant {
                     GenerateVariant::Bytes(args) => C2SGenerateVariant::Bytes(
@@ -582,9 +927,17 @@ async fn main2() -> Result<(), loga::Error> {
             }).await?;
             output(&res)?;
         },
+        Command::DeriveSshSign(args) => {
+            let res = req(ipc::ReqDeriveSshSign {
+                key: args.key.0,
+                namespace: args.namespace,
+                data: args.data.value,
+            }).await?;
+            output(res)?;
+        },
         Command::DeriveOtp(args) => {
             let res = req(ipc::ReqDeriveOtp { key: args.key.0 }).await?;
-            output(res)?;
+            output(res.token)?;
         },
         Command::ScanCards => {
             let mut card_stream = CardStream::new(&log);
@@ -594,7 +947,8 @@ async fn main2() -> Result<(), loga::Error> {
                     Err(e) => {
                         let e = match e {
                             passworth_native::error::UiErr::Internal(i) => i,
-                            passworth_native::error::UiErr::External(e, i) => {
+                            passworth_native::error::UiErr::External(e, i) |
+                            passworth_native::error::UiErr::Incorrect(e, i) => {
                                 i.unwrap_or(loga::err(&e))
                             },
                             passworth_native::error::UiErr::InternalUnresolvable(e) => e,
@@ -0,0 +1,186 @@
+//! Append-only record of permission decisions and what was done with them.
+use {
+    super::{
+        auditdb,
+        dbutil::tx,
+        permission::PrincipalMeta,
+        state::State,
+    },
+    chrono::{
+        DateTime,
+        Utc,
+    },
+    loga::ResultContext,
+    passworth::{
+        datapath::SpecificPath,
+        ipc,
+    },
+    serde::Serialize,
+    std::{
+        collections::BTreeSet,
+        str::FromStr,
+    },
+};
+
+/// Accumulates the permission decisions made while handling a single request.
+#[derive(Default)]
+pub struct AuditDecision {
+    pub decided: bool,
+    pub paths: Vec<SpecificPath>,
+    /// Ids (config index) of rules that matched the principal.
+    pub rules: BTreeSet<usize>,
+    /// `None` if no prompt was shown, otherwise whether the user approved.
+    pub prompt: Option<bool>,
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditResult {
+    Ok,
+    Unauthorized,
+    /// The user rejected the prompt, or rejected it earlier and that was remembered.
+    Rejected,
+    /// Failed because the database was locked and the client asked not to unlock.
+    Locked,
+    /// Failed because a write precondition didn't hold.
+    Conflict,
+    Error,
+}
+
+pub fn req_variant(req: &ipc::msg::ServerReq) -> &'static str {
+    match req {
+        ipc::msg::ServerReq::Tag(..) => return "tag",
+        ipc::msg::ServerReq::Lock(..) => return "lock",
+        ipc::msg::ServerReq::FactorChange(..) => return "factor_change",
+        ipc::msg::ServerReq::NoUnlock(..) => return "no_unlock",
+        ipc::msg::ServerReq::MetaStatus(..) => return "meta_status",
+        ipc::msg::ServerReq::MetaKeys(..) => return "meta_keys",
+        ipc::msg::ServerReq::MetaRevisions(..) => return "meta_revisions",
+        ipc::msg::ServerReq::MetaPgpPubkey(..) => return "meta_pgp_pubkey",
+        ipc::msg::ServerReq::MetaSshPubkey(..) => return "meta_ssh_pubkey",
+        ipc::msg::ServerReq::MetaAudit(..) => return "meta_audit",
+        ipc::msg::ServerReq::Read(..) => return "read",
+        ipc::msg::ServerReq::Write(..) => return "write",
+        ipc::msg::ServerReq::WriteMove(..) => return "write_move",
+        ipc::msg::ServerReq::WriteGenerate(..) => return "write_generate",
+        ipc::msg::ServerReq::WriteRevert(..) => return "write_revert",
+        ipc::msg::ServerReq::Batch(..) => return "batch",
+        ipc::msg::ServerReq::DerivePgpSign(..) => return "derive_pgp_sign",
+        ipc::msg::ServerReq::DerivePgpDecrypt(..) => return "derive_pgp_decrypt",
+        ipc::msg::ServerReq::DeriveSshSign(..) => return "derive_ssh_sign",
+        ipc::msg::ServerReq::DeriveOtp(..) => return "derive_otp",
+        ipc::msg::ServerReq::UiRegister(..) => return "ui_register",
+        ipc::msg::ServerReq::UiNext(..) => return "ui_next",
+        ipc::msg::ServerReq::UiAnswer(..) => return "ui_answer",
+        ipc::msg::ServerReq::Watch(..) => return "watch",
+        ipc::msg::ServerReq::WatchNext(..) => return "watch_next",
+    }
+}
+
+/// Record a request that involved a permission decision. Requests with no decision
+/// (ex: tagging) are skipped.
+pub async fn record(
+    state: &State,
+    variant: &str,
+    principal: &PrincipalMeta,
+    decision: AuditDecision,
+    result: AuditResult,
+) -> Result<(), loga::Error> {
+    if !decision.decided {
+        return Ok(());
+    }
+    let conn = rusqlite::Connection::open(&state.auditdb_path).context("Error opening audit database")?;
+    let variant = variant.to_string();
+    let paths = serde_json::to_string(&decision.paths.iter().map(|x| x.to_string()).collect::<Vec<_>>()).unwrap();
+    let principal = serde_json::to_string(principal).unwrap();
+    let rules = serde_json::to_string(&decision.rules).unwrap();
+    let prompt = match decision.prompt {
+        None => "none",
+        Some(true) => "approved",
+        Some(false) => "rejected",
+    };
+    let result = serde_json::to_value(&result).unwrap().as_str().unwrap().to_string();
+    tx(conn, move |txn| {
+        auditdb::audit_insert(txn, Utc::now(), &variant, &paths, &principal, &rules, prompt, &result)?;
+        return Ok(());
+    }).await?;
+    return Ok(());
+}
+
+/// Returns audit entries in chronological order, filtered by the query. An entry
+/// matches a path filter if any of its paths are at or below one of the filter
+/// paths, and matches a binary filter if any process in the principal chain ran
+/// that binary.
+///
+/// Only paths `visible` to the caller are returned. Entries with no visible paths
+/// are omitted, and entries with some hidden paths have their principal removed.
+pub async fn query(
+    state: &State,
+    req: ipc::ReqMetaAudit,
+    visible: impl Fn(&SpecificPath) -> bool,
+) -> Result<serde_json::Value, loga::Error> {
+    let start = match &req.start {
+        Some(s) => DateTime::parse_from_rfc3339(s).context("Invalid start time")?.with_timezone(&Utc),
+        None => DateTime::<Utc>::MIN_UTC,
+    };
+    let end = match &req.end {
+        Some(s) => DateTime::parse_from_rfc3339(s).context("Invalid end time")?.with_timezone(&Utc),
+        None => DateTime::<Utc>::MAX_UTC,
+    };
+    let conn = rusqlite::Connection::open(&state.auditdb_path).context("Error opening audit database")?;
+    let rows = tx(conn, move |txn| {
+        return Ok(auditdb::audit_list(txn, start, end)?);
+    }).await?;
+    let mut out = vec![];
+    for row in rows {
+        let paths = serde_json::from_str::<Vec<String>>(&row.paths).unwrap();
+        if !req.paths.is_empty() {
+            let mut matched = false;
+            for path in &paths {
+                let Ok(path) = SpecificPath::from_str(path) else {
+                    continue;
+                };
+                if req.paths.iter().any(|filter| path.0.starts_with(&filter.0)) {
+                    matched = true;
+                    break;
+                }
+            }
+            if !matched {
+                continue;
+            }
+        }
+        let mut principal = serde_json::from_str::<serde_json::Value>(&row.principal).unwrap();
+        if let Some(binary) = &req.binary {
+            let matched =
+                principal
+                    .get("chain")
+                    .and_then(|x| x.as_array())
+                    .map(|chain| chain.iter().any(|proc| proc.get("binary").and_then(|x| x.as_str()) == Some(binary)))
+                    .unwrap_or(false);
+            if !matched {
+                continue;
+            }
+        }
+        let visible_paths = paths.iter().filter(|path| match SpecificPath::from_str(path) {
+            Ok(path) => visible(&path),
+            Err(_) => false,
+        }).cloned().collect::<Vec<_>>();
+        if visible_paths.is_empty() {
+            continue;
+        }
+        if visible_paths.len() < paths.len() {
+            principal = serde_json::Value::Null;
+        }
+        out.push(serde_json::json!({
+            "id": row.id,
+            "stamp": row.stamp.to_rfc3339(),
+            "variant": row.variant,
+            "paths": visible_paths,
+            "principal": principal,
+            "rules": serde_json::from_str::<serde_json::Value>(&row.rules).unwrap(),
+            "prompt": row.prompt,
+            "result": row.result,
+        }));
+    }
+    return Ok(serde_json::Value::Array(out));
+}
@@ -1,16 +1,22 @@
 use {
     super::privdb,
+    chrono::Utc,
+    flowcontrol::exenum,
     libc::c_void,
     loga::{
         ea,
         ErrContext,
         ResultContext,
     },
+    passworth::datapath::SpecificPath,
     rusqlite::{
         Connection,
         Transaction,
     },
-    std::path::Path,
+    std::{
+        path::Path,
+        str::FromStr,
+    },
     tokio::task::spawn_blocking,
 };
 
@@ -41,6 +47,31 @@ pub async fn tx<
     }).await?;
 }
 
+/// Like `tx` for operations with their own error type. The transaction is rolled
+/// back if the operation fails; the outer error is for failures of the transaction
+/// itself.
+pub async fn tx_res<
+    T: 'static + Send,
+    E: 'static + Send,
+>(
+    mut conn: Connection,
+    f: impl 'static + Send + FnOnce(&mut Transaction) -> Result<T, E>,
+) -> Result<Result<T, E>, loga::Error> {
+    return spawn_blocking(move || {
+        let mut txn = conn.transaction()?;
+        match f(&mut txn) {
+            Ok(r) => {
+                txn.commit().context("Transaction commit failed")?;
+                return Ok(Ok(r));
+            },
+            Err(e) => {
+                txn.rollback().context("Error rolling back transaction")?;
+                return Ok(Err(e));
+            },
+        }
+    }).await?;
+}
+
 pub fn open_privdb(path: &Path, token: &str) -> Result<Connection, loga::Error> {
     let mut privdbc = rusqlite::Connection::open(&path).unwrap();
     let token = token.as_bytes();
@@ -53,3 +84,86 @@ pub fn open_privdb(path: &Path, token: &str) -> Result<Connection, loga::Error>
     privdb::migrate(&mut privdbc).context("Error migrating private db")?;
     return Ok(privdbc);
 }
+
+pub fn bury(root: &mut serde_json::Value, path: &SpecificPath, value: serde_json::Value) {
+    let mut at = root;
+    for seg in &path.0 {
+        match &at {
+            serde_json::Value::Object(_) => (),
+            _ => {
+                *at = serde_json::Value::Object(serde_json::Map::new());
+            },
+        }
+        let o = exenum!(at, serde_json:: Value:: Object(o) => o).unwrap();
+        let next = o.entry(seg).or_insert(serde_json::Value::Null);
+        at = next;
+    }
+    *at = value;
+}
+
+pub fn set(txn: &mut Transaction, pairs: Vec<(SpecificPath, serde_json::Value)>) -> Result<(), loga::Error> {
+    let now = Utc::now();
+    for (mut path, value) in pairs {
+        // Clear out everything above and below that would be shaded by this
+        if !path.0.is_empty() {
+            for i in 0 .. path.0.len() - 1 {
+                // If setting at `/a/b/c = { .. }` and a value exists at e.g. `/a = 4`
+                let parent_path = SpecificPath(path.0[..i].iter().cloned().collect()).to_string();
+                if privdb::values_get_exact(txn, &parent_path, i64::MAX)?
+                    .filter(
+                        |x| serde_json::from_str::<serde_json::Value>(&x.data).unwrap() != serde_json::Value::Null,
+                    )
+                    .is_some() {
+                    privdb::values_insert(
+                        txn,
+                        now,
+                        &parent_path,
+                        &serde_json::to_string(&serde_json::Value::Null).unwrap(),
+                    )?;
+                }
+            }
+        }
+        for row in privdb::values_get(txn, &path.to_string(), i64::MAX)? {
+            // If setting at `/a/b/c` and a value exists at e.g. `/a/b/c/d`
+            privdb::values_insert(txn, now, &row.path, &serde_json::to_string(&serde_json::Value::Null).unwrap())?;
+        }
+
+        // Add the new data
+        let mut stack = vec![(None as Option<String>, value, true)];
+        while let Some((seg, at, descending)) = stack.pop() {
+            if descending {
+                if let Some(seg) = &seg {
+                    path.0.push(seg.clone());
+                }
+                stack.push((seg, at.clone(), false));
+                match &at {
+                    serde_json::Value::Object(o) => {
+                        for (k, v) in o {
+                            stack.push((Some(k.to_string()), v.clone(), true));
+                        }
+                    },
+                    _ => {
+                        privdb::values_insert(txn, now, &path.to_string(), &serde_json::to_string(&at).unwrap())?;
+                    },
+                }
+            } else {
+                if seg.is_some() {
+                    path.0.pop();
+                }
+            }
+        }
+    }
+    return Ok(());
+}
+
+pub fn get(txn: &mut Transaction, path: &SpecificPath, at: Option<i64>) -> Result<serde_json::Value, loga::Error> {
+    let mut root = serde_json::Value::Null;
+    for row in privdb::values_get(txn, &path.to_string(), at.unwrap_or(i64::MAX))? {
+        let data = serde_json::from_str::<serde_json::Value>(&row.data).unwrap();
+        if data == serde_json::Value::Null {
+            continue;
+        }
+        bury(&mut root, &SpecificPath(SpecificPath::from_str(&row.path).unwrap().0.split_off(path.0.len())), data);
+    }
+    return Ok(root);
+}
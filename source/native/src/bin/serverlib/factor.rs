@@ -6,7 +6,10 @@ use {
     passworth_native::config::latest::{
         ConfigAuthFactor,
         ConfigAuthFactorVariant,
+        ConfigCredFido2,
+        ConfigCredKeyfile,
         ConfigCredSmartcards,
+        ConfigCredTpm,
     },
     std::{
         collections::{
@@ -28,9 +31,14 @@ pub struct FactorTree {
 pub enum FactorTreeVariant {
     And(Vec<Arc<FactorTree>>),
     Or(Vec<Arc<FactorTree>>),
+    /// Required count, children
+    Threshold(usize, Vec<Arc<FactorTree>>),
     Password,
     Smartcards(ConfigCredSmartcards),
     RecoveryPhrase,
+    Tpm(ConfigCredTpm),
+    Fido2(ConfigCredFido2),
+    Keyfile(ConfigCredKeyfile),
 }
 
 pub fn build_factor_tree(
@@ -88,6 +96,39 @@ pub fn build_factor_tree(
             }
             variant = FactorTreeVariant::Or(out);
         },
+        ConfigAuthFactorVariant::Threshold(t) => {
+            let mut out = vec![];
+            let mut errs = vec![];
+            if t.children.is_empty() {
+                return Err(log.err("Factor's child list is empty"));
+            }
+            if t.children.len() > 255 {
+                return Err(log.err("Threshold factors can have at most 255 children"));
+            }
+            if t.k < 1 || t.k > t.children.len() {
+                return Err(
+                    loga::err_with(
+                        "Threshold k must be between 1 and the number of children",
+                        ea!(factor = at_id, k = t.k, children = t.children.len()),
+                    ),
+                );
+            }
+            if t.children.iter().collect::<HashSet<_>>().len() != t.children.len() {
+                return Err(log.err("Threshold factor's child list has duplicates"));
+            }
+            for child_id in &t.children {
+                match build_factor_tree(&seen, config_factors, out_factors, &child_id) {
+                    Ok(c) => out.push(c),
+                    Err(e) => {
+                        errs.push(e);
+                    },
+                }
+            }
+            if !errs.is_empty() {
+                return Err(log.agg_err("Errors processing children of factor", errs));
+            }
+            variant = FactorTreeVariant::Threshold(t.k, out);
+        },
         ConfigAuthFactorVariant::Password => {
             variant = FactorTreeVariant::Password;
         },
@@ -97,6 +138,23 @@ pub fn build_factor_tree(
         ConfigAuthFactorVariant::RecoveryCode => {
             variant = FactorTreeVariant::RecoveryPhrase;
         },
+        ConfigAuthFactorVariant::Tpm(t) => {
+            if let Some(pcrs) = &t.pcrs {
+                if let Some(pcr) = pcrs.iter().find(|x| **x > 23) {
+                    return Err(loga::err_with("TPM PCR index out of range (0-23)", ea!(factor = at_id, pcr = pcr)));
+                }
+            }
+            variant = FactorTreeVariant::Tpm(t.clone());
+        },
+        ConfigAuthFactorVariant::Fido2(f) => {
+            variant = FactorTreeVariant::Fido2(f.clone());
+        },
+        ConfigAuthFactorVariant::Keyfile(k) => {
+            if k.path.as_os_str().is_empty() {
+                return Err(log.err("Keyfile path is empty"));
+            }
+            variant = FactorTreeVariant::Keyfile(k.clone());
+        },
     };
     let out = Arc::new(FactorTree {
         id: at.id.clone(),
@@ -106,3 +164,22 @@ pub fn build_factor_tree(
     out_factors.insert(at_id.clone(), out.clone());
     return Ok(out);
 }
+
+/// Find a factor in the tree by id.
+pub fn find_factor(root: &Arc<FactorTree>, id: &str) -> Option<Arc<FactorTree>> {
+    let mut stack = vec![root.clone()];
+    while let Some(at) = stack.pop() {
+        if at.id == id {
+            return Some(at);
+        }
+        match &at.variant {
+            FactorTreeVariant::And(children) |
+            FactorTreeVariant::Or(children) |
+            FactorTreeVariant::Threshold(_, children) => {
+                stack.extend(children.iter().cloned());
+            },
+            _ => { },
+        }
+    }
+    return None;
+}
@@ -1,7 +1,26 @@
 use {
     super::{
         dbutil::open_privdb,
+        grants::{
+            PromptDecision,
+            PROMPT_GRANT_DURATION,
+        },
+        fido2::{
+            self,
+            Fido2State,
+        },
+        keyfile::{
+            keyfile_path,
+            read_keyfile,
+        },
+        pidfd::Inode,
         privdb,
+        pubdb,
+        tpm::{
+            self,
+            TpmSealed,
+        },
+        unlockbackoff,
     },
     crate::{
         FactorTree,
@@ -43,14 +62,26 @@ use {
         ResultContext,
     },
     openpgp_card_sequoia::state::Open,
+    passworth::{
+        datapath::SpecificPath,
+        ipc::FactorChange,
+    },
     passworth_native::{
-        config::latest::ConfigCredSmartcards,
+        config::latest::{
+            ConfigCredKeyfile,
+            ConfigCredSmartcards,
+            ConfigCredTpm,
+        },
         crypto::{
             self,
             get_card_pubkey,
             local_decrypt,
             local_encrypt,
+            unwrap_token,
+            wrap_token,
             CardStream,
+            Kdf,
+            WrappedToken,
         },
         error::{
             FromAnyErr,
@@ -68,6 +99,10 @@ use {
         RngCore,
     },
     rusqlite::Connection,
+    sharks::{
+        Share,
+        Sharks,
+    },
     sequoia_openpgp::serialize::stream::{
         Encryptor2,
         LiteralWriter,
@@ -85,12 +120,12 @@ use {
         },
         future::Future,
         io::Write,
-        path::PathBuf,
-        rc::Rc,
-        sync::{
-            Arc,
-            Mutex,
+        path::{
+            Path,
+            PathBuf,
         },
+        rc::Rc,
+        sync::Arc,
         time::{
             Duration,
             Instant,
@@ -127,7 +162,6 @@ fn add_shortcut(w: &impl gtk4::glib::object::IsA<gtk4::Widget>, keys: &[Key], f:
 
 pub struct FgState {
     pub log: Log,
-    pub last_prompts: Mutex<HashMap<usize, Instant>>,
 }
 
 async fn do_form_dialog<
@@ -342,9 +376,9 @@ async fn ui_get_smartcard(
     };
 }
 
-async fn ui_unlock_smartcard(
+async fn unlock_smartcard<U: FgUi>(
     log: &Log,
-    app: &Application,
+    ui: &U,
     initial_warning: Option<String>,
     title: Title,
     config: &ConfigCredSmartcards,
@@ -358,80 +392,17 @@ async fn ui_unlock_smartcard(
         match async {
             // Pin entry
             let pin = if !config.fixed_pin {
-                const KEYS: &[&[Key]] =
-                    &[
-                        &[Key::Arabic_0, Key::KP_0, Key::N, Key::B],
-                        &[Key::Arabic_1, Key::KP_1, Key::M, Key::X],
-                        &[Key::Arabic_2, Key::KP_2, Key::comma, Key::C],
-                        &[Key::Arabic_3, Key::KP_3, Key::period, Key::V],
-                        &[Key::Arabic_4, Key::KP_4, Key::J, Key::S],
-                        &[Key::Arabic_5, Key::KP_5, Key::K, Key::D],
-                        &[Key::Arabic_6, Key::KP_6, Key::L, Key::F],
-                        &[Key::Arabic_7, Key::KP_7, Key::U, Key::W],
-                        &[Key::Arabic_8, Key::KP_8, Key::I, Key::E],
-                        &[Key::Arabic_9, Key::KP_9, Key::O, Key::R],
-                    ];
-                let layout = vbox();
-                attach_error(&layout, warning.take());
-                layout.append(&label("Enter card PIN."));
-                let digits =
-                    gtk4::PasswordEntry::builder().hexpand(true).show_peek_icon(true).can_focus(false).build();
-                let pin_layout = grid();
-                for i in -1 .. 9 as i32 {
-                    let column = if i == -1 {
-                        0
-                    } else {
-                        i.rem_euclid(3)
-                    };
-                    let row = 3 - i.div_floor(3);
-                    let value = (i + 1) as usize;
-                    let button = gtk4::Button::builder().label(value.to_string()).can_focus(false).build();
-                    button.connect_clicked({
-                        let digits = digits.clone();
-                        move |_button| {
-                            digits.set_text(&format!("{}{}", digits.text().as_str(), value.to_string()));
-                        }
-                    });
-                    add_shortcut(&button, KEYS[value], {
-                        let button = button.clone();
-                        move || button.emit_clicked()
-                    });
-                    pin_layout.attach(&button, column, row, 1, 1);
-                }
-                {
-                    let button = gtk4::Button::builder().icon_name("edit-clear").can_focus(false).build();
-                    button.connect_clicked({
-                        let digits = digits.clone();
-                        move |_button| {
-                            digits.set_text("");
-                        }
-                    });
-                    add_shortcut(&button, &[Key::BackSpace, Key::Delete], {
-                        let button = button.clone();
-                        move || button.emit_clicked()
-                    });
-                    pin_layout.attach(&button, 1, 4, 1, 1);
-                }
-                layout.append(&halign_center(pin_layout));
-                layout.append(&digits);
-                let pin = match do_form_dialog(&app, warning.take(), title.clone(), &layout, None, {
-                    let digits = digits.clone();
-                    move || {
-                        return Ok(digits.text().to_string());
-                    }
-                }).await {
-                    Some(x) => x,
+                match ui.smartcard_pin(warning.take(), title.clone()).await {
+                    Some(x) => Some(x),
                     None => {
                         return Ok(None);
                     },
-                };
-                Some(pin)
+                }
             } else {
                 None
             };
             let card =
-                match ui_get_smartcard(
-                    app,
+                match ui.get_smartcard(
                     &mut card_stream,
                     warning.take(),
                     title.clone(),
@@ -443,37 +414,25 @@ async fn ui_unlock_smartcard(
                     },
                 };
             let (card, pubkey) = get_card_pubkey(card).await?;
-            let Some(card_config) =
-                config.smartcards.iter().filter(|c| c.fingerprint == pubkey.fingerprint().to_string()).next() else {
-                    return Ok(None);
-                };
-            let card = card.enter_pin(card_config.pin.clone().or(pin)).await?;
-            match card
-                .decrypt(
-                    state
-                        .get(&card_config.fingerprint)
-                        .context_with(
-                            "Missing stored state for smartcard with fingerprint",
-                            ea!(fingerprint = card_config.fingerprint),
-                        )?
-                        .clone(),
-                )
-                .await? {
+            let fingerprint = pubkey.fingerprint().to_string();
+            let Some(encrypted) = state.get(&fingerprint) else {
+                return Err(UiErr::External(format!("Smartcard had the wrong fingerprint: {}", fingerprint), None));
+            };
+
+            // Cards added at runtime aren't in the config and always use the entered pin
+            let config_pin =
+                config.smartcards.iter().filter(|c| c.fingerprint == fingerprint).next().and_then(|c| c.pin.clone());
+            let card = card.enter_pin(config_pin.or(pin)).await?;
+            match card.decrypt(encrypted.clone()).await? {
                 crypto::MaybeNeedTouch::NeedTouch(card) => {
-                    let layout = vbox();
-                    if let Some(warning) = warning.take() {
-                        layout.append(&label(&warning));
-                    }
-                    layout.append(
-                        &label(&format!("Confirm the action on the smartcard {}", card_config.fingerprint)),
-                    );
-                    return select!{
-                        _ = ui_window(&app, title.clone(), &layout) => Ok(None),
-                        r = card.wait_for_touch() => {
-                            let (_, decrypted) = r?;
-                            Ok(Some(decrypted))
-                        },
-                    };
+                    let Some(r) =
+                        ui
+                            .smartcard_touch(warning.take(), title.clone(), &fingerprint, card.wait_for_touch())
+                            .await else {
+                            return Ok(None);
+                        };
+                    let (_, decrypted) = r?;
+                    return Ok(Some(decrypted));
                 },
                 crypto::MaybeNeedTouch::Decryptor(_, decrypted) => {
                     return Ok(Some(decrypted));
@@ -487,7 +446,7 @@ async fn ui_unlock_smartcard(
                     warning = Some("Internal error, check logs for details.".to_string());
                     continue;
                 },
-                UiErr::External(e, i) => {
+                UiErr::External(e, i) | UiErr::Incorrect(e, i) => {
                     if let Some(i) = i {
                         log.log_err(loga::WARN, i);
                     }
@@ -502,6 +461,190 @@ async fn ui_unlock_smartcard(
     }
 }
 
+/// Wait for a card with one of the fingerprints and encrypt the token to it. Returns
+/// the card's fingerprint and the encrypted token.
+async fn enroll_smartcard<
+    U: FgUi,
+>(
+    ui: &U,
+    card_stream: &mut CardStream,
+    mut warning: Option<String>,
+    title: Title,
+    fingerprints: &HashSet<String>,
+    token: &[u8],
+) -> Result<Option<(String, Vec<u8>)>, UiErr> {
+    loop {
+        let Some(mut card) =
+            ui.get_smartcard(
+                card_stream,
+                warning.take(),
+                title.clone(),
+                &fingerprints.iter().map(|x| x.as_str()).collect::<Vec<_>>(),
+            ).await else {
+                return Ok(None);
+            };
+        let mut card_tx = card.transaction().context("Error starting smartcard transaction")?;
+        let Some(pubkey) =
+            card_tx
+                .public_key(openpgp_card_sequoia::types::KeyType::Decryption)
+                .map_err(|e| loga::err(e.to_string()))? else {
+                warning = Some("Couldn't determine public key for card.".to_string());
+                continue;
+            };
+        let fingerprint = pubkey.fingerprint().to_string();
+        if !fingerprints.contains(&fingerprint) {
+            warning = Some(format!("Smartcard had the wrong fingerprint: {}", fingerprint));
+            continue;
+        };
+        let mut sink = vec![];
+        let message = Message::new(&mut sink);
+        let message =
+            Encryptor2::for_recipients(message, vec![Recipient::from(&pubkey)])
+                .build()
+                .map_err(|e| loga::err(e.to_string()))
+                .context("Error building encryptor")?;
+        let mut w =
+            LiteralWriter::new(message)
+                .build()
+                .map_err(|e| loga::err(e.to_string()))
+                .context("Error forming encryption writer")?;
+        w.write_all(token).context("Error streaming data to encrypt")?;
+        w.finalize().map_err(|e| loga::err(e.to_string())).context("Error finalizing encryption")?;
+        return Ok(Some((fingerprint, sink)));
+    }
+}
+
+/// Generate a recovery phrase, show it, and have the user confirm it.
+async fn new_recovery_phrase<U: FgUi>(ui: &U, warning: Option<String>, title: Title) -> Result<Option<Vec<u8>>, UiErr> {
+    // Show
+    let phrase = generate::gen_bip39();
+    let Some(_) = ui.show_recovery_phrase(warning, title.clone(), &phrase).await else {
+        return Ok(None);
+    };
+    let phrase = phrase.join(" ").as_bytes().to_vec();
+
+    // Confirm
+    let Some(confirm_passphrase) = ui.recovery_phrase(None, title, "Confirm the phrase.").await else {
+        return Ok(None);
+    };
+    if confirm_passphrase != phrase {
+        return Err(
+            UiErr::external("Recovery phrases didn't match; make sure you wrote down the correct order as well."),
+        );
+    }
+    return Ok(Some(phrase));
+}
+
+/// Unsealing doesn't involve the user, so failures abort instead of retrying.
+async fn unseal_tpm(log: &Log, config: &ConfigCredTpm, sealed: TpmSealed) -> Result<Vec<u8>, UiErr> {
+    let token = gtk4::gio::spawn_blocking({
+        let config = config.clone();
+        move || tpm::unseal(&config, &sealed)
+    }).await.any_context()?.stack_context(log, "Error unsealing token with TPM")?;
+    let Some(token) = token else {
+        return Err(
+            UiErr::InternalUnresolvable(
+                log.err("The TPM refused to unseal the token; the boot state (PCR values) may have changed"),
+            ),
+        );
+    };
+    return Ok(token);
+}
+
+/// Run a blocking authenticator operation while asking the user to touch the key.
+/// Failures (ex: the wrong key) are shown to the user so they can retry.
+async fn fido2_touch_blocking<
+    U: FgUi,
+    T: 'static + Send,
+>(
+    ui: &U,
+    warning: Option<String>,
+    title: Title,
+    desc: &str,
+    f: impl 'static + Send + FnOnce() -> Result<T, loga::Error>,
+) -> Result<Option<T>, UiErr> {
+    let Some(r) = ui.fido2_touch(warning, title, desc, gtk4::gio::spawn_blocking(f)).await else {
+        return Ok(None);
+    };
+    return Ok(Some(r.any_context()?.to_ui_err_external("Error communicating with security key")?));
+}
+
+/// Read the keyfile, waiting for it to appear if it's missing.
+async fn wait_keyfile<
+    U: FgUi,
+>(ui: &U, warning: Option<String>, title: Title, config: &ConfigCredKeyfile) -> Result<Option<Vec<u8>>, UiErr> {
+    let path = keyfile_path(config);
+    if let Some(token) = gtk4::gio::spawn_blocking({
+        let path = path.clone();
+        move || read_keyfile(&path)
+    }).await.any_context()?? {
+        return Ok(Some(token));
+    }
+    let Some(token) = ui.get_keyfile(warning, title, &path, {
+        let path = path.clone();
+        async move {
+            loop {
+                let path = path.clone();
+                let Some(token) = gtk4::gio::spawn_blocking(move || {
+                    std::thread::sleep(Duration::from_secs(1));
+                    return read_keyfile(&path);
+                }).await.any_context()?? else {
+                    continue;
+                };
+                return Ok(token) as Result<_, loga::Error>;
+            }
+        }
+    }).await else {
+        return Ok(None);
+    };
+    return Ok(Some(token?));
+}
+
+/// Protect the token of a factor the user enters a secret for (password, recovery
+/// phrase). Returns the factor state.
+async fn wrap_secret(secret: Vec<u8>, token: Vec<u8>) -> Result<Vec<u8>, UiErr> {
+    let wrapped = gtk4::gio::spawn_blocking(move || wrap_token(Kdf::new(), &secret, &token)).await.any_context()??;
+    return Ok(serde_json::to_vec(&wrapped).unwrap());
+}
+
+/// Get the token of a factor the user entered a secret for, plus new state if the
+/// stored KDF parameters are outdated. Factors set up before KDFs were used have no
+/// state and use the secret as the token, so the new state for those wraps the
+/// secret itself to keep the token the same. The secret is still used directly
+/// above the factor (as the database key for a root factor) after this; only
+/// replacing the factor fixes that.
+async fn unwrap_secret(
+    log: &Log,
+    state: Option<&Vec<u8>>,
+    secret: Vec<u8>,
+    incorrect: String,
+) -> Result<(Vec<u8>, Option<Vec<u8>>), UiErr> {
+    let Some(state) = state else {
+        log.log(
+            loga::WARN,
+            "Factor predates secret wrapping so its secret is its token, replace the factor (ex: change its id)",
+        );
+        let upgrade = wrap_secret(secret.clone(), secret.clone()).await?;
+        return Ok((secret, Some(upgrade)));
+    };
+    let wrapped =
+        serde_json::from_slice::<WrappedToken>(state).stack_context(log, "Unable to deserialize secret factor state")?;
+    let Some((token, upgrade)) = gtk4::gio::spawn_blocking(move || {
+        let Some(token) = unwrap_token(&wrapped, &secret)? else {
+            return Ok(None);
+        };
+        let upgrade = if wrapped.kdf.is_current() {
+            None
+        } else {
+            Some(wrap_token(Kdf::new(), &secret, &token)?)
+        };
+        return Ok(Some((token, upgrade))) as Result<_, loga::Error>;
+    }).await.any_context()?? else {
+        return Err(UiErr::Incorrect(incorrect, None));
+    };
+    return Ok((token, upgrade.map(|x| serde_json::to_vec(&x).unwrap())));
+}
+
 #[derive(Clone)]
 enum Either {
     /// New, optionally old - old is only used for unlocking via old creds when
@@ -536,6 +679,9 @@ struct DoCredsRes {
     prev_tokens: HashMap<String, Vec<u8>>,
     new_tokens: HashMap<String, Vec<u8>>,
     store_state: HashMap<String, Vec<u8>>,
+    /// New state for previous factors with outdated KDF parameters, to store if the
+    /// unlock succeeds.
+    upgrade_state: HashMap<String, Vec<u8>>,
 }
 
 fn label(text: &str) -> Label {
@@ -580,11 +726,12 @@ fn halign_end<T: gtk4::glib::object::IsA<gtk4::Widget>>(w: T) -> T {
 /// Exits when manually closed. Closes window when dropped.
 async fn ui_window(app: &Application, title: Title, body: &impl gtk4::glib::object::IsA<gtk4::Widget>) {
     let (close_tx, close_rx) = oneshot::channel();
-    let window = gtk4::ApplicationWindow::builder().application(app).title(&format!("Passworth - {}", match title {
-        Title::Initialize(x) => format!("Initialize [{}]", x),
-        Title::Unlock(x) => format!("Unlock [{}]", x),
-        Title::Prompt(x) => format!("Prompt [{}]", x),
-    })).resizable(false).build();
+    let window =
+        gtk4::ApplicationWindow::builder()
+            .application(app)
+            .title(&format!("Passworth - {}", title.text()))
+            .resizable(false)
+            .build();
     window.init_layer_shell();
     window.set_layer(gtk4_layer_shell::Layer::Overlay);
     window.set_keyboard_mode(gtk4_layer_shell::KeyboardMode::Exclusive);
@@ -645,25 +792,450 @@ async fn ui_window(app: &Application, title: Title, body: &impl gtk4::glib::obje
     close_rx.await.ignore();
 }
 
+/// The user interaction primitives used by unlock, initialization, and prompts. Each
+/// returns `None` if the user aborted.
+pub trait FgUi: Clone {
+    async fn choose(
+        &self,
+        warning: Option<String>,
+        title: Title,
+        choices: &Vec<Arc<FactorTree>>,
+    ) -> Option<Arc<FactorTree>>;
+    async fn password(&self, warning: Option<String>, title: Title, desc: &str) -> Option<Vec<u8>>;
+    /// Get a new password, confirmed.
+    async fn new_password(&self, warning: Option<String>, title: Title, desc: &str) -> Option<Vec<u8>>;
+    /// Show a newly generated phrase for the user to write down.
+    async fn show_recovery_phrase(&self, warning: Option<String>, title: Title, phrase: &[String]) -> Option<()>;
+    async fn recovery_phrase(&self, warning: Option<String>, title: Title, message: &str) -> Option<Vec<u8>>;
+    async fn smartcard_pin(&self, warning: Option<String>, title: Title) -> Option<String>;
+    async fn get_smartcard(
+        &self,
+        card_stream: &mut CardStream,
+        warning: Option<String>,
+        title: Title,
+        fingerprints: &[&str],
+    ) -> Option<openpgp_card_sequoia::Card<Open>>;
+    /// Tell the user to touch the card while `touch` waits for it.
+    async fn smartcard_touch<T>(
+        &self,
+        warning: Option<String>,
+        title: Title,
+        fingerprint: &str,
+        touch: impl Future<Output = T>,
+    ) -> Option<T>;
+    /// Tell the user to touch the FIDO2 key while `touch` waits for it.
+    async fn fido2_touch<T>(
+        &self,
+        warning: Option<String>,
+        title: Title,
+        desc: &str,
+        touch: impl Future<Output = T>,
+    ) -> Option<T>;
+    /// Tell the user the keyfile is missing while `wait` waits for it to appear.
+    async fn get_keyfile<T>(
+        &self,
+        warning: Option<String>,
+        title: Title,
+        path: &Path,
+        wait: impl Future<Output = T>,
+    ) -> Option<T>;
+    /// Tell the user unlocking is delayed by `delay` due to failed attempts while
+    /// `wait` waits it out.
+    async fn backoff<T>(
+        &self,
+        warning: Option<String>,
+        title: Title,
+        delay: Duration,
+        wait: impl Future<Output = T>,
+    ) -> Option<T>;
+    async fn prompt(&self, args: Arc<B2FPrompt>) -> Result<Option<PromptDecision>, loga::Error>;
+}
+
+#[derive(Clone)]
+pub struct GtkUi {
+    pub log: Log,
+    pub app: Application,
+}
+
+impl FgUi for GtkUi {
+    async fn choose(
+        &self,
+        warning: Option<String>,
+        title: Title,
+        choices: &Vec<Arc<FactorTree>>,
+    ) -> Option<Arc<FactorTree>> {
+        return ui_choose(&self.app, warning, title, choices).await;
+    }
+
+    async fn password(&self, warning: Option<String>, title: Title, desc: &str) -> Option<Vec<u8>> {
+        let layout = vbox();
+        attach_error(&layout, warning);
+        layout.append(&label(&format!("Enter password for {}.", desc)));
+        let form_layout = grid();
+        form_layout.attach(&halign_end(label("Password")), 1, 1, 1, 1);
+        let password = {
+            let password = gtk4::PasswordEntry::builder().hexpand(true).build();
+            form_layout.attach(&password, 2, 1, 1, 1);
+            password
+        };
+        layout.append(&form_layout);
+        return do_form_dialog(&self.app, None, title, &layout, None, move || {
+            return Ok(password.text().as_bytes().to_vec());
+        }).await;
+    }
+
+    async fn new_password(&self, warning: Option<String>, title: Title, desc: &str) -> Option<Vec<u8>> {
+        let layout = vbox();
+        attach_error(&layout, warning);
+        layout.append(&label(&format!("Enter new password for [{}].", desc)));
+        let generators =
+            [
+                ("Alphanumeric", generate::gen_alphanum as fn(usize) -> String),
+                ("Use anywhere", generate::gen_safe_alphanum),
+                ("Alphanumeric and symbols", generate::gen_alphanum_symbols),
+            ];
+        let password = gtk4::PasswordEntry::builder().hexpand(true).build();
+        password.set_show_peek_icon(true);
+        let confirm_password = gtk4::PasswordEntry::builder().hexpand(true).build();
+        let generate_dropdown = gtk4::DropDown::builder().build();
+        let generate_len = gtk4::Scale::builder().draw_value(true).hexpand(true).build();
+        let generate_button = gtk4::Button::builder().label("Generate").build();
+        {
+            let form_layout = grid();
+            form_layout.attach(&halign_end(label("Password")), 1, 1, 1, 1);
+            form_layout.attach(&password, 2, 1, 1, 1);
+            form_layout.attach(&halign_end(label("Confirm")), 1, 2, 1, 1);
+            form_layout.attach(&confirm_password, 2, 2, 1, 1);
+            layout.append(&form_layout);
+        }
+        {
+            let generate_layout = vbox();
+            generate_layout.set_margin_bottom(SPACING1);
+            generate_layout.set_margin_top(SPACING1);
+            generate_layout.set_margin_start(SPACING1);
+            generate_layout.set_margin_end(SPACING1);
+            let line1 = hbox();
+            let dropdown_strings = gtk4::StringList::new(&[]);
+            for (title, _) in generators {
+                dropdown_strings.append(&title);
+            }
+            generate_dropdown.set_model(Some(&dropdown_strings));
+            line1.append(&generate_dropdown);
+            line1.append(&generate_button);
+            generate_layout.append(&line1);
+            let line2 = hbox();
+            line2.append(&label("Length"));
+            generate_len.set_round_digits(0);
+            generate_len.set_range(5., 50.);
+            generate_len.set_value(16.);
+            line2.append(&generate_len);
+            generate_layout.append(&line2);
+            layout.append(
+                &gtk4::Expander::builder()
+                    .label("Generate")
+                    .child(&gtk4::Frame::builder().child(&generate_layout).build())
+                    .build(),
+            );
+        }
+        generate_button.connect_clicked({
+            let dropdown = generate_dropdown.clone();
+            let password = password.clone();
+            let confirm_password = confirm_password.clone();
+            move |_button| {
+                confirm_password.set_text("");
+                password.set_text(&(generators[dropdown.selected() as usize].1)(generate_len.value() as usize));
+            }
+        });
+        return do_form_dialog(&self.app, None, title, &layout, None, move || {
+            if password.text() == confirm_password.text() {
+                return Ok(password.text().as_bytes().to_vec());
+            } else {
+                return Err("Password mismatch".to_string());
+            }
+        }).await;
+    }
+
+    async fn show_recovery_phrase(&self, warning: Option<String>, title: Title, phrase: &[String]) -> Option<()> {
+        let layout = vbox();
+        attach_error(&layout, warning);
+        layout.append(&label("Write down this phrase list, with numbers, and store it somewhere secure."));
+        let form_layout = grid();
+        for (i, word) in phrase.iter().enumerate() {
+            let base_col = (i as i32 / (BIP39_PHRASELEN / 2) as i32) * 2;
+            let row = i as i32 % (BIP39_PHRASELEN / 2) as i32;
+            form_layout.attach(&halign_end(label(&format!("{}.", i + 1))), base_col, row, 1, 1);
+            let word_label = monospace(word);
+            word_label.set_hexpand(true);
+            form_layout.attach(&halign_start(word_label), base_col + 1, row, 1, 1);
+        }
+        layout.append(&form_layout);
+        return do_form_dialog(&self.app, None, title, &layout, None, || {
+            return Ok(());
+        }).await;
+    }
+
+    async fn recovery_phrase(&self, warning: Option<String>, title: Title, message: &str) -> Option<Vec<u8>> {
+        return ui_recovery_entry(&self.app, warning, title, message).await;
+    }
+
+    async fn smartcard_pin(&self, warning: Option<String>, title: Title) -> Option<String> {
+        const KEYS: &[&[Key]] =
+            &[
+                &[Key::Arabic_0, Key::KP_0, Key::N, Key::B],
+                &[Key::Arabic_1, Key::KP_1, Key::M, Key::X],
+                &[Key::Arabic_2, Key::KP_2, Key::comma, Key::C],
+                &[Key::Arabic_3, Key::KP_3, Key::period, Key::V],
+                &[Key::Arabic_4, Key::KP_4, Key::J, Key::S],
+                &[Key::Arabic_5, Key::KP_5, Key::K, Key::D],
+                &[Key::Arabic_6, Key::KP_6, Key::L, Key::F],
+                &[Key::Arabic_7, Key::KP_7, Key::U, Key::W],
+                &[Key::Arabic_8, Key::KP_8, Key::I, Key::E],
+                &[Key::Arabic_9, Key::KP_9, Key::O, Key::R],
+            ];
+        let layout = vbox();
+        attach_error(&layout, warning);
+        layout.append(&label("Enter card PIN."));
+        let digits = gtk4::PasswordEntry::builder().hexpand(true).show_peek_icon(true).can_focus(false).build();
+        let pin_layout = grid();
+        for i in -1 .. 9 as i32 {
+            let column = if i == -1 {
+                0
+            } else {
+                i.rem_euclid(3)
+            };
+            let row = 3 - i.div_floor(3);
+            let value = (i + 1) as usize;
+            let button = gtk4::Button::builder().label(value.to_string()).can_focus(false).build();
+            button.connect_clicked({
+                let digits = digits.clone();
+                move |_button| {
+                    digits.set_text(&format!("{}{}", digits.text().as_str(), value.to_string()));
+                }
+            });
+            add_shortcut(&button, KEYS[value], {
+                let button = button.clone();
+                move || button.emit_clicked()
+            });
+            pin_layout.attach(&button, column, row, 1, 1);
+        }
+        {
+            let button = gtk4::Button::builder().icon_name("edit-clear").can_focus(false).build();
+            button.connect_clicked({
+                let digits = digits.clone();
+                move |_button| {
+                    digits.set_text("");
+                }
+            });
+            add_shortcut(&button, &[Key::BackSpace, Key::Delete], {
+                let button = button.clone();
+                move || button.emit_clicked()
+            });
+            pin_layout.attach(&button, 1, 4, 1, 1);
+        }
+        layout.append(&halign_center(pin_layout));
+        layout.append(&digits);
+        return do_form_dialog(&self.app, None, title, &layout, None, {
+            let digits = digits.clone();
+            move || {
+                return Ok(digits.text().to_string());
+            }
+        }).await;
+    }
+
+    async fn get_smartcard(
+        &self,
+        card_stream: &mut CardStream,
+        warning: Option<String>,
+        title: Title,
+        fingerprints: &[&str],
+    ) -> Option<openpgp_card_sequoia::Card<Open>> {
+        return ui_get_smartcard(&self.app, card_stream, warning, title, fingerprints).await;
+    }
+
+    async fn smartcard_touch<T>(
+        &self,
+        warning: Option<String>,
+        title: Title,
+        fingerprint: &str,
+        touch: impl Future<Output = T>,
+    ) -> Option<T> {
+        let layout = vbox();
+        if let Some(warning) = warning {
+            layout.append(&label(&warning));
+        }
+        layout.append(&label(&format!("Confirm the action on the smartcard {}", fingerprint)));
+        return select!{
+            _ = ui_window(&self.app, title, &layout) => None,
+            r = touch => Some(r),
+        };
+    }
+
+    async fn fido2_touch<T>(
+        &self,
+        warning: Option<String>,
+        title: Title,
+        desc: &str,
+        touch: impl Future<Output = T>,
+    ) -> Option<T> {
+        let layout = vbox();
+        attach_error(&layout, warning);
+        layout.append(&label(&format!("Touch the security key for {}.", desc)));
+        return select!{
+            _ = ui_window(&self.app, title, &layout) => None,
+            r = touch => Some(r),
+        };
+    }
+
+    async fn get_keyfile<T>(
+        &self,
+        warning: Option<String>,
+        title: Title,
+        path: &Path,
+        wait: impl Future<Output = T>,
+    ) -> Option<T> {
+        let layout = vbox();
+        attach_error(&layout, warning);
+        layout.append(&label("Insert the drive with keyfile:"));
+        layout.append(&monospace(&format!(" • {}", path.to_string_lossy())));
+        return select!{
+            _ = ui_window(&self.app, title, &layout) => None,
+            r = wait => Some(r),
+        };
+    }
+
+    async fn backoff<T>(
+        &self,
+        warning: Option<String>,
+        title: Title,
+        delay: Duration,
+        wait: impl Future<Output = T>,
+    ) -> Option<T> {
+        let layout = vbox();
+        attach_error(&layout, warning);
+        layout.append(&label("Unlocking is delayed due to repeated failed attempts."));
+        let countdown = label(&format!("{} seconds remaining", delay.as_secs()));
+        layout.append(&countdown);
+        let end = Instant::now() + delay;
+        return select!{
+            _ = ui_window(&self.app, title, &layout) => None,
+            r = wait => Some(r),
+            _ = async {
+                loop {
+                    gtk4::glib::timeout_future_seconds(1).await;
+                    countdown.set_label(
+                        &format!("{} seconds remaining", end.saturating_duration_since(Instant::now()).as_secs()),
+                    );
+                }
+            } => unreachable!(),
+        };
+    }
+
+    async fn prompt(&self, args: Arc<B2FPrompt>) -> Result<Option<PromptDecision>, loga::Error> {
+        let mut rule_descs = args.prompt_rules.values().map(|(desc, _)| desc.clone()).collect::<Vec<_>>();
+        rule_descs.sort();
+        let rule_desc = rule_descs.join("\n");
+        let grant_minutes = PROMPT_GRANT_DURATION.as_secs() / 60;
+        return ui_loop(&self.log, self, move |ui, _| {
+            let rule_desc = rule_desc.clone();
+            let args = args.clone();
+            async move {
+                let (res_tx, res_rx) = oneshot::channel();
+                let res_tx = Rc::new(RefCell::new(Some(res_tx)));
+                let layout = vbox();
+                layout.append(&label("Confirm request by application matching rule?"));
+                layout.append(&halign_center(monospace(&rule_desc)));
+                layout.append(&label("Request"));
+                layout.append(&monospace(&args.variant));
+                layout.append(&label("Paths"));
+                layout.append(&monospace(&args.paths.iter().map(|x| x.to_string()).collect::<Vec<_>>().join("\n")));
+                layout.append(&label("Requester (process, then parents)"));
+                layout.append(&monospace(&args.principal));
+                let deny_layout = hbox();
+                let allow_layout = hbox();
+                let mut default_button = None;
+                for (layout, text, decision) in [
+                    (
+                        &deny_layout,
+                        format!("Deny for {} minutes", grant_minutes),
+                        PromptDecision::DenyFor(PROMPT_GRANT_DURATION),
+                    ),
+                    (&deny_layout, "Deny".to_string(), PromptDecision::Deny),
+                    (&allow_layout, "Allow until process exits".to_string(), PromptDecision::AllowProcess),
+                    (
+                        &allow_layout,
+                        format!("Allow for {} minutes", grant_minutes),
+                        PromptDecision::AllowFor(PROMPT_GRANT_DURATION),
+                    ),
+                    (&allow_layout, "Allow once".to_string(), PromptDecision::AllowOnce),
+                ] {
+                    let is_default = matches!(decision, PromptDecision::AllowOnce);
+                    let button = gtk4::Button::builder().label(&text).halign(gtk4::Align::End).build();
+                    button.connect_clicked({
+                        let res_tx = res_tx.clone();
+                        let decision = RefCell::new(Some(decision));
+                        move |_button| {
+                            let Some(decision) = decision.borrow_mut().take() else {
+                                return;
+                            };
+                            if let Some(res_tx) = res_tx.borrow_mut().take() {
+                                res_tx.send(decision).ignore();
+                            }
+                        }
+                    });
+                    if is_default {
+                        button.add_css_class("suggested-action");
+                        button.set_receives_default(true);
+                        default_button = Some(button.clone());
+                    }
+                    layout.append(&button);
+                }
+                if let Some(button) = default_button {
+                    add_shortcut(&button, &[Key::KP_Enter, Key::Return], {
+                        let button = button.clone();
+                        move || button.emit_clicked()
+                    });
+                }
+                layout.append(&halign_end(deny_layout));
+                layout.append(&halign_end(allow_layout));
+                return select!{
+                    _ = ui_window(&ui.app, Title::Prompt(rule_desc.clone()), &layout) => Ok(None),
+                    r = res_rx => Ok(r.ok())
+                };
+            }
+        }).await;
+    }
+}
+
 #[derive(Clone)]
-enum Title {
+pub enum Title {
     Initialize(String),
     Unlock(String),
     Prompt(String),
 }
 
+impl Title {
+    pub fn text(&self) -> String {
+        match self {
+            Title::Initialize(x) => return format!("Initialize [{}]", x),
+            Title::Unlock(x) => return format!("Unlock [{}]", x),
+            Title::Prompt(x) => return format!("Prompt [{}]", x),
+        }
+    }
+}
+
 /// Retry ui interaction as long as there are errors. `Ok(None)` aborts.
 async fn ui_loop<
+    U: FgUi,
     T,
     F: Future<Output = Result<Option<T>, UiErr>>,
-    N: FnMut(Application, Option<String>) -> F,
->(log: &loga::Log, app: &Application, mut f: N) -> Result<Option<T>, loga::Error> {
+    N: FnMut(U, Option<String>) -> F,
+>(log: &loga::Log, ui: &U, mut f: N) -> Result<Option<T>, loga::Error> {
     let mut show_error = None;
     loop {
-        match f(app.clone(), show_error.take()).await {
+        match f(ui.clone(), show_error.take()).await {
             Ok(r) => return Ok(r),
             Err(e) => match e {
-                UiErr::External(e, e_internal) => {
+                UiErr::External(e, e_internal) | UiErr::Incorrect(e, e_internal) => {
                     if let Some(e_internal) = e_internal {
                         log.log_err(loga::WARN, e_internal);
                     };
@@ -684,8 +1256,10 @@ async fn ui_loop<
     };
 }
 
-async fn do_creds(
-    app: Application,
+async fn do_creds<
+    U: FgUi,
+>(
+    ui: U,
     state: Arc<FgState>,
     token_changed: HashSet<String>,
     state_changed: HashSet<String>,
@@ -697,6 +1271,7 @@ async fn do_creds(
     let mut stack = vec![(start, true)];
     let mut new_tokens = HashMap::<String, Vec<u8>>::new();
     let mut store_state = HashMap::<String, Vec<u8>>::new();
+    let mut upgrade_state = HashMap::<String, Vec<u8>>::new();
     while let Some((at, descending)) = stack.pop() {
         match &at {
             Either::New(new, old) => {
@@ -765,8 +1340,7 @@ async fn do_creds(
                                     }
                                     if !already_unlocked {
                                         let Some(child) =
-                                            ui_choose(
-                                                &app,
+                                            ui.choose(
                                                 show_error.take(),
                                                 Title::Unlock(new.desc.to_string()),
                                                 &prev_children,
@@ -818,90 +1392,66 @@ async fn do_creds(
                                 new_tokens.insert(new.id.clone(), token);
                             }
                         },
-                        FactorTreeVariant::Password => {
-                            let layout = vbox();
-                            attach_error(&layout, show_error.take());
-                            layout.append(&label(&format!("Enter new password for [{}].", new.desc)));
-                            let generators =
-                                [
-                                    ("Alphanumeric", generate::gen_alphanum as fn(usize) -> String),
-                                    ("Use anywhere", generate::gen_safe_alphanum),
-                                    ("Alphanumeric and symbols", generate::gen_alphanum_symbols),
-                                ];
-                            let password = gtk4::PasswordEntry::builder().hexpand(true).build();
-                            password.set_show_peek_icon(true);
-                            let confirm_password = gtk4::PasswordEntry::builder().hexpand(true).build();
-                            let generate_dropdown = gtk4::DropDown::builder().build();
-                            let generate_len = gtk4::Scale::builder().draw_value(true).hexpand(true).build();
-                            let generate_button = gtk4::Button::builder().label("Generate").build();
-                            {
-                                let form_layout = grid();
-                                form_layout.attach(&halign_end(label("Password")), 1, 1, 1, 1);
-                                form_layout.attach(&password, 2, 1, 1, 1);
-                                form_layout.attach(&halign_end(label("Confirm")), 1, 2, 1, 1);
-                                form_layout.attach(&confirm_password, 2, 2, 1, 1);
-                                layout.append(&form_layout);
-                            }
-                            {
-                                let generate_layout = vbox();
-                                generate_layout.set_margin_bottom(SPACING1);
-                                generate_layout.set_margin_top(SPACING1);
-                                generate_layout.set_margin_start(SPACING1);
-                                generate_layout.set_margin_end(SPACING1);
-                                let line1 = hbox();
-                                let dropdown_strings = gtk4::StringList::new(&[]);
-                                for (title, _) in generators {
-                                    dropdown_strings.append(&title);
+                        FactorTreeVariant::Threshold(k, children) => {
+                            // Shares are all regenerated together, which needs every child's token
+                            let reshare =
+                                !matches!(prev_variant, Some(FactorTreeVariant::Threshold(..))) ||
+                                    token_changed.contains(&new.id) ||
+                                    state_changed.contains(&new.id);
+                            let keep_token =
+                                matches!(prev_variant, Some(FactorTreeVariant::Threshold(..))) &&
+                                    !token_changed.contains(&new.id);
+                            if descending {
+                                stack.push((at.clone(), false));
+                                let mut match_old = HashMap::new();
+                                if let Some(FactorTreeVariant::Threshold(_, prev_children)) = prev_variant {
+                                    for child in prev_children {
+                                        match_old.insert(child.id.clone(), child.clone());
+                                    }
                                 }
-                                generate_dropdown.set_model(Some(&dropdown_strings));
-                                line1.append(&generate_dropdown);
-                                line1.append(&generate_button);
-                                generate_layout.append(&line1);
-                                let line2 = hbox();
-                                line2.append(&label("Length"));
-                                generate_len.set_round_digits(0);
-                                generate_len.set_range(5., 50.);
-                                generate_len.set_value(16.);
-                                line2.append(&generate_len);
-                                generate_layout.append(&line2);
-                                layout.append(
-                                    &gtk4::Expander::builder()
-                                        .label("Generate")
-                                        .child(&gtk4::Frame::builder().child(&generate_layout).build())
-                                        .build(),
-                                );
-                            }
-                            generate_button.connect_clicked({
-                                let dropdown = generate_dropdown.clone();
-                                let password = password.clone();
-                                let confirm_password = confirm_password.clone();
-                                move |_button| {
-                                    confirm_password.set_text("");
-                                    password.set_text(
-                                        &(generators[dropdown.selected() as usize].1)(generate_len.value() as usize),
-                                    );
+                                for child in children.iter().rev() {
+                                    if new_tokens.contains_key(&child.id) {
+                                        continue;
+                                    }
+                                    if reshare || state_changed.contains(&child.id) {
+                                        stack.push((Either::New(child.clone(), match_old.remove(&child.id)), true));
+                                    }
+                                }
+                                if keep_token && !prev_tokens.contains_key(&new.id) {
+                                    stack.push((Either::Prev(old.clone().unwrap()), true));
                                 }
-                            });
+                            } else {
+                                let token = if keep_token {
+                                    prev_tokens
+                                        .get(&new.id)
+                                        .stack_context(&log, "Previous threshold token missing after unlock")?
+                                        .clone()
+                                } else {
+                                    gen_token()
+                                };
+                                if reshare {
+                                    let mut state = HashMap::new();
+                                    for (child, share) in children.iter().zip(Sharks(*k as u8).dealer(&token)) {
+                                        state.insert(
+                                            child.id.clone(),
+                                            local_encrypt(new_tokens.get(&child.id).unwrap(), &Vec::from(&share)),
+                                        );
+                                    }
+                                    store_state.insert(new.id.clone(), serde_json::to_vec(&state).unwrap());
+                                }
+                                new_tokens.insert(new.id.clone(), token);
+                            }
+                        },
+                        FactorTreeVariant::Password => {
                             let Some(password) =
-                                do_form_dialog(
-                                    &app,
-                                    show_error.take(),
-                                    Title::Initialize(new.desc.clone()),
-                                    &layout,
-                                    None,
-                                    {
-                                        move || {
-                                            if password.text() == confirm_password.text() {
-                                                return Ok(password.text().as_bytes().to_vec());
-                                            } else {
-                                                return Err("Password mismatch".to_string());
-                                            }
-                                        }
-                                    },
-                                ).await else {
+                                ui
+                                    .new_password(show_error.take(), Title::Initialize(new.desc.clone()), &new.desc)
+                                    .await else {
                                     return Ok(None);
                                 };
-                            new_tokens.insert(new.id.clone(), password);
+                            let token = gen_token();
+                            store_state.insert(new.id.clone(), wrap_secret(password, token.clone()).await?);
+                            new_tokens.insert(new.id.clone(), token);
                         },
                         FactorTreeVariant::Smartcards(c) => {
                             // Retrieve existing state data, or empty
@@ -920,9 +1470,9 @@ async fn do_creds(
                                 }
                                 if let Some(FactorTreeVariant::Smartcards(prev_children)) = prev_variant {
                                     let Some(token) =
-                                        ui_unlock_smartcard(
+                                        unlock_smartcard(
                                             &log,
-                                            &app,
+                                            &ui,
                                             show_error.take(),
                                             Title::Initialize(new.desc.to_string()),
                                             prev_children,
@@ -945,48 +1495,19 @@ async fn do_creds(
                             }
                             let mut card_stream = CardStream::new(&log);
                             while !remaining_new_fingerprints.is_empty() {
-                                let Some(mut card) =
-                                    ui_get_smartcard(
-                                        &app,
+                                let Some((fingerprint, encrypted)) =
+                                    enroll_smartcard(
+                                        &ui,
                                         &mut card_stream,
                                         show_error.take(),
                                         Title::Initialize(new.desc.to_string()),
-                                        &remaining_new_fingerprints.iter().map(|x| x.as_str()).collect::<Vec<_>>(),
-                                    ).await else {
+                                        &remaining_new_fingerprints,
+                                        &token,
+                                    ).await? else {
                                         return Ok(None);
                                     };
-                                let mut card_tx = card.transaction().context("Error starting smartcard transaction")?;
-                                let Some(pubkey) =
-                                    card_tx
-                                        .public_key(openpgp_card_sequoia::types::KeyType::Decryption)
-                                        .map_err(|e| loga::err(e.to_string()))? else {
-                                        show_error = Some("Couldn't determine public key for card.".to_string());
-                                        continue;
-                                    };
-                                let fingerprint = pubkey.fingerprint().to_string();
-                                if !remaining_new_fingerprints.remove(&fingerprint) {
-                                    show_error =
-                                        Some(format!("Smartcard had the wrong fingerprint: {}", fingerprint));
-                                    continue;
-                                };
-                                let mut sink = vec![];
-                                let message = Message::new(&mut sink);
-                                let message =
-                                    Encryptor2::for_recipients(message, vec![Recipient::from(&pubkey)])
-                                        .build()
-                                        .map_err(|e| loga::err(e.to_string()))
-                                        .context("Error building encryptor")?;
-                                let mut w =
-                                    LiteralWriter::new(message)
-                                        .build()
-                                        .map_err(|e| loga::err(e.to_string()))
-                                        .context("Error forming encryption writer")?;
-                                w.write_all(&token).context("Error streaming data to encrypt")?;
-                                w
-                                    .finalize()
-                                    .map_err(|e| loga::err(e.to_string()))
-                                    .context("Error finalizing encryption")?;
-                                state.insert(fingerprint.clone(), sink);
+                                remaining_new_fingerprints.remove(&fingerprint);
+                                state.insert(fingerprint, encrypted);
                             }
 
                             // Bundle + store everything
@@ -994,58 +1515,89 @@ async fn do_creds(
                             new_tokens.insert(new.id.clone(), token);
                         },
                         FactorTreeVariant::RecoveryPhrase => {
-                            // Show
-                            let layout = vbox();
-                            attach_error(&layout, show_error.take());
-                            layout.append(
-                                &label("Write down this phrase list, with numbers, and store it somewhere secure."),
-                            );
-                            let form_layout = grid();
-                            let phrase = generate::gen_bip39();
-                            for (i, word) in phrase.iter().enumerate() {
-                                let base_col = (i as i32 / (BIP39_PHRASELEN / 2) as i32) * 2;
-                                let row = i as i32 % (BIP39_PHRASELEN / 2) as i32;
-                                form_layout.attach(&halign_end(label(&format!("{}.", i + 1))), base_col, row, 1, 1);
-                                let word_label = monospace(word);
-                                word_label.set_hexpand(true);
-                                form_layout.attach(&halign_start(word_label), base_col + 1, row, 1, 1);
-                            }
-                            layout.append(&form_layout);
-                            let Some(_) =
-                                do_form_dialog(
-                                    &app,
+                            let Some(phrase) =
+                                new_recovery_phrase(
+                                    &ui,
                                     show_error.take(),
                                     Title::Initialize(new.desc.to_string()),
-                                    &layout,
-                                    None,
-                                    || {
-                                        return Ok(());
+                                ).await? else {
+                                    return Ok(None);
+                                };
+                            let token = gen_token();
+                            store_state.insert(new.id.clone(), wrap_secret(phrase, token.clone()).await?);
+                            new_tokens.insert(new.id.clone(), token);
+                        },
+                        FactorTreeVariant::Tpm(c) => {
+                            // Keep the token if only the sealing changed
+                            let token = shed!{
+                                'found _;
+                                if let Some(key) = prev_tokens.get(&new.id) {
+                                    break 'found key.clone();
+                                }
+                                if let Some(FactorTreeVariant::Tpm(prev_config)) = prev_variant {
+                                    let sealed =
+                                        serde_json::from_slice::<TpmSealed>(
+                                            prev_state
+                                                .get(&new.id)
+                                                .stack_context(&log, "Sealed token for factor missing in database")?,
+                                        ).stack_context(&log, "Unable to deserialize TPM state")?;
+                                    break 'found unseal_tpm(&log, prev_config, sealed).await?;
+                                }
+                                break 'found gen_token();
+                            };
+                            let sealed = gtk4::gio::spawn_blocking({
+                                let c = c.clone();
+                                let token = token.clone();
+                                move || tpm::seal(&c, &token)
+                            }).await.any_context()?.stack_context(&log, "Error sealing token with TPM")?;
+                            store_state.insert(new.id.clone(), serde_json::to_vec(&sealed).unwrap());
+                            new_tokens.insert(new.id.clone(), token);
+                        },
+                        FactorTreeVariant::Fido2(c) => {
+                            // Create credential
+                            let Some(key_state) =
+                                fido2_touch_blocking(
+                                    &ui,
+                                    show_error.take(),
+                                    Title::Initialize(new.desc.to_string()),
+                                    &new.desc,
+                                    {
+                                        let c = c.clone();
+                                        move || fido2::enroll(&c)
                                     },
-                                ).await else {
+                                ).await? else {
                                     return Ok(None);
                                 };
-                            let phrase = phrase.join(" ").as_bytes().to_vec();
+                            let key_state_bytes = serde_json::to_vec(&key_state).unwrap();
 
-                            // Confirm
-                            let Some(confirm_passphrase) =
-                                ui_recovery_entry(
-                                    &app,
+                            // Get the secret, needs a second touch
+                            let Some(token) =
+                                fido2_touch_blocking(
+                                    &ui,
+                                    None,
+                                    Title::Initialize(new.desc.to_string()),
+                                    &new.desc,
+                                    {
+                                        let c = c.clone();
+                                        move || fido2::derive(&c, &key_state)
+                                    },
+                                ).await? else {
+                                    return Ok(None);
+                                };
+                            store_state.insert(new.id.clone(), key_state_bytes);
+                            new_tokens.insert(new.id.clone(), token);
+                        },
+                        FactorTreeVariant::Keyfile(c) => {
+                            let Some(token) =
+                                wait_keyfile(
+                                    &ui,
                                     show_error.take(),
                                     Title::Initialize(new.desc.to_string()),
-                                    "Confirm the phrase.",
-                                ).await else {
+                                    c,
+                                ).await? else {
                                     return Ok(None);
                                 };
-                            if confirm_passphrase != phrase {
-                                return Err(
-                                    UiErr::external(
-                                        "Recovery phrases didn't match; make sure you wrote down the correct order as well.",
-                                    ),
-                                );
-                            }
-
-                            // Save
-                            new_tokens.insert(new.id.clone(), phrase);
+                            new_tokens.insert(new.id.clone(), token);
                         },
                     }
                 }
@@ -1092,7 +1644,7 @@ async fn do_creds(
                                         ).context("Error parsing stored state for factor")? {
                                             Some(t) => t,
                                             None => return Err(
-                                                UiErr::External(
+                                                UiErr::Incorrect(
                                                     format!("Incorrect factor [{}], decryption failed", child.id),
                                                     None,
                                                 ),
@@ -1105,8 +1657,7 @@ async fn do_creds(
                             if !done {
                                 stack.push((at.clone(), false));
                                 let Some(child) =
-                                    ui_choose(
-                                        &app,
+                                    ui.choose(
                                         show_error.take(),
                                         Title::Unlock(prev.desc.to_string()),
                                         children,
@@ -1116,34 +1667,79 @@ async fn do_creds(
                                 stack.push((Either::Prev(child), true));
                             }
                         },
+                        FactorTreeVariant::Threshold(k, children) => {
+                            let state =
+                                serde_json::from_slice::<HashMap<String, Vec<u8>>>(
+                                    &prev_state
+                                        .get(&prev.id)
+                                        .context_with("State for factor missing in database", ea!(factor = prev.id))?,
+                                ).context_with("Error parsing stored factor state", ea!(factor = prev.id))?;
+                            let mut shares = vec![];
+                            let mut remaining = vec![];
+                            for child in children {
+                                let Some(key) = prev_tokens.get(&child.id) else {
+                                    remaining.push(child.clone());
+                                    continue;
+                                };
+                                let share =
+                                    match local_decrypt(
+                                        &key,
+                                        &state
+                                            .get(&child.id)
+                                            .context(&format!("Missing child state for [{}] in threshold", child.id))?,
+                                    ).context("Error parsing stored state for factor")? {
+                                        Some(t) => t,
+                                        None => return Err(
+                                            UiErr::Incorrect(
+                                                format!("Incorrect factor [{}], decryption failed", child.id),
+                                                None,
+                                            ),
+                                        ),
+                                    };
+                                shares.push(
+                                    Share::try_from(
+                                        share.as_slice(),
+                                    ).map_err(|e| loga::err(e)).context("Error parsing stored share")?,
+                                );
+                            }
+                            if shares.len() >= *k {
+                                let token =
+                                    Sharks(*k as u8)
+                                        .recover(&shares)
+                                        .map_err(|e| loga::err(e))
+                                        .stack_context(&log, "Error combining threshold shares")?;
+                                prev_tokens.insert(prev.id.clone(), token);
+                            } else {
+                                stack.push((at.clone(), false));
+                                let Some(child) =
+                                    ui.choose(
+                                        show_error.take(),
+                                        Title::Unlock(format!("{} ({} of {})", prev.desc, shares.len() + 1, k)),
+                                        &remaining,
+                                    ).await else {
+                                        return Ok(None);
+                                    };
+                                stack.push((Either::Prev(child), true));
+                            }
+                        },
                         FactorTreeVariant::Password => {
-                            let layout = vbox();
-                            attach_error(&layout, show_error.take());
-                            layout.append(&label(&format!("Enter password for {}.", prev.desc)));
-                            let form_layout = grid();
-                            form_layout.attach(&halign_end(label("Password")), 1, 1, 1, 1);
-                            let password = {
-                                let password = gtk4::PasswordEntry::builder().hexpand(true).build();
-                                form_layout.attach(&password, 2, 1, 1, 1);
-                                password
-                            };
-                            layout.append(&form_layout);
                             let Some(password) =
-                                do_form_dialog(
-                                    &app,
-                                    show_error.take(),
-                                    Title::Unlock(prev.desc.to_string()),
-                                    &layout,
-                                    None,
-                                    {
-                                        move || {
-                                            return Ok(password.text().as_bytes().to_vec());
-                                        }
-                                    },
-                                ).await else {
+                                ui
+                                    .password(show_error.take(), Title::Unlock(prev.desc.to_string()), &prev.desc)
+                                    .await else {
                                     return Ok(None);
                                 };
-                            prev_tokens.insert(prev.id.clone(), password);
+                            let (token, upgrade) =
+                                unwrap_secret(
+                                    &log,
+                                    prev_state.get(&prev.id),
+                                    password,
+                                    format!("Incorrect password for [{}]", prev.desc),
+                                ).await?;
+                            if let Some(upgrade) = upgrade {
+                                upgrade_state.insert(prev.id.clone(), upgrade);
+                            }
+                            prev_tokens.insert(prev.id.clone(), token);
                         },
                         FactorTreeVariant::Smartcards(c) => {
                             let state =
@@ -1153,9 +1749,9 @@ async fn do_creds(
                                         .stack_context(&log, "Pretoken for factor missing in database")?,
                                 ).stack_context(&log, "Unable to deserialize smartcards state")?;
                             let Some(token) =
-                                ui_unlock_smartcard(
+                                unlock_smartcard(
                                     &log,
-                                    &app,
+                                    &ui,
                                     show_error.take(),
                                     Title::Unlock(prev.desc.to_string()),
                                     c,
@@ -1167,15 +1763,67 @@ async fn do_creds(
                         },
                         FactorTreeVariant::RecoveryPhrase => {
                             let Some(phrase) =
-                                ui_recovery_entry(
-                                    &app,
+                                ui.recovery_phrase(
                                     show_error.take(),
                                     Title::Unlock(prev.desc.to_string()),
                                     "Enter the recovery phrase words in the correct order.",
                                 ).await else {
                                     return Ok(None);
                                 };
-                            prev_tokens.insert(prev.id.clone(), phrase);
+                            let (token, upgrade) =
+                                unwrap_secret(
+                                    &log,
+                                    prev_state.get(&prev.id),
+                                    phrase,
+                                    format!("Incorrect recovery phrase for [{}]", prev.desc),
+                                ).await?;
+                            if let Some(upgrade) = upgrade {
+                                upgrade_state.insert(prev.id.clone(), upgrade);
+                            }
+                            prev_tokens.insert(prev.id.clone(), token);
+                        },
+                        FactorTreeVariant::Tpm(c) => {
+                            let sealed =
+                                serde_json::from_slice::<TpmSealed>(
+                                    &prev_state
+                                        .get(&prev.id)
+                                        .stack_context(&log, "Sealed token for factor missing in database")?,
+                                ).stack_context(&log, "Unable to deserialize TPM state")?;
+                            prev_tokens.insert(prev.id.clone(), unseal_tpm(&log, c, sealed).await?);
+                        },
+                        FactorTreeVariant::Fido2(c) => {
+                            let key_state =
+                                serde_json::from_slice::<Fido2State>(
+                                    &prev_state
+                                        .get(&prev.id)
+                                        .stack_context(&log, "Credential for factor missing in database")?,
+                                ).stack_context(&log, "Unable to deserialize FIDO2 state")?;
+                            let Some(token) =
+                                fido2_touch_blocking(
+                                    &ui,
+                                    show_error.take(),
+                                    Title::Unlock(prev.desc.to_string()),
+                                    &prev.desc,
+                                    {
+                                        let c = c.clone();
+                                        move || fido2::derive(&c, &key_state)
+                                    },
+                                ).await? else {
+                                    return Ok(None);
+                                };
+                            prev_tokens.insert(prev.id.clone(), token);
+                        },
+                        FactorTreeVariant::Keyfile(c) => {
+                            let Some(token) =
+                                wait_keyfile(
+                                    &ui,
+                                    show_error.take(),
+                                    Title::Unlock(prev.desc.to_string()),
+                                    c,
+                                ).await? else {
+                                    return Ok(None);
+                                };
+                            prev_tokens.insert(prev.id.clone(), token);
                         },
                     }
                 }
@@ -1186,10 +1834,67 @@ async fn do_creds(
         prev_tokens: prev_tokens,
         new_tokens: new_tokens,
         store_state: store_state,
+        upgrade_state: upgrade_state,
     }));
 }
 
+/// Wait out the delay from previous failed unlock attempts, adding a note to the
+/// warning if there was one. `None` if the user cancelled.
+async fn wait_backoff<
+    U: FgUi,
+>(ui: &U, pubdb_path: &Path, warning: Option<String>, title: Title) -> Result<Option<Option<String>>, UiErr> {
+    let delay = gtk4::gio::spawn_blocking({
+        let pubdb_path = pubdb_path.to_path_buf();
+        move || unlockbackoff::remaining_delay(&pubdb_path)
+    }).await.any_context()??;
+    if delay.is_zero() {
+        return Ok(Some(warning));
+    }
+    let end = Instant::now() + delay;
+    let Some(res) = ui.backoff(warning.clone(), title, delay, async move {
+        loop {
+            let remaining = end.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(()) as Result<_, loga::Error>;
+            }
+
+            // Sleep in short steps so cancelling doesn't leave a thread sleeping for the rest
+            // of the delay
+            gtk4::gio::spawn_blocking(
+                move || std::thread::sleep(remaining.min(Duration::from_secs(1))),
+            ).await.any_context()?;
+        }
+    }).await else {
+        return Ok(None);
+    };
+    res?;
+    return Ok(
+        Some(
+            Some(
+                format!(
+                    "{}Unlocking was delayed {} seconds due to repeated failed attempts.",
+                    warning.map(|x| format!("{} ", x)).unwrap_or_default(),
+                    delay.as_secs()
+                ),
+            ),
+        ),
+    );
+}
+
+/// Count the attempt as failed if the user entered something wrong. Other errors
+/// (ex: communicating with a security key) aren't counted.
+async fn record_backoff<T>(pubdb_path: &Path, res: Result<T, UiErr>) -> Result<T, UiErr> {
+    if let Err(UiErr::Incorrect(..)) = &res {
+        gtk4::gio::spawn_blocking({
+            let pubdb_path = pubdb_path.to_path_buf();
+            move || unlockbackoff::record_failure(&pubdb_path)
+        }).await.any_context()??;
+    }
+    return res;
+}
+
 pub struct B2FUnlock {
+    pub pubdb_path: PathBuf,
     pub privdb_path: PathBuf,
     pub root_factor: Arc<FactorTree>,
     pub state: HashMap<String, Vec<u8>>,
@@ -1201,45 +1906,83 @@ pub struct B2FUnlockResult {
     pub tokens: HashMap<String, Vec<u8>>,
 }
 
-pub async fn do_unlock(
-    state: Arc<FgState>,
-    app: &Application,
-    args: Arc<B2FUnlock>,
-) -> Result<Option<B2FUnlockResult>, loga::Error> {
-    return ui_loop(&state.log.clone(), app, move |app, show_err| {
+pub async fn do_unlock<
+    U: FgUi,
+>(state: Arc<FgState>, ui: &U, args: Arc<B2FUnlock>) -> Result<Option<B2FUnlockResult>, loga::Error> {
+    return ui_loop(&state.log.clone(), ui, move |ui, show_err| {
         let args = args.clone();
         let state = state.clone();
         async move {
-            let res =
-                match do_creds(
-                    app,
-                    state,
-                    HashSet::new(),
-                    HashSet::new(),
-                    HashMap::new(),
-                    args.state.clone(),
-                    show_err,
-                    Either::Prev(args.root_factor.clone()),
-                ).await? {
-                    Some(x) => Arc::new(x),
-                    None => return Ok(None),
-                };
-            let privdbc = gtk4::gio::spawn_blocking({
-                let args = args.clone();
-                let res = res.clone();
-                move || {
-                    // Confirm token
-                    let mut privdbc =
-                        open_privdb(
-                            &args.privdb_path,
-                            &zbase32::encode_full_bytes(&res.prev_tokens.get(&args.root_factor.id).unwrap()),
+            let title = Title::Unlock(args.root_factor.desc.clone());
+            let Some(show_err) = wait_backoff(&ui, &args.pubdb_path, show_err, title).await? else {
+                return Ok(None);
+            };
+            let res = async {
+                let res =
+                    match do_creds(
+                        ui,
+                        state.clone(),
+                        HashSet::new(),
+                        HashSet::new(),
+                        HashMap::new(),
+                        args.state.clone(),
+                        show_err,
+                        Either::Prev(args.root_factor.clone()),
+                    ).await? {
+                        Some(x) => Arc::new(x),
+                        None => return Ok(None),
+                    };
+                let privdbc = gtk4::gio::spawn_blocking({
+                    let args = args.clone();
+                    let res = res.clone();
+                    move || {
+                        // Confirm token
+                        let mut privdbc =
+                            open_privdb(
+                                &args.privdb_path,
+                                &zbase32::encode_full_bytes(&res.prev_tokens.get(&args.root_factor.id).unwrap()),
+                            )?;
+                        privdb::migrate(
+                            &mut privdbc,
+                        ).context_with(
+                            "Error setting up priv database",
+                            ea!(path = args.privdb_path.to_string_lossy()),
                         )?;
-                    privdb::migrate(
-                        &mut privdbc,
-                    ).context_with("Error setting up priv database", ea!(path = args.privdb_path.to_string_lossy()))?;
-                    return Ok(privdbc) as Result<_, loga::Error>;
+                        return Ok(privdbc) as Result<_, loga::Error>;
+                    }
+                }).await.any_context()?.map_err(|e| {
+                    // Legacy secret factors aren't checked before this, so this may be a wrong secret
+                    return UiErr::Incorrect(
+                        "Failed to unlock database".to_string(),
+                        Some(e.context("Failed to unlock database")),
+                    );
+                })?;
+                return Ok(Some((res, privdbc))) as Result<_, UiErr>;
+            }.await;
+
+            // Track failures, store upgraded factor state
+            let Some((res, privdbc)) = record_backoff(&args.pubdb_path, res).await? else {
+                return Ok(None);
+            };
+            if let Err(e) = gtk4::gio::spawn_blocking({
+                let pubdb_path = args.pubdb_path.clone();
+                let upgrade_state = res.upgrade_state.clone();
+                move || {
+                    unlockbackoff::clear_failures(&pubdb_path)?;
+                    let mut pubdbc =
+                        rusqlite::Connection::open(&pubdb_path).context("Error opening pub database")?;
+                    for (k, v) in upgrade_state {
+                        pubdb::factor_add(
+                            &mut pubdbc,
+                            &k,
+                            &v,
+                        ).context_with("Error storing upgraded factor state", ea!(factor = k))?;
+                    }
+                    return Ok(()) as Result<_, loga::Error>;
                 }
-            }).await.any_context()?.to_ui_err_external("Failed to unlock database")?;
+            }).await.any_context()? {
+                state.log.log_err(loga::WARN, e.context("Error updating factor state after unlock"));
+            }
             return Ok(Some(B2FUnlockResult {
                 privdbc: privdbc,
                 root_token: zbase32::encode_full_bytes(&res.prev_tokens.get(&args.root_factor.id).unwrap().clone()),
@@ -1264,15 +2007,13 @@ pub struct B2FInitializeResult {
     pub store_state: HashMap<String, Vec<u8>>,
 }
 
-pub async fn do_initialize(
-    state: Arc<FgState>,
-    app: &Application,
-    args: Arc<B2FInitialize>,
-) -> Result<Option<B2FInitializeResult>, loga::Error> {
-    let Some(r) = ui_loop(&state.log.clone(), app, {
+pub async fn do_initialize<
+    U: FgUi,
+>(state: Arc<FgState>, ui: &U, args: Arc<B2FInitialize>) -> Result<Option<B2FInitializeResult>, loga::Error> {
+    let Some(r) = ui_loop(&state.log.clone(), ui, {
         let args = args.clone();
-        move |app, show_err| do_creds(
-            app,
+        move |ui, show_err| do_creds(
+            ui,
             state.clone(),
             args.tokens_changed.clone(),
             args.state_changed.clone(),
@@ -1290,84 +2031,180 @@ pub async fn do_initialize(
     }));
 }
 
-pub struct B2FPrompt {
-    pub prompt_rules: HashMap<usize, (String, u64)>,
+pub struct B2FFactorChange {
+    pub pubdb_path: PathBuf,
+    pub factor: Arc<FactorTree>,
+    /// Already checked to apply to the factor.
+    pub change: FactorChange,
+    pub state: HashMap<String, Vec<u8>>,
 }
 
-pub async fn do_prompt(
-    state: Arc<FgState>,
-    app: &Application,
-    args: Arc<B2FPrompt>,
-) -> Result<Option<bool>, loga::Error> {
-    let now = Instant::now();
-    let mut elapsed_rule_desc = None;
-    for (rule_id, (rule_desc, rule_remember)) in &args.prompt_rules {
-        if let Some(last) = state.last_prompts.lock().unwrap().get(&rule_id) {
-            if last.checked_add(Duration::from_secs(*rule_remember)).map(|x| x > now).unwrap_or(true) {
-                continue;
+pub enum B2FFactorChangeResult {
+    /// The factor's new state. The token is unchanged.
+    Changed(Vec<u8>),
+    /// The factor predates secret wrapping so its token is its old secret, which a
+    /// new secret would only wrap.
+    LegacyToken,
+}
+
+/// Unlock the factor alone, then change its credentials, keeping the token.
+pub async fn do_factor_change<
+    U: FgUi,
+>(state: Arc<FgState>, ui: &U, args: Arc<B2FFactorChange>) -> Result<Option<B2FFactorChangeResult>, loga::Error> {
+    let log = state.log.fork(ea!(factor = args.factor.id));
+    let Some(token) = ui_loop(&log, ui, {
+        let args = args.clone();
+        let log = log.clone();
+        move |ui, show_err| {
+            let args = args.clone();
+            let state = state.clone();
+            let log = log.clone();
+            async move {
+                let Some(show_err) =
+                    wait_backoff(&ui, &args.pubdb_path, show_err, Title::Unlock(args.factor.desc.clone())).await? else {
+                        return Ok(None);
+                    };
+                let res =
+                    record_backoff(
+                        &args.pubdb_path,
+                        do_creds(
+                            ui,
+                            state,
+                            HashSet::new(),
+                            HashSet::new(),
+                            HashMap::new(),
+                            args.state.clone(),
+                            show_err,
+                            Either::Prev(args.factor.clone()),
+                        ).await,
+                    ).await?;
+                let Some(res) = res else {
+                    return Ok(None);
+                };
+                if let Err(e) = gtk4::gio::spawn_blocking({
+                    let pubdb_path = args.pubdb_path.clone();
+                    move || unlockbackoff::clear_failures(&pubdb_path)
+                }).await.any_context()? {
+                    log.log_err(loga::WARN, e.context("Error clearing failed unlock attempts"));
+                }
+                return Ok(Some(res.prev_tokens.get(&args.factor.id).unwrap().clone()));
             }
         }
-        elapsed_rule_desc = Some(rule_desc.clone());
-        break;
-    }
-    let Some(elapsed_rule_desc) = elapsed_rule_desc else {
-        return Ok(Some(true));
+    }).await? else {
+        return Ok(None);
     };
-    let out = ui_loop(&state.log, app, move |app, _| {
-        let elapsed_rule_desc = elapsed_rule_desc.clone();
-        async move {
-            let (res_tx, res_rx) = oneshot::channel();
-            let res_tx = Rc::new(RefCell::new(Some(res_tx)));
-            let layout = vbox();
-            layout.append(&label("Confirm request by application matching rule?"));
-            layout.append(&halign_center(monospace(&elapsed_rule_desc)));
-            let button_layout = hbox();
-            button_layout.append(&{
-                let button = gtk4::Button::builder().label("Cancel").halign(gtk4::Align::End).build();
-                button.connect_clicked({
-                    let res_tx = res_tx.clone();
-                    move |_button| {
-                        res_tx.borrow_mut().take().unwrap().send(false).ignore();
-                    }
-                });
-                button
-            });
-            button_layout.append(&{
-                let button =
-                    gtk4::Button::builder()
-                        .label("Ok")
-                        .halign(gtk4::Align::End)
-                        .css_classes(["suggested-action"])
-                        .build();
-                button.set_receives_default(true);
-                button.connect_clicked({
-                    let res_tx = res_tx.clone();
-                    move |_button| {
-                        res_tx.borrow_mut().take().unwrap().send(true).ignore();
-                    }
-                });
-                add_shortcut(&button, &[Key::KP_Enter, Key::Return], {
-                    let button = button.clone();
-                    move || button.emit_clicked()
-                });
-                button
-            });
-            return select!{
-                _ = ui_window(&app, Title::Prompt(elapsed_rule_desc.clone()), &layout) => Ok(None),
-                r = res_rx => Ok(r.ok())
-            };
-        }
-    }).await?;
-    if out == Some(true) {
-        for rule_id in args.prompt_rules.keys() {
-            state.last_prompts.lock().unwrap().insert(*rule_id, now);
+    if let FactorChange::Password | FactorChange::RecoveryPhrase = &args.change {
+        let legacy = gtk4::gio::spawn_blocking({
+            let state = args.state.get(&args.factor.id).cloned();
+            let token = token.clone();
+            move || {
+                let Some(state) = state else {
+                    return Ok(true);
+                };
+                let wrapped =
+                    serde_json::from_slice::<WrappedToken>(
+                        &state,
+                    ).context("Unable to deserialize secret factor state")?;
+                return Ok(unwrap_token(&wrapped, &token)?.as_ref() == Some(&token)) as Result<_, loga::Error>;
+            }
+        }).await.any_context()??;
+        if legacy {
+            return Ok(Some(B2FFactorChangeResult::LegacyToken));
         }
     }
-    return Ok(out);
+    let Some(new_state) = ui_loop(&log, ui, {
+        let log = log.clone();
+        move |ui, show_err| {
+            let args = args.clone();
+            let log = log.clone();
+            let token = token.clone();
+            async move {
+                let title = Title::Initialize(args.factor.desc.clone());
+                let smartcards_state = || match args.state.get(&args.factor.id) {
+                    Some(state) => serde_json::from_slice::<HashMap<String, Vec<u8>>>(
+                        state,
+                    ).stack_context(&log, "Unable to deserialize smartcards state"),
+                    None => Ok(HashMap::new()),
+                };
+                match &args.change {
+                    FactorChange::Password => {
+                        let Some(password) = ui.new_password(show_err, title, &args.factor.desc).await else {
+                            return Ok(None);
+                        };
+                        return Ok(Some(wrap_secret(password, token).await?));
+                    },
+                    FactorChange::RecoveryPhrase => {
+                        let Some(phrase) = new_recovery_phrase(&ui, show_err, title).await? else {
+                            return Ok(None);
+                        };
+                        return Ok(Some(wrap_secret(phrase, token).await?));
+                    },
+                    FactorChange::AddSmartcard(fingerprint) => {
+                        let mut state = smartcards_state()?;
+                        let Some((fingerprint, encrypted)) =
+                            enroll_smartcard(
+                                &ui,
+                                &mut CardStream::new(&log),
+                                show_err,
+                                title,
+                                &HashSet::from([fingerprint.clone()]),
+                                &token,
+                            ).await? else {
+                                return Ok(None);
+                            };
+                        state.insert(fingerprint, encrypted);
+                        return Ok(Some(serde_json::to_vec(&state).unwrap()));
+                    },
+                    FactorChange::RemoveSmartcard(fingerprint) => {
+                        let mut state = smartcards_state()?;
+                        state.remove(fingerprint);
+                        return Ok(Some(serde_json::to_vec(&state).unwrap()));
+                    },
+                }
+            }
+        }
+    }).await? else {
+        return Ok(None);
+    };
+    return Ok(Some(B2FFactorChangeResult::Changed(new_state)));
+}
+
+pub struct B2FPrompt {
+    /// Rules requiring confirmation that haven't been decided yet (by rule id). Values
+    /// are the rule description and remember duration.
+    pub prompt_rules: HashMap<usize, (String, u64)>,
+    /// The request type (ex: `read`)
+    pub variant: String,
+    pub paths: Vec<SpecificPath>,
+    /// Summary of the requesting process chain
+    pub principal: String,
+    /// Pidfd inodes of the processes in the requesting chain, where known.
+    pub chain: Vec<Inode>,
 }
 
 pub enum B2F {
     Initialize(B2FInitialize, oneshot::Sender<Result<Option<B2FInitializeResult>, loga::Error>>),
     Unlock(B2FUnlock, oneshot::Sender<Result<Option<B2FUnlockResult>, loga::Error>>),
-    Prompt(B2FPrompt, oneshot::Sender<Result<Option<bool>, loga::Error>>),
+    Prompt(B2FPrompt, oneshot::Sender<Result<Option<PromptDecision>, loga::Error>>),
+    FactorChange(B2FFactorChange, oneshot::Sender<Result<Option<B2FFactorChangeResult>, loga::Error>>),
+}
+
+/// Handle foreground requests until the channel closes.
+pub async fn run<U: FgUi>(state: Arc<FgState>, ui: U, mut fg_rx: mpsc::Receiver<B2F>) {
+    while let Some(req) = fg_rx.recv().await {
+        match req {
+            B2F::Unlock(req, resp) => {
+                resp.send(do_unlock(state.clone(), &ui, Arc::new(req)).await).ignore();
+            },
+            B2F::Initialize(req, resp) => {
+                resp.send(do_initialize(state.clone(), &ui, Arc::new(req)).await).ignore();
+            },
+            B2F::Prompt(req, resp) => {
+                resp.send(ui.prompt(Arc::new(req)).await).ignore();
+            },
+            B2F::FactorChange(req, resp) => {
+                resp.send(do_factor_change(state.clone(), &ui, Arc::new(req)).await).ignore();
+            },
+        }
+    }
 }
@@ -0,0 +1,418 @@
+//! Forwarding of foreground interactions to a client registered over IPC, for
+//! unlocking from a remote shell or a custom UI.
+use {
+    super::{
+        factor::FactorTree,
+        fg::{
+            B2FPrompt,
+            FgUi,
+            Title,
+        },
+        grants::{
+            PromptDecision,
+            PROMPT_GRANT_DURATION,
+        },
+        pidfd::Inode,
+    },
+    loga::{
+        conversion::ResultIgnore,
+        ea,
+        Log,
+    },
+    openpgp_card_sequoia::state::Open,
+    passworth::ipc,
+    passworth_native::crypto::CardStream,
+    std::{
+        future::Future,
+        path::Path,
+        sync::{
+            atomic::{
+                AtomicU64,
+                Ordering,
+            },
+            Arc,
+            Mutex,
+        },
+        time::Duration,
+    },
+    tokio::{
+        select,
+        sync::{
+            oneshot,
+            Notify,
+        },
+    },
+};
+
+struct Pending {
+    id: u64,
+    /// Taken when delivered to the client.
+    question: Option<ipc::UiQuestion>,
+    answer: oneshot::Sender<Option<ipc::UiAnswer>>,
+}
+
+struct Handler {
+    id: u64,
+    /// Pidfd inode of the registered client's process.
+    process: Inode,
+}
+
+#[derive(Default)]
+struct UiDelegateInner {
+    handler: Option<Handler>,
+    /// Interactions are sequential so there's at most one outstanding question.
+    /// Dropping this cancels the interaction.
+    pending: Option<Pending>,
+}
+
+#[derive(Default)]
+pub struct UiDelegate {
+    next_id: AtomicU64,
+    inner: Mutex<UiDelegateInner>,
+    question_ready: Notify,
+}
+
+impl UiDelegate {
+    /// Register a connection from the process (pidfd inode) as the interaction
+    /// handler. Unregisters when the result is dropped.
+    pub fn register(self: &Arc<Self>, process: Inode) -> Result<UiHandler, loga::Error> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.handler.is_some() {
+            return Err(loga::err("Another client is already registered to handle interactions"));
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        inner.handler = Some(Handler {
+            id: id,
+            process: process,
+        });
+        return Ok(UiHandler {
+            delegate: self.clone(),
+            id: id,
+        });
+    }
+
+    /// Queue a question for the registered client. Returns `None` if no client is
+    /// registered. The receiver gets `None` if the client cancels or disconnects.
+    fn ask(
+        &self,
+        title: &Title,
+        warning: Option<String>,
+        variant: ipc::UiQuestionVariant,
+    ) -> Option<oneshot::Receiver<Option<ipc::UiAnswer>>> {
+        return self.ask_excluding(title, warning, variant, &[]);
+    }
+
+    /// Like `ask`, but also returns `None` if the registered client's process is one
+    /// of `chain`.
+    fn ask_excluding(
+        &self,
+        title: &Title,
+        warning: Option<String>,
+        variant: ipc::UiQuestionVariant,
+        chain: &[Inode],
+    ) -> Option<oneshot::Receiver<Option<ipc::UiAnswer>>> {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(handler) = &inner.handler else {
+            return None;
+        };
+        if chain.contains(&handler.process) {
+            return None;
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (answer_tx, answer_rx) = oneshot::channel();
+        inner.pending = Some(Pending {
+            id: id,
+            question: Some(ipc::UiQuestion {
+                id: id,
+                title: title.text(),
+                warning: warning,
+                variant: variant,
+            }),
+            answer: answer_tx,
+        });
+        self.question_ready.notify_one();
+        return Some(answer_rx);
+    }
+}
+
+pub struct UiHandler {
+    delegate: Arc<UiDelegate>,
+    id: u64,
+}
+
+impl UiHandler {
+    /// Wait for the next question.
+    pub async fn next(&self) -> ipc::UiQuestion {
+        loop {
+            if let Some(question) =
+                self.delegate.inner.lock().unwrap().pending.as_mut().and_then(|p| p.question.take()) {
+                return question;
+            }
+            self.delegate.question_ready.notified().await;
+        }
+    }
+
+    pub fn answer(&self, req: ipc::ReqUiAnswer) -> Result<(), loga::Error> {
+        let mut inner = self.delegate.inner.lock().unwrap();
+        let Some(pending) = inner.pending.take_if(|p| p.id == req.id) else {
+            return Err(loga::err_with("No outstanding interaction with this id", ea!(id = req.id)));
+        };
+        pending.answer.send(req.answer).ignore();
+        return Ok(());
+    }
+}
+
+impl Drop for UiHandler {
+    fn drop(&mut self) {
+        let mut inner = self.delegate.inner.lock().unwrap();
+        if inner.handler.as_ref().map(|x| x.id) == Some(self.id) {
+            inner.handler = None;
+            inner.pending = None;
+        }
+    }
+}
+
+/// Sends interactions to the registered client if there is one, otherwise to the
+/// wrapped frontend.
+#[derive(Clone)]
+pub struct DelegatedUi<U: FgUi> {
+    pub log: Log,
+    pub delegate: Arc<UiDelegate>,
+    pub inner: U,
+}
+
+impl<U: FgUi> DelegatedUi<U> {
+    fn unexpected<T>(&self) -> Option<T> {
+        self.log.log(loga::WARN, "Interaction client sent the wrong type of answer, cancelling");
+        return None;
+    }
+}
+
+impl<U: FgUi> FgUi for DelegatedUi<U> {
+    async fn choose(
+        &self,
+        warning: Option<String>,
+        title: Title,
+        choices: &Vec<Arc<FactorTree>>,
+    ) -> Option<Arc<FactorTree>> {
+        let Some(answer) =
+            self
+                .delegate
+                .ask(
+                    &title,
+                    warning.clone(),
+                    ipc::UiQuestionVariant::Choose(choices.iter().map(|x| x.desc.clone()).collect()),
+                ) else {
+                return self.inner.choose(warning, title, choices).await;
+            };
+        match answer.await.ok().flatten()? {
+            ipc::UiAnswer::Choice(i) => return choices.get(i).cloned(),
+            _ => return self.unexpected(),
+        }
+    }
+
+    async fn password(&self, warning: Option<String>, title: Title, desc: &str) -> Option<Vec<u8>> {
+        let Some(answer) =
+            self.delegate.ask(&title, warning.clone(), ipc::UiQuestionVariant::Password(desc.to_string())) else {
+                return self.inner.password(warning, title, desc).await;
+            };
+        match answer.await.ok().flatten()? {
+            ipc::UiAnswer::Secret(s) => return Some(s.into_bytes()),
+            _ => return self.unexpected(),
+        }
+    }
+
+    async fn new_password(&self, warning: Option<String>, title: Title, desc: &str) -> Option<Vec<u8>> {
+        let Some(answer) =
+            self.delegate.ask(&title, warning.clone(), ipc::UiQuestionVariant::NewPassword(desc.to_string())) else {
+                return self.inner.new_password(warning, title, desc).await;
+            };
+        match answer.await.ok().flatten()? {
+            ipc::UiAnswer::Secret(s) => return Some(s.into_bytes()),
+            _ => return self.unexpected(),
+        }
+    }
+
+    async fn show_recovery_phrase(&self, warning: Option<String>, title: Title, phrase: &[String]) -> Option<()> {
+        let Some(answer) =
+            self
+                .delegate
+                .ask(&title, warning.clone(), ipc::UiQuestionVariant::ShowRecoveryPhrase(phrase.to_vec())) else {
+                return self.inner.show_recovery_phrase(warning, title, phrase).await;
+            };
+        match answer.await.ok().flatten()? {
+            ipc::UiAnswer::Done => return Some(()),
+            _ => return self.unexpected(),
+        }
+    }
+
+    async fn recovery_phrase(&self, warning: Option<String>, title: Title, message: &str) -> Option<Vec<u8>> {
+        let Some(answer) =
+            self
+                .delegate
+                .ask(&title, warning.clone(), ipc::UiQuestionVariant::RecoveryPhrase(message.to_string())) else {
+                return self.inner.recovery_phrase(warning, title, message).await;
+            };
+        match answer.await.ok().flatten()? {
+            ipc::UiAnswer::Secret(s) => return Some(s.split_whitespace().collect::<Vec<_>>().join(" ").into_bytes()),
+            _ => return self.unexpected(),
+        }
+    }
+
+    async fn smartcard_pin(&self, warning: Option<String>, title: Title) -> Option<String> {
+        let Some(answer) = self.delegate.ask(&title, warning.clone(), ipc::UiQuestionVariant::SmartcardPin) else {
+            return self.inner.smartcard_pin(warning, title).await;
+        };
+        match answer.await.ok().flatten()? {
+            ipc::UiAnswer::Secret(s) => return Some(s),
+            _ => return self.unexpected(),
+        }
+    }
+
+    async fn get_smartcard(
+        &self,
+        card_stream: &mut CardStream,
+        warning: Option<String>,
+        title: Title,
+        fingerprints: &[&str],
+    ) -> Option<openpgp_card_sequoia::Card<Open>> {
+        let Some(answer) =
+            self
+                .delegate
+                .ask(
+                    &title,
+                    warning.clone(),
+                    ipc::UiQuestionVariant::GetSmartcard(fingerprints.iter().map(|x| x.to_string()).collect()),
+                ) else {
+                return self.inner.get_smartcard(card_stream, warning, title, fingerprints).await;
+            };
+
+        // Informational, any answer cancels
+        return select!{
+            card = card_stream.next() => card,
+            _ = answer => None,
+        };
+    }
+
+    async fn smartcard_touch<T>(
+        &self,
+        warning: Option<String>,
+        title: Title,
+        fingerprint: &str,
+        touch: impl Future<Output = T>,
+    ) -> Option<T> {
+        let Some(answer) =
+            self
+                .delegate
+                .ask(&title, warning.clone(), ipc::UiQuestionVariant::SmartcardTouch(fingerprint.to_string())) else {
+                return self.inner.smartcard_touch(warning, title, fingerprint, touch).await;
+            };
+
+        // Informational, any answer cancels
+        return select!{
+            t = touch => Some(t),
+            _ = answer => None,
+        };
+    }
+
+    async fn fido2_touch<T>(
+        &self,
+        warning: Option<String>,
+        title: Title,
+        desc: &str,
+        touch: impl Future<Output = T>,
+    ) -> Option<T> {
+        let Some(answer) =
+            self.delegate.ask(&title, warning.clone(), ipc::UiQuestionVariant::Fido2Touch(desc.to_string())) else {
+                return self.inner.fido2_touch(warning, title, desc, touch).await;
+            };
+
+        // Informational, any answer cancels
+        return select!{
+            t = touch => Some(t),
+            _ = answer => None,
+        };
+    }
+
+    async fn get_keyfile<T>(
+        &self,
+        warning: Option<String>,
+        title: Title,
+        path: &Path,
+        wait: impl Future<Output = T>,
+    ) -> Option<T> {
+        let Some(answer) =
+            self
+                .delegate
+                .ask(
+                    &title,
+                    warning.clone(),
+                    ipc::UiQuestionVariant::GetKeyfile(path.to_string_lossy().to_string()),
+                ) else {
+                return self.inner.get_keyfile(warning, title, path, wait).await;
+            };
+
+        // Informational, any answer cancels
+        return select!{
+            t = wait => Some(t),
+            _ = answer => None,
+        };
+    }
+
+    async fn backoff<T>(
+        &self,
+        warning: Option<String>,
+        title: Title,
+        delay: Duration,
+        wait: impl Future<Output = T>,
+    ) -> Option<T> {
+        let Some(answer) =
+            self.delegate.ask(&title, warning.clone(), ipc::UiQuestionVariant::Backoff(delay.as_secs())) else {
+                return self.inner.backoff(warning, title, delay, wait).await;
+            };
+
+        // Informational, any answer cancels
+        return select!{
+            t = wait => Some(t),
+            _ = answer => None,
+        };
+    }
+
+    async fn prompt(&self, args: Arc<B2FPrompt>) -> Result<Option<PromptDecision>, loga::Error> {
+        let mut rule_descs = args.prompt_rules.values().map(|(desc, _)| desc.clone()).collect::<Vec<_>>();
+        rule_descs.sort();
+
+        // The client could approve its own requests (or those of processes it started),
+        // so those go to the frontend
+        let Some(answer) =
+            self
+                .delegate
+                .ask_excluding(
+                    &Title::Prompt(rule_descs.join(", ")),
+                    None,
+                    ipc::UiQuestionVariant::Prompt(ipc::UiQuestionPrompt {
+                        rules: rule_descs,
+                        variant: args.variant.clone(),
+                        paths: args.paths.clone(),
+                        principal: args.principal.clone(),
+                        grant_seconds: PROMPT_GRANT_DURATION.as_secs(),
+                    }),
+                    &args.chain,
+                ) else {
+                return self.inner.prompt(args).await;
+            };
+        let Some(answer) = answer.await.ok().flatten() else {
+            return Ok(None);
+        };
+        match answer {
+            ipc::UiAnswer::Prompt(d) => return Ok(Some(match d {
+                ipc::UiPromptDecision::AllowOnce => PromptDecision::AllowOnce,
+                ipc::UiPromptDecision::AllowFor => PromptDecision::AllowFor(PROMPT_GRANT_DURATION),
+                ipc::UiPromptDecision::AllowProcess => PromptDecision::AllowProcess,
+                ipc::UiPromptDecision::Deny => PromptDecision::Deny,
+                ipc::UiPromptDecision::DenyFor => PromptDecision::DenyFor(PROMPT_GRANT_DURATION),
+            })),
+            _ => return Ok(self.unexpected()),
+        }
+    }
+}
@@ -0,0 +1,259 @@
+//! Terminal implementation of foreground interactions, for machines without a
+//! graphical session. End of input (ctrl+d) aborts the current interaction.
+use {
+    super::{
+        factor::FactorTree,
+        fg::{
+            B2FPrompt,
+            FgUi,
+            Title,
+        },
+        grants::{
+            PromptDecision,
+            PROMPT_GRANT_DURATION,
+        },
+    },
+    loga::Log,
+    openpgp_card_sequoia::state::Open,
+    passworth_native::{
+        crypto::CardStream,
+        generate::{
+            bip39,
+            BIP39_PHRASELEN,
+        },
+        tty::tty_io,
+    },
+    std::{
+        collections::HashSet,
+        future::Future,
+        path::{
+            Path,
+            PathBuf,
+        },
+        sync::Arc,
+        time::Duration,
+    },
+    tokio::task::spawn_blocking,
+};
+
+#[derive(Clone)]
+pub struct TtyUi {
+    pub log: Log,
+    pub path: PathBuf,
+}
+
+fn header(title: &Title, warning: Option<String>) -> String {
+    let mut out = format!("\n== Passworth - {} ==\n", title.text());
+    if let Some(warning) = warning {
+        out.push_str(&format!("! {}\n", warning));
+    }
+    return out;
+}
+
+impl TtyUi {
+    async fn io(&self, output: String, input: Option<bool>) -> Option<String> {
+        let path = self.path.clone();
+        match spawn_blocking(move || tty_io(&path, &output, input)).await {
+            Ok(Ok(x)) => return x,
+            Ok(Err(e)) => {
+                self.log.log_err(loga::WARN, e);
+                return None;
+            },
+            Err(e) => {
+                self.log.log_err(loga::WARN, loga::err(e.to_string()).context("Terminal io worker failed"));
+                return None;
+            },
+        }
+    }
+
+    async fn say(&self, output: String) {
+        self.io(output, None).await;
+    }
+}
+
+impl FgUi for TtyUi {
+    async fn choose(
+        &self,
+        warning: Option<String>,
+        title: Title,
+        choices: &Vec<Arc<FactorTree>>,
+    ) -> Option<Arc<FactorTree>> {
+        let mut output = header(&title, warning);
+        output.push_str("Choose an unlock method.\n");
+        for (i, method) in choices.iter().enumerate() {
+            output.push_str(&format!("  {}. {}\n", i + 1, method.desc));
+        }
+        loop {
+            let line = self.io(format!("{}Number: ", output), Some(false)).await?;
+            if let Ok(i) = usize::from_str_radix(line.trim(), 10) {
+                if i >= 1 && i <= choices.len() {
+                    return Some(choices[i - 1].clone());
+                }
+            }
+            output = "Invalid choice.\n".to_string();
+        }
+    }
+
+    async fn password(&self, warning: Option<String>, title: Title, desc: &str) -> Option<Vec<u8>> {
+        let output = format!("{}Enter password for {}.\nPassword: ", header(&title, warning), desc);
+        return Some(self.io(output, Some(true)).await?.into_bytes());
+    }
+
+    async fn new_password(&self, warning: Option<String>, title: Title, desc: &str) -> Option<Vec<u8>> {
+        let mut output = format!("{}Enter new password for [{}].\n", header(&title, warning), desc);
+        loop {
+            let password = self.io(format!("{}Password: ", output), Some(true)).await?;
+            let confirm = self.io("Confirm: ".to_string(), Some(true)).await?;
+            if password == confirm {
+                return Some(password.into_bytes());
+            }
+            output = "Password mismatch\n".to_string();
+        }
+    }
+
+    async fn show_recovery_phrase(&self, warning: Option<String>, title: Title, phrase: &[String]) -> Option<()> {
+        let mut output = header(&title, warning);
+        output.push_str("Write down this phrase list, with numbers, and store it somewhere secure.\n");
+        for (i, word) in phrase.iter().enumerate() {
+            output.push_str(&format!("  {}. {}\n", i + 1, word));
+        }
+        output.push_str("Press enter when done.");
+        self.io(output, Some(false)).await?;
+
+        // Scroll the phrase out of view
+        self.say("\n".repeat(100)).await;
+        return Some(());
+    }
+
+    async fn recovery_phrase(&self, warning: Option<String>, title: Title, message: &str) -> Option<Vec<u8>> {
+        let word_set = bip39().into_iter().collect::<HashSet<_>>();
+        let mut output = format!("{}{}\nEnter the words separated by spaces.\n", header(&title, warning), message);
+        loop {
+            let line = self.io(format!("{}Phrase: ", output), Some(true)).await?;
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            if words.len() != BIP39_PHRASELEN {
+                output = format!("Expected {} words but got {}.\n", BIP39_PHRASELEN, words.len());
+                continue;
+            }
+            if !words.iter().all(|x| word_set.contains(x)) {
+                output = "Some words are spelled incorrectly\n".to_string();
+                continue;
+            }
+            return Some(words.join(" ").into_bytes());
+        }
+    }
+
+    async fn smartcard_pin(&self, warning: Option<String>, title: Title) -> Option<String> {
+        return self.io(format!("{}Enter card PIN.\nPIN: ", header(&title, warning)), Some(true)).await;
+    }
+
+    async fn get_smartcard(
+        &self,
+        card_stream: &mut CardStream,
+        warning: Option<String>,
+        title: Title,
+        fingerprints: &[&str],
+    ) -> Option<openpgp_card_sequoia::Card<Open>> {
+        let mut output = header(&title, warning);
+        output.push_str("Insert or swipe smartcard with GPG fingerprint:\n");
+        for fingerprint in fingerprints {
+            output.push_str(&format!("  • {}\n", fingerprint));
+        }
+        self.say(output).await;
+        return card_stream.next().await;
+    }
+
+    async fn smartcard_touch<T>(
+        &self,
+        warning: Option<String>,
+        title: Title,
+        fingerprint: &str,
+        touch: impl Future<Output = T>,
+    ) -> Option<T> {
+        self
+            .say(format!("{}Confirm the action on the smartcard {}\n", header(&title, warning), fingerprint))
+            .await;
+        return Some(touch.await);
+    }
+
+    async fn fido2_touch<T>(
+        &self,
+        warning: Option<String>,
+        title: Title,
+        desc: &str,
+        touch: impl Future<Output = T>,
+    ) -> Option<T> {
+        self.say(format!("{}Touch the security key for {}\n", header(&title, warning), desc)).await;
+        return Some(touch.await);
+    }
+
+    async fn get_keyfile<T>(
+        &self,
+        warning: Option<String>,
+        title: Title,
+        path: &Path,
+        wait: impl Future<Output = T>,
+    ) -> Option<T> {
+        self.say(format!("{}Waiting for keyfile {}\n", header(&title, warning), path.to_string_lossy())).await;
+        return Some(wait.await);
+    }
+
+    async fn backoff<T>(
+        &self,
+        warning: Option<String>,
+        title: Title,
+        delay: Duration,
+        wait: impl Future<Output = T>,
+    ) -> Option<T> {
+        self
+            .say(
+                format!(
+                    "{}Unlocking is delayed {} seconds due to repeated failed attempts\n",
+                    header(&title, warning),
+                    delay.as_secs()
+                ),
+            )
+            .await;
+        return Some(wait.await);
+    }
+
+    async fn prompt(&self, args: Arc<B2FPrompt>) -> Result<Option<PromptDecision>, loga::Error> {
+        let mut rule_descs = args.prompt_rules.values().map(|(desc, _)| desc.clone()).collect::<Vec<_>>();
+        rule_descs.sort();
+        let grant_minutes = PROMPT_GRANT_DURATION.as_secs() / 60;
+        let mut output = header(&Title::Prompt(rule_descs.join(", ")), None);
+        output.push_str("Confirm request by application matching rule?\n");
+        for desc in &rule_descs {
+            output.push_str(&format!("  {}\n", desc));
+        }
+        output.push_str(&format!("Request: {}\n", args.variant));
+        output.push_str("Paths:\n");
+        for path in &args.paths {
+            output.push_str(&format!("  {}\n", path.to_string()));
+        }
+        output.push_str("Requester (process, then parents):\n");
+        for line in args.principal.lines() {
+            output.push_str(&format!("  {}\n", line));
+        }
+        output.push_str("[o] Allow once\n");
+        output.push_str(&format!("[m] Allow for {} minutes\n", grant_minutes));
+        output.push_str("[p] Allow until process exits\n");
+        output.push_str("[d] Deny\n");
+        output.push_str(&format!("[D] Deny for {} minutes\n", grant_minutes));
+        loop {
+            let Some(line) = self.io(format!("{}Choice: ", output), Some(false)).await else {
+                return Ok(None);
+            };
+            match line.trim() {
+                "o" => return Ok(Some(PromptDecision::AllowOnce)),
+                "m" => return Ok(Some(PromptDecision::AllowFor(PROMPT_GRANT_DURATION))),
+                "p" => return Ok(Some(PromptDecision::AllowProcess)),
+                "d" => return Ok(Some(PromptDecision::Deny)),
+                "D" => return Ok(Some(PromptDecision::DenyFor(PROMPT_GRANT_DURATION))),
+                _ => {
+                    output = "Invalid choice.\n".to_string();
+                },
+            }
+        }
+    }
+}
@@ -0,0 +1,123 @@
+//! Deriving factor tokens from a FIDO2 authenticator's hmac-secret extension. All
+//! functions are blocking, and block until the user touches the authenticator.
+use {
+    ctap_hid_fido2::{
+        fidokey::{
+            get_assertion::get_assertion_params::Extension as GetExtension,
+            make_credential::make_credential_params::Extension as MakeExtension,
+            GetAssertionArgsBuilder,
+            MakeCredentialArgsBuilder,
+        },
+        verifier,
+        Cfg,
+        FidoKeyHidFactory,
+    },
+    loga::ResultContext,
+    passworth_native::config::latest::ConfigCredFido2,
+    rand::{
+        rng,
+        RngCore,
+    },
+    serde::{
+        Deserialize,
+        Serialize,
+    },
+};
+
+pub const DEFAULT_RP_ID: &str = "passworth";
+
+/// Stored in the factor state.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct Fido2State {
+    /// Id of the credential created on the authenticator
+    pub credential_id: Vec<u8>,
+    /// Input to hmac-secret, the output of which is the token
+    pub salt: Vec<u8>,
+}
+
+fn rp_id(config: &ConfigCredFido2) -> &str {
+    return config.rp_id.as_ref().map(|x| x.as_str()).unwrap_or(DEFAULT_RP_ID);
+}
+
+/// Create a new hmac-secret credential on the connected authenticator.
+pub fn enroll(config: &ConfigCredFido2) -> Result<Fido2State, loga::Error> {
+    let device =
+        FidoKeyHidFactory::create(&Cfg::init())
+            .map_err(|e| loga::err(e.to_string()))
+            .context("Error connecting to FIDO2 authenticator")?;
+    let challenge = verifier::create_challenge();
+    let attestation =
+        device
+            .make_credential_with_args(
+                &MakeCredentialArgsBuilder::new(rp_id(config), &challenge)
+                    .without_pin_and_uv()
+                    .extensions(&[MakeExtension::HmacSecret(Some(true))])
+                    .build(),
+            )
+            .map_err(|e| loga::err(e.to_string()))
+            .context("Error creating credential on FIDO2 authenticator")?;
+    if !attestation.extensions.iter().any(|x| matches!(x, MakeExtension::HmacSecret(Some(true)))) {
+        return Err(loga::err("FIDO2 authenticator doesn't support the hmac-secret extension"));
+    }
+    let verified = verifier::verify_attestation(rp_id(config), &challenge, &attestation);
+    if !verified.is_success {
+        return Err(loga::err("FIDO2 authenticator returned an invalid attestation"));
+    }
+    let mut salt = vec![0u8; 32];
+    rng().fill_bytes(&mut salt);
+    return Ok(Fido2State {
+        credential_id: verified.credential_id,
+        salt: salt,
+    });
+}
+
+/// Get the token for an enrolled credential.
+pub fn derive(config: &ConfigCredFido2, state: &Fido2State) -> Result<Vec<u8>, loga::Error> {
+    let salt =
+        <[u8; 32]>::try_from(
+            state.salt.as_slice(),
+        ).map_err(|_| loga::err("Stored FIDO2 salt has the wrong length, expected 32 bytes"))?;
+    let device =
+        FidoKeyHidFactory::create(&Cfg::init())
+            .map_err(|e| loga::err(e.to_string()))
+            .context("Error connecting to FIDO2 authenticator")?;
+    let challenge = verifier::create_challenge();
+    let assertions =
+        device
+            .get_assertion_with_args(
+                &GetAssertionArgsBuilder::new(rp_id(config), &challenge)
+                    .without_pin_and_uv()
+                    .credential_id(&state.credential_id)
+                    .extensions(&[GetExtension::HmacSecret(Some(salt))])
+                    .build(),
+            )
+            .map_err(|e| loga::err(e.to_string()))
+            .context("Error getting assertion from FIDO2 authenticator, it may not be the enrolled one")?;
+    for assertion in assertions {
+        for ext in assertion.extensions {
+            if let GetExtension::HmacSecret(Some(output)) = ext {
+                return Ok(output.to_vec());
+            }
+        }
+    }
+    return Err(loga::err("FIDO2 authenticator didn't return an hmac-secret"));
+}
+
+#[test]
+#[ignore]
+fn test_enroll_derive() {
+    // Needs an authenticator that doesn't wait for touch, ex: a software authenticator
+    // on a virtual (uhid) device, as the only one connected. Run with `cargo test --
+    // --ignored test_enroll_derive`.
+    let config = ConfigCredFido2 { rp_id: Some("passworth-test".to_string()) };
+    let state = enroll(&config).unwrap();
+    let token = derive(&config, &state).unwrap();
+    assert_eq!(token.len(), 32);
+    assert_eq!(token, derive(&config, &state).unwrap());
+    let other_salt = Fido2State {
+        credential_id: state.credential_id.clone(),
+        salt: vec![1u8; 32],
+    };
+    assert_ne!(token, derive(&config, &other_salt).unwrap());
+}
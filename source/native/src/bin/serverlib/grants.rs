@@ -0,0 +1,143 @@
+//! Decisions made at access prompts, remembered per requester and rule.
+use {
+    super::{
+        permission::PrincipalMeta,
+        pidfd::Inode,
+    },
+    std::{
+        collections::HashMap,
+        os::fd::OwnedFd,
+        time::{
+            Duration,
+            Instant,
+        },
+    },
+};
+
+/// How long the "for a while" prompt choices last.
+pub const PROMPT_GRANT_DURATION: Duration = Duration::from_secs(15 * 60);
+
+pub enum PromptDecision {
+    AllowOnce,
+    AllowFor(Duration),
+    /// Until the requesting process exits.
+    AllowProcess,
+    Deny,
+    DenyFor(Duration),
+}
+
+#[derive(Hash, PartialEq, Eq, Clone)]
+enum GrantPrincipal {
+    /// The pidfd inode of a specific process.
+    Process(Inode),
+    /// The identity (`PrincipalMeta::identity`) of the requester, shared by new
+    /// processes started the same way.
+    Identity(String),
+}
+
+enum GrantExpiry {
+    At(Instant),
+    /// Pidfd becomes readable when the process exits
+    ProcessExit(OwnedFd),
+}
+
+struct Grant {
+    allow: bool,
+    expiry: GrantExpiry,
+}
+
+impl Grant {
+    fn expired(&self, now: Instant) -> bool {
+        match &self.expiry {
+            GrantExpiry::At(at) => return *at <= now,
+            GrantExpiry::ProcessExit(pidfd) => {
+                let mut fds = [rustix::event::PollFd::new(pidfd, rustix::event::PollFlags::IN)];
+                return rustix::event::poll(&mut fds, 0).map(|count| count > 0).unwrap_or(true);
+            },
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Grants(HashMap<(GrantPrincipal, usize), Grant>);
+
+impl Grants {
+    fn prune(&mut self, now: Instant) {
+        self.0.retain(|_, grant| !grant.expired(now));
+    }
+
+    /// Returns `Some(true)` if there's an active allow grant for the rule, `Some(false)`
+    /// for an active deny.
+    pub fn get(&mut self, principal: &PrincipalMeta, rule: usize) -> Option<bool> {
+        let now = Instant::now();
+        self.prune(now);
+        let mut keys = vec![(GrantPrincipal::Identity(principal.identity()), rule)];
+        if let Some(inode) = principal.chain.first().and_then(|x| x.pidfd_inode) {
+            keys.push((GrantPrincipal::Process(inode), rule));
+        }
+        let mut out = None;
+        for key in keys {
+            let Some(grant) = self.0.get(&key) else {
+                continue;
+            };
+
+            // Denies win
+            if !grant.allow {
+                return Some(false);
+            }
+            out = Some(true);
+        }
+        return out;
+    }
+
+    /// Remember a prompt decision for the rule. `remember` is the rule's configured
+    /// remember duration, applied to single approvals. `pidfd` is only used for
+    /// process-lifetime approvals.
+    pub fn insert(
+        &mut self,
+        principal: &PrincipalMeta,
+        rule: usize,
+        decision: &PromptDecision,
+        remember: Duration,
+        pidfd: Option<OwnedFd>,
+    ) {
+        let now = Instant::now();
+        let identity = (GrantPrincipal::Identity(principal.identity()), rule);
+        match decision {
+            PromptDecision::AllowOnce => {
+                if remember.is_zero() {
+                    return;
+                }
+                self.0.insert(identity, Grant {
+                    allow: true,
+                    expiry: GrantExpiry::At(now + remember),
+                });
+            },
+            PromptDecision::AllowFor(duration) => {
+                self.0.insert(identity, Grant {
+                    allow: true,
+                    expiry: GrantExpiry::At(now + *duration),
+                });
+            },
+            PromptDecision::AllowProcess => {
+                let Some(inode) = principal.chain.first().and_then(|x| x.pidfd_inode) else {
+                    return;
+                };
+                let Some(pidfd) = pidfd else {
+                    return;
+                };
+                self.0.insert((GrantPrincipal::Process(inode), rule), Grant {
+                    allow: true,
+                    expiry: GrantExpiry::ProcessExit(pidfd),
+                });
+            },
+            PromptDecision::Deny => { },
+            PromptDecision::DenyFor(duration) => {
+                self.0.insert(identity, Grant {
+                    allow: false,
+                    expiry: GrantExpiry::At(now + *duration),
+                });
+            },
+        }
+    }
+}
@@ -0,0 +1,44 @@
+//! Deriving factor tokens from the contents of a file. All functions are blocking.
+use {
+    loga::{
+        ea,
+        ResultContext,
+    },
+    passworth_native::config::latest::ConfigCredKeyfile,
+    sha2::{
+        Digest,
+        Sha256,
+    },
+    std::{
+        io::ErrorKind,
+        path::PathBuf,
+    },
+};
+
+/// Set by systemd for services with `LoadCredential=` or
+/// `LoadCredentialEncrypted=`.
+pub const ENV_CREDENTIALS_DIRECTORY: &str = "CREDENTIALS_DIRECTORY";
+
+/// Relative paths are resolved in the systemd credentials directory if there is one.
+pub fn keyfile_path(config: &ConfigCredKeyfile) -> PathBuf {
+    if config.path.is_relative() {
+        if let Some(dir) = std::env::var_os(ENV_CREDENTIALS_DIRECTORY) {
+            return PathBuf::from(dir).join(&config.path);
+        }
+    }
+    return config.path.clone();
+}
+
+/// Returns `None` if the file doesn't exist (yet), ex: if the drive it's on isn't
+/// inserted.
+pub fn read_keyfile(path: &PathBuf) -> Result<Option<Vec<u8>>, loga::Error> {
+    let body = match std::fs::read(path) {
+        Ok(b) => b,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).context_with("Error reading keyfile", ea!(path = path.to_string_lossy())),
+    };
+    if body.is_empty() {
+        return Err(loga::err_with("Keyfile is empty", ea!(path = path.to_string_lossy())));
+    }
+    return Ok(Some(Sha256::digest(&body).to_vec()));
+}
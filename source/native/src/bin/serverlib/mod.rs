@@ -4,4 +4,18 @@ pub mod dbutil;
 pub mod fg;
 pub mod factor;
 pub mod permission;
-pub mod pidfd;
\ No newline at end of file
+pub mod pidfd;
+pub mod state;
+pub mod sshagent;
+pub mod auditdb;
+pub mod audit;
+pub mod unlockconfig;
+pub mod grants;
+pub mod fgtty;
+pub mod fgipc;
+pub mod tpm;
+pub mod fido2;
+pub mod keyfile;
+pub mod unlockbackoff;
+pub mod watch;
+pub mod ops;
@@ -0,0 +1,263 @@
+//! Data operations shared by the individual requests and batches. Each runs inside
+//! the caller's priv database transaction.
+use {
+    super::{
+        dbutil::{
+            bury,
+            get,
+            set,
+        },
+        privdb,
+    },
+    chrono::{
+        DateTime,
+        Utc,
+    },
+    loga::ResultContext,
+    passworth::{
+        datapath::SpecificPath,
+        ipc,
+        utils::to_b32,
+    },
+    passworth_native::generate,
+    rusqlite::Transaction,
+    sequoia_openpgp::cert::CertBuilder,
+};
+
+pub enum OpErr {
+    /// Caused by the request or the stored data, reported to the client as is.
+    Resp(ipc::RespError),
+    Err(loga::Error),
+}
+
+impl From<loga::Error> for OpErr {
+    fn from(value: loga::Error) -> Self {
+        return Self::Err(value);
+    }
+}
+
+impl OpErr {
+    pub fn resp(code: ipc::ErrorCode, message: impl ToString) -> Self {
+        return Self::Resp(ipc::RespError::new(code, message));
+    }
+}
+
+/// The string stored at the path, for keys, urls, etc.
+pub fn get_string(txn: &mut Transaction, path: &SpecificPath) -> Result<String, OpErr> {
+    match get(txn, path, None)? {
+        serde_json::Value::Null => return Err(OpErr::resp(ipc::ErrorCode::NotFound, "No value at path")),
+        serde_json::Value::String(v) => return Ok(v),
+        _ => return Err(OpErr::resp(ipc::ErrorCode::WrongType, "Value at path is not a string")),
+    }
+}
+
+fn precondition_holds(
+    txn: &mut Transaction,
+    path: &SpecificPath,
+    precondition: &ipc::WritePrecondition,
+) -> Result<bool, OpErr> {
+    match precondition {
+        ipc::WritePrecondition::Absent => {
+            // A value above would be cleared by writing here, so it counts too
+            for i in 0 .. path.0.len() {
+                let parent_path = SpecificPath(path.0[..i].iter().cloned().collect()).to_string();
+                if privdb::values_get_exact(txn, &parent_path, i64::MAX)?
+                    .filter(
+                        |x| serde_json::from_str::<serde_json::Value>(&x.data).unwrap() != serde_json::Value::Null,
+                    )
+                    .is_some() {
+                    return Ok(false);
+                }
+            }
+            for row in privdb::values_get(txn, &path.to_string(), i64::MAX)? {
+                if serde_json::from_str::<serde_json::Value>(&row.data).unwrap() != serde_json::Value::Null {
+                    return Ok(false);
+                }
+            }
+            return Ok(true);
+        },
+        ipc::WritePrecondition::RevStamp(stamp) => {
+            let Ok(stamp) = DateTime::parse_from_rfc3339(stamp) else {
+                return Err(OpErr::resp(ipc::ErrorCode::InvalidArgument, "Invalid rev_stamp, expected RFC 3339"));
+            };
+            let stamp = stamp.with_timezone(&Utc);
+            let latest =
+                privdb::values_get(txn, &path.to_string(), i64::MAX)?.into_iter().map(|row| row.rev_stamp).max();
+            return Ok(latest == Some(stamp));
+        },
+    }
+}
+
+fn check_preconditions(
+    txn: &mut Transaction,
+    preconditions: &[(SpecificPath, ipc::WritePrecondition)],
+) -> Result<(), OpErr> {
+    for (path, precondition) in preconditions {
+        if !precondition_holds(txn, path, precondition)? {
+            return Err(
+                OpErr::resp(ipc::ErrorCode::Conflict, format!("Precondition failed at {}", path.to_string())),
+            );
+        }
+    }
+    return Ok(());
+}
+
+pub fn read(txn: &mut Transaction, req: &ipc::ReqRead) -> Result<serde_json::Value, loga::Error> {
+    let mut root = serde_json::Value::Null;
+    for path in &req.paths {
+        bury(&mut root, path, get(txn, path, req.at)?);
+    }
+    return Ok(root);
+}
+
+pub fn write(txn: &mut Transaction, req: ipc::ReqWrite) -> Result<(), OpErr> {
+    check_preconditions(txn, &req.preconditions)?;
+    set(txn, req.values)?;
+    return Ok(());
+}
+
+pub fn write_move(txn: &mut Transaction, req: ipc::ReqWriteMove) -> Result<(), OpErr> {
+    check_preconditions(txn, &req.preconditions)?;
+    if get(txn, &req.to, None)? != serde_json::Value::Null && !req.overwrite {
+        return Err(
+            OpErr::resp(ipc::ErrorCode::Conflict, "Attempt to move over existing value with overwrite off"),
+        );
+    }
+    let data = get(txn, &req.from, None)?;
+    set(txn, vec![(req.from, serde_json::Value::Null), (req.to, data)])?;
+    return Ok(());
+}
+
+fn generate_value(variant: ipc::C2SGenerateVariant) -> Result<serde_json::Value, loga::Error> {
+    match variant {
+        ipc::C2SGenerateVariant::Bytes(args) => {
+            return Ok(serde_json::Value::String(to_b32(&generate::gen_bytes(args.length))));
+        },
+        ipc::C2SGenerateVariant::SafeAlphanumeric(args) => {
+            return Ok(serde_json::Value::String(generate::gen_safe_alphanum(args.length)));
+        },
+        ipc::C2SGenerateVariant::Alphanumeric(args) => {
+            return Ok(serde_json::Value::String(generate::gen_alphanum(args.length)));
+        },
+        ipc::C2SGenerateVariant::AlphanumericSymbols(args) => {
+            return Ok(serde_json::Value::String(generate::gen_alphanum_symbols(args.length)));
+        },
+        ipc::C2SGenerateVariant::Pgp => {
+            let (cert, _) =
+                CertBuilder::new()
+                    .set_cipher_suite(sequoia_openpgp::cert::CipherSuite::Cv25519)
+                    .add_signing_subkey()
+                    .add_subkey(
+                        sequoia_openpgp::types::KeyFlags::empty()
+                            .set_transport_encryption()
+                            .set_storage_encryption(),
+                        None,
+                        None,
+                    )
+                    .generate()
+                    .map_err(|e| loga::err(e.to_string()))
+                    .context("Error generating pgp cert")?;
+            {
+                let mut bytes = Vec::new();
+                sequoia_openpgp::serialize::Serialize::serialize(&cert.armored(), &mut bytes)
+                    .map_err(|e| loga::err(e.to_string()))
+                    .context("Error serializing public key")?;
+            }
+            let mut w =
+                sequoia_openpgp::armor::Writer::with_headers(
+                    vec![],
+                    sequoia_openpgp::armor::Kind::SecretKey,
+                    cert.armor_headers().iter().map(|value| ("Comment", value.as_str())).collect::<Vec<_>>(),
+                )?;
+            sequoia_openpgp::serialize::Serialize::serialize(&cert.as_tsk().armored(), &mut w)
+                .map_err(|e| loga::err(e.to_string()))
+                .context("Error serializing private key")?;
+            return Ok(serde_json::Value::String(String::from_utf8(w.finalize()?).unwrap()));
+        },
+        ipc::C2SGenerateVariant::Ssh => {
+            let key =
+                ssh_key::PrivateKey::random(
+                    &mut ssh_key::rand_core::OsRng,
+                    ssh_key::Algorithm::default(),
+                ).context("Error generating ssh key")?;
+            return Ok(
+                serde_json::Value::String(
+                    key
+                        .to_openssh(Default::default())
+                        .context("Error encoding ssh key in openssh PEM format")?
+                        .to_string(),
+                ),
+            );
+        },
+    }
+}
+
+pub fn write_generate(txn: &mut Transaction, req: ipc::ReqWriteGenerate) -> Result<(), OpErr> {
+    check_preconditions(txn, &req.preconditions)?;
+    if get(txn, &req.path, None)? != serde_json::Value::Null && !req.overwrite {
+        return Err(OpErr::resp(ipc::ErrorCode::Conflict, "Destructive command but flag to allow not specified"));
+    }
+    let data = generate_value(req.variant)?;
+    set(txn, vec![(req.path, data)])?;
+    return Ok(());
+}
+
+pub fn write_revert(txn: &mut Transaction, req: ipc::ReqWriteRevert) -> Result<(), loga::Error> {
+    for path in req.paths {
+        let data = get(txn, &path, Some(req.at))?;
+        set(txn, vec![(path, data)])?;
+    }
+    return Ok(());
+}
+
+/// Paths written by the operation, plus any checked by preconditions. These need
+/// `write` permission.
+pub fn write_paths(
+    written: impl IntoIterator<Item = SpecificPath>,
+    preconditions: &[(SpecificPath, ipc::WritePrecondition)],
+) -> Vec<SpecificPath> {
+    return written.into_iter().chain(preconditions.iter().map(|(path, _)| path.clone())).collect();
+}
+
+/// The paths the operation needs permission for, and the paths it writes (`None`
+/// if it only reads).
+pub fn batch_op_paths(op: &ipc::BatchOp) -> (Vec<SpecificPath>, Option<Vec<SpecificPath>>) {
+    match op {
+        ipc::BatchOp::Read(req) => return (req.paths.clone(), None),
+        ipc::BatchOp::Write(req) => {
+            let written = req.values.iter().map(|(path, _)| path.clone()).collect::<Vec<_>>();
+            return (write_paths(written.clone(), &req.preconditions), Some(written));
+        },
+        ipc::BatchOp::WriteMove(req) => {
+            let written = vec![req.from.clone(), req.to.clone()];
+            return (write_paths(written.clone(), &req.preconditions), Some(written));
+        },
+        ipc::BatchOp::WriteGenerate(req) => {
+            let written = vec![req.path.clone()];
+            return (write_paths(written.clone(), &req.preconditions), Some(written));
+        },
+        ipc::BatchOp::WriteRevert(req) => return (req.paths.clone(), Some(req.paths.clone())),
+    }
+}
+
+pub fn batch_op(txn: &mut Transaction, op: ipc::BatchOp) -> Result<ipc::BatchOpResult, OpErr> {
+    match op {
+        ipc::BatchOp::Read(req) => return Ok(ipc::BatchOpResult::Read(read(txn, &req)?)),
+        ipc::BatchOp::Write(req) => {
+            write(txn, req)?;
+            return Ok(ipc::BatchOpResult::Write);
+        },
+        ipc::BatchOp::WriteMove(req) => {
+            write_move(txn, req)?;
+            return Ok(ipc::BatchOpResult::WriteMove);
+        },
+        ipc::BatchOp::WriteGenerate(req) => {
+            write_generate(txn, req)?;
+            return Ok(ipc::BatchOpResult::WriteGenerate);
+        },
+        ipc::BatchOp::WriteRevert(req) => {
+            write_revert(txn, req)?;
+            return Ok(ipc::BatchOpResult::WriteRevert);
+        },
+    }
+}
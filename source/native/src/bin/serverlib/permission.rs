@@ -1,16 +1,16 @@
 use {
     super::pidfd::Inode,
     crate::serverlib::{
+        audit::AuditDecision,
         fg::{
             B2FPrompt,
             B2F,
         },
+        grants::PromptDecision,
         pidfd::pidfd,
+        state::State,
     },
-    flowcontrol::{
-        shed,
-        ta_return,
-    },
+    flowcontrol::ta_return,
     loga::{
         ea,
         DebugDisplay,
@@ -28,11 +28,22 @@ use {
             ConfigPermissionRule,
             ConfigPrompt,
             MatchBinary,
+            MatchCgroup,
+            MatchNamespace,
+            MatchSystemdUnit,
             UserGroupId,
         },
         v1::PermitLevel,
     },
-    serde::Serialize,
+    regex::Regex,
+    serde::{
+        Deserialize,
+        Serialize,
+    },
+    sha2::{
+        Digest,
+        Sha256,
+    },
     std::{
         collections::{
             HashMap,
@@ -56,12 +67,15 @@ use {
             Arc,
             Mutex,
         },
+        time::Duration,
     },
     tokio::{
         fs::{
             read,
             read_link,
+            File,
         },
+        io::AsyncReadExt,
         sync::oneshot,
         task::{
             spawn,
@@ -89,13 +103,31 @@ pub struct RuleMatchTag {
     pub user_id: u32,
 }
 
+#[derive(Debug)]
+pub struct RuleMatchBinary {
+    /// For debug output
+    pub config: MatchBinary,
+    pub walk_ancestors: usize,
+    pub path: Option<PathBuf>,
+    pub path_glob: Option<glob::Pattern>,
+    pub path_regex: Option<Regex>,
+    /// Lowercase hex
+    pub sha256: Option<String>,
+    pub first_arg_path: Option<PathBuf>,
+    pub first_arg_name: Option<String>,
+}
+
 #[derive(Debug)]
 pub struct Rule {
     pub id: usize,
     pub match_tag: Option<RuleMatchTag>,
     pub match_user: Option<RuleMatchUser>,
-    pub match_binary: Option<MatchBinary>,
+    pub match_binary: Option<RuleMatchBinary>,
+    pub match_cgroup: Option<MatchCgroup>,
+    pub match_systemd_unit: Option<MatchSystemdUnit>,
+    pub match_namespace: Option<MatchNamespace>,
     pub permit: PermitLevel,
+    pub deny: bool,
     pub prompt: Option<ConfigPrompt>,
 }
 
@@ -110,6 +142,9 @@ pub struct RuleTree {
 pub struct RuleTreeRoot {
     pub tree: Arc<RuleTree>,
     pub any_match_binary: bool,
+    /// The furthest ancestor any rule matches binary hashes on, if any. Principal scans
+    /// only hash executables up to here.
+    pub binary_sha256_walk_ancestors: Option<usize>,
 }
 
 pub fn build_rule_tree(
@@ -118,13 +153,54 @@ pub fn build_rule_tree(
 ) -> Result<RuleTreeRoot, loga::Error> {
     let mut rules = RuleTree::default();
     let mut any_match_binary = false;
+    let mut binary_sha256_walk_ancestors: Option<usize> = None;
     for (id, rule) in config_rules.iter().enumerate() {
         if rule.match_binary.is_some() {
             any_match_binary = true;
         }
         let out_rule = Arc::new(Rule {
             id: id,
-            match_binary: rule.match_binary.clone(),
+            match_binary: match &rule.match_binary {
+                Some(b) => {
+                    if b.path.is_none() && b.path_glob.is_none() && b.path_regex.is_none() && b.sha256.is_none() {
+                        return Err(
+                            loga::err_with(
+                                "Rule binary match must specify at least one of path, path_glob, path_regex, or sha256",
+                                ea!(rule = id),
+                            ),
+                        );
+                    }
+                    if b.sha256.is_some() {
+                        binary_sha256_walk_ancestors =
+                            Some(binary_sha256_walk_ancestors.unwrap_or(0).max(b.walk_ancestors));
+                    }
+                    Some(RuleMatchBinary {
+                        config: b.clone(),
+                        walk_ancestors: b.walk_ancestors,
+                        path: b.path.clone(),
+                        path_glob: match &b.path_glob {
+                            Some(g) => Some(
+                                glob::Pattern::new(
+                                    g,
+                                ).context_with("Invalid binary path glob in rule", ea!(rule = id, glob = g))?,
+                            ),
+                            None => None,
+                        },
+                        path_regex: match &b.path_regex {
+                            Some(r) => Some(
+                                Regex::new(
+                                    &format!("^(?:{})$", r),
+                                ).context_with("Invalid binary path regex in rule", ea!(rule = id, regex = r))?,
+                            ),
+                            None => None,
+                        },
+                        sha256: b.sha256.as_ref().map(|x| x.to_ascii_lowercase()),
+                        first_arg_path: b.first_arg_path.clone(),
+                        first_arg_name: b.first_arg_name.clone(),
+                    })
+                },
+                None => None,
+            },
             match_tag: match &rule.match_tag {
                 Some(r) => Some(RuleMatchTag {
                     walk_ancestors: r.walk_ancestors,
@@ -165,7 +241,39 @@ pub fn build_rule_tree(
                 }),
                 None => None,
             },
+            match_cgroup: rule.match_cgroup.clone(),
+            match_systemd_unit: rule.match_systemd_unit.clone(),
+            match_namespace: match &rule.match_namespace {
+                Some(n) => {
+                    if n.machine.is_none() && n.container_manager.is_none() && n.mnt.is_none() && n.pid.is_none() &&
+                        n.user.is_none() {
+                        return Err(
+                            loga::err_with(
+                                concat!(
+                                    "Rule namespace match must specify at least one of machine, container_manager, ",
+                                    "mnt, pid, or user"
+                                ),
+                                ea!(rule = id),
+                            ),
+                        );
+                    }
+                    if n.container_manager.is_some() && n.machine.is_none() && n.mnt.is_none() && n.pid.is_none() {
+                        return Err(
+                            loga::err_with(
+                                concat!(
+                                    "Rule namespace match container_manager can be set by any process so it must be ",
+                                    "combined with machine, mnt, or pid"
+                                ),
+                                ea!(rule = id),
+                            ),
+                        );
+                    }
+                    Some(n.clone())
+                },
+                None => None,
+            },
             permit: rule.permit,
+            deny: rule.deny,
             prompt: rule.prompt.clone(),
         });
         for path in &rule.paths {
@@ -187,27 +295,152 @@ pub fn build_rule_tree(
     return Ok(RuleTreeRoot {
         tree: Arc::new(rules),
         any_match_binary: any_match_binary,
+        binary_sha256_walk_ancestors: binary_sha256_walk_ancestors,
     });
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub struct PrincipalMetaProc {
     pid: i32,
     uid: Option<u32>,
     gid: Option<u32>,
     binary: Option<PathBuf>,
+    /// Lowercase hex, only populated if some rule matches on hash.
+    #[serde(default)]
+    binary_sha256: Option<String>,
     first_arg_path: Option<PathBuf>,
     tags: Option<HashSet<String>>,
+    #[serde(skip)]
+    pub pidfd_inode: Option<Inode>,
+    /// Unified hierarchy cgroup path, like `/system.slice/backup.service`.
+    #[serde(default)]
+    cgroup: Option<String>,
+    /// Namespace inodes
+    #[serde(default)]
+    mnt_ns: Option<u64>,
+    #[serde(default)]
+    pid_ns: Option<u64>,
+    #[serde(default)]
+    user_ns: Option<u64>,
+    /// Machine name from `systemd-machined` cgroup naming.
+    #[serde(default)]
+    machine: Option<String>,
+    /// `container` environment variable.
+    #[serde(default)]
+    container_manager: Option<String>,
+}
+
+/// Undo systemd unit name escaping (`\xNN`).
+fn systemd_unescape(name: &str) -> String {
+    let mut out = vec![];
+    let mut bytes = name.as_bytes();
+    while !bytes.is_empty() {
+        if bytes.len() >= 4 && bytes[0] == b'\\' && bytes[1] == b'x' {
+            if let Ok(b) = u8::from_str_radix(&String::from_utf8_lossy(&bytes[2..4]), 16) {
+                out.push(b);
+                bytes = &bytes[4..];
+                continue;
+            }
+        }
+        out.push(bytes[0]);
+        bytes = &bytes[1..];
+    }
+    return String::from_utf8_lossy(&out).to_string();
 }
 
+#[derive(Serialize)]
 pub struct PrincipalMeta {
     /// Starts at process, then first parent, then 2nd, etc.
-    chain: Vec<PrincipalMetaProc>,
+    pub chain: Vec<PrincipalMetaProc>,
 }
 
+impl PrincipalMeta {
+    /// A key for remembering decisions about this requester, without details that
+    /// change each time the requester is started (pids, tags, namespaces).
+    pub fn identity(&self) -> String {
+        return serde_json::to_string(
+            &self
+                .chain
+                .iter()
+                .map(|x| (x.uid, x.gid, &x.binary, &x.first_arg_path, &x.cgroup, &x.machine))
+                .collect::<Vec<_>>(),
+        ).unwrap();
+    }
+
+    /// Human readable description of the process chain, one line per process.
+    pub fn summary(&self) -> String {
+        let mut out = vec![];
+        for proc in &self.chain {
+            let mut line = format!("[{}]", proc.pid);
+            match &proc.binary {
+                Some(b) => line.push_str(&format!(" {}", b.to_string_lossy())),
+                None => line.push_str(" (unknown binary)"),
+            }
+            if let Some(a) = &proc.first_arg_path {
+                line.push_str(&format!(" {}", a.to_string_lossy()));
+            }
+            if let Some(uid) = proc.uid {
+                line.push_str(&format!(", uid {}", uid));
+            }
+            if let Some(tags) = &proc.tags {
+                let mut tags = tags.iter().cloned().collect::<Vec<_>>();
+                tags.sort();
+                line.push_str(&format!(", tags {}", tags.join(" ")));
+            }
+            if let Some(machine) = &proc.machine {
+                line.push_str(&format!(", container {}", machine));
+            }
+            out.push(line);
+        }
+        return out.join("\n");
+    }
+}
+
+/// Identifies executable contents for caching hashes: device, inode, and
+/// modification time (seconds, nanoseconds).
+pub type BinaryIdentity = (u64, u64, i64, i64);
+
+/// Hash an executable, streaming it in chunks. Results are cached by file identity
+/// so unchanged executables aren't rehashed for every request.
+async fn hash_binary(
+    binary_hashes: &Mutex<HashMap<BinaryIdentity, String>>,
+    path: &Path,
+) -> Result<String, std::io::Error> {
+    fn identity(meta: &std::fs::Metadata) -> BinaryIdentity {
+        return (meta.dev(), meta.ino(), meta.mtime(), meta.mtime_nsec());
+    }
+
+    let mut file = File::open(path).await?;
+    let before = identity(&file.metadata().await?);
+    if let Some(hash) = binary_hashes.lock().unwrap().get(&before) {
+        return Ok(hash.clone());
+    }
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let count = file.read(&mut buf).await?;
+        if count == 0 {
+            break;
+        }
+        hasher.update(&buf[..count]);
+    }
+    let hash = hasher.finalize().iter().map(|x| format!("{:02x}", x)).collect::<String>();
+
+    // Don't cache if modified while reading
+    if identity(&file.metadata().await?) == before {
+        binary_hashes.lock().unwrap().insert(before, hash.clone());
+    }
+    return Ok(hash);
+}
+
+/// `hash_walk_ancestors` is how far up the chain to hash executables (none if
+/// `None`), see `RuleTreeRoot::binary_sha256_walk_ancestors`.
 pub async fn scan_principal(
     log: &Log,
     tags: &Arc<Mutex<HashMap<Inode, HashSet<String>>>>,
+    binary_hashes: &Arc<Mutex<HashMap<BinaryIdentity, String>>>,
+    hash_walk_ancestors: Option<usize>,
     pid: i32,
 ) -> Result<PrincipalMeta, loga::Error> {
     struct AsyncPrincipalMetaProc {
@@ -215,8 +448,15 @@ pub async fn scan_principal(
         uid: Option<u32>,
         gid: Option<u32>,
         binary: JoinHandle<Option<PathBuf>>,
+        binary_sha256: JoinHandle<Option<String>>,
         first_arg_path: JoinHandle<Option<PathBuf>>,
-        tags: Pin<Box<dyn 'static + Sync + Send + Future<Output = Option<HashSet<String>>>>>,
+        tags: Pin<Box<dyn 'static + Sync + Send + Future<Output = (Option<Inode>, Option<HashSet<String>>)>>>,
+        cgroup: Option<String>,
+        mnt_ns: Option<u64>,
+        pid_ns: Option<u64>,
+        user_ns: Option<u64>,
+        machine: Option<String>,
+        container_manager: Option<String>,
     }
 
     let mut chain0 = vec![];
@@ -288,6 +528,74 @@ pub async fn scan_principal(
             }
         }
 
+        // Find cgroup, preferring the unified (v2) hierarchy then the systemd v1
+        // hierarchy
+        let mut cgroup = None;
+        match read(proc_path.join("cgroup")).await {
+            Ok(body) => {
+                for line in body.split(|x| *x == b'\n') {
+                    let mut splits = line.splitn(3, |x| *x == b':');
+                    let Some(((_, controllers), path)) = splits.next().zip(splits.next()).zip(splits.next()) else {
+                        continue;
+                    };
+                    let path = String::from_utf8_lossy(path).to_string();
+                    match controllers {
+                        b"" => {
+                            cgroup = Some(path);
+                            break;
+                        },
+                        b"name=systemd" => {
+                            cgroup = Some(path);
+                        },
+                        _ => { },
+                    }
+                }
+            },
+            Err(e) => {
+                log.log_err(loga::WARN, e.context("Unable to read proc cgroup"));
+            },
+        }
+
+        // Identify namespaces and container
+        let mut ns = HashMap::new();
+        for kind in ["mnt", "pid", "user"] {
+            let ns_path = proc_path.join("ns").join(kind);
+            match tokio::fs::metadata(&ns_path).await {
+                Ok(meta) => {
+                    ns.insert(kind, meta.ino());
+                },
+                Err(e) => {
+                    log.log_err(
+                        loga::WARN,
+                        e.context_with("Unable to read proc namespace", ea!(path = ns_path.dbg_str())),
+                    );
+                },
+            }
+        }
+        let machine = cgroup.as_ref().and_then(|cgroup| {
+            let mut segs = cgroup.split('/').skip(1);
+            if segs.next() != Some("machine.slice") {
+                return None;
+            }
+            let name = segs.next()?.strip_prefix("machine-")?.strip_suffix(".scope")?;
+            return Some(systemd_unescape(name));
+        });
+        let mut container_manager = None;
+        match read(proc_path.join("environ")).await {
+            Ok(body) => {
+                for entry in body.split(|x| *x == 0) {
+                    if let Some(value) = entry.strip_prefix(b"container=") {
+                        container_manager = Some(String::from_utf8_lossy(value).to_string());
+                        break;
+                    }
+                }
+            },
+            Err(e) => {
+                // Unreadable for processes of other users unless running as root
+                log.log_err(loga::DEBUG, e.context("Unable to read proc environ"));
+            },
+        }
+
         // Build meta chain entry
         /// Ensure that a path refers to an absolute path in the root filesystem (not a
         /// chroot'd different file).
@@ -356,6 +664,36 @@ pub async fn scan_principal(
                 }
             }
         });
+        let binary_sha256 = spawn({
+            let exe_path = proc_path.join("exe");
+            let binary_hashes = binary_hashes.clone();
+            let hash = hash_walk_ancestors.map(|x| chain0.len() <= x).unwrap_or(false);
+            let log = log.clone();
+            async move {
+                if !hash {
+                    return None;
+                }
+
+                // Read via the proc link rather than the resolved path so this works for deleted
+                // or namespaced executables
+                match hash_binary(&binary_hashes, &exe_path).await {
+                    Ok(hash) => Some(hash),
+                    Err(e) => {
+                        // Unreadable for processes of other users (ex: init) unless running as root
+                        let level = if e.kind() == ErrorKind::PermissionDenied {
+                            loga::DEBUG
+                        } else {
+                            loga::WARN
+                        };
+                        log.log_err(
+                            level,
+                            e.context_with("Unable to read proc exe for hashing", ea!(path = exe_path.dbg_str())),
+                        );
+                        None
+                    },
+                }
+            }
+        });
         let first_arg_path = spawn({
             let cmdline_path = proc_path.join("cmdline");
             let proc_path = proc_path.clone();
@@ -391,12 +729,13 @@ pub async fn scan_principal(
             Box::pin(async move {
                 match async {
                     let pidfd_inode = pidfd(at).await?.0;
-                    return Ok(tags.lock().unwrap().get(&pidfd_inode).cloned()) as Result<_, loga::Error>;
+                    let tags = tags.lock().unwrap().get(&pidfd_inode).cloned();
+                    return Ok((Some(pidfd_inode), tags)) as Result<_, loga::Error>;
                 }.await {
                     Ok(x) => x,
                     Err(e) => {
                         log.log_err(loga::WARN, e.context("Unable to read pidfd for process in chain"));
-                        None
+                        (None, None)
                     },
                 }
             })
@@ -406,8 +745,15 @@ pub async fn scan_principal(
             uid: uid,
             gid: gid,
             binary: binary,
+            binary_sha256: binary_sha256,
             first_arg_path: first_arg_path,
             tags: tags,
+            cgroup: cgroup,
+            mnt_ns: ns.get("mnt").cloned(),
+            pid_ns: ns.get("pid").cloned(),
+            user_ns: ns.get("user").cloned(),
+            machine: machine,
+            container_manager: container_manager,
         });
 
         // Ascend
@@ -419,13 +765,22 @@ pub async fn scan_principal(
     }
     let mut chain1 = vec![];
     for e in chain0 {
+        let (pidfd_inode, tags) = e.tags.await;
         let meta = PrincipalMetaProc {
             pid: e.pid,
             uid: e.uid,
             gid: e.gid,
             binary: e.binary.await?,
+            binary_sha256: e.binary_sha256.await?,
             first_arg_path: e.first_arg_path.await?,
-            tags: e.tags.await,
+            tags: tags,
+            pidfd_inode: pidfd_inode,
+            cgroup: e.cgroup,
+            mnt_ns: e.mnt_ns,
+            pid_ns: e.pid_ns,
+            user_ns: e.user_ns,
+            machine: e.machine,
+            container_manager: e.container_manager,
         };
         chain1.push(meta);
     }
@@ -437,8 +792,15 @@ pub async fn scan_principal(
             ("uid", x.uid.dbg_str()),
             ("gid", x.gid.dbg_str()),
             ("binary", x.binary.dbg_str()),
+            ("binary_sha256", x.binary_sha256.dbg_str()),
             ("first_arg_path", x.first_arg_path.dbg_str()),
             ("tags", x.tags.dbg_str()),
+            ("cgroup", x.cgroup.dbg_str()),
+            ("mnt_ns", x.mnt_ns.dbg_str()),
+            ("pid_ns", x.pid_ns.dbg_str()),
+            ("user_ns", x.user_ns.dbg_str()),
+            ("machine", x.machine.dbg_str()),
+            ("container_manager", x.container_manager.dbg_str()),
         ]
             .into_iter()
             .map(|(k, v)| format!("- {} [{}]", k, v))
@@ -448,6 +810,7 @@ pub async fn scan_principal(
     return Ok(PrincipalMeta { chain: chain1 });
 }
 
+#[derive(Serialize, Clone, Copy)]
 pub struct Perms {
     pub lock: bool,
     pub meta: bool,
@@ -456,24 +819,366 @@ pub struct Perms {
     pub write: bool,
 }
 
-pub async fn permit(
+impl Perms {
+    pub fn none() -> Self {
+        return Perms {
+            lock: false,
+            meta: false,
+            derive: false,
+            read: false,
+            write: false,
+        };
+    }
+}
+
+#[derive(Serialize)]
+pub struct ClauseProcTrace {
+    pub pid: i32,
+    pub matched: bool,
+}
+
+#[derive(Serialize)]
+pub struct ClauseTrace {
+    pub clause: &'static str,
+    /// Each process checked, starting with the requester and walking up through
+    /// ancestors until a match or `walk_ancestors` is exceeded.
+    pub procs: Vec<ClauseProcTrace>,
+    pub matched: bool,
+    /// The result couldn't be determined, like tags when explaining a live process
+    /// (only the running server knows them). `matched` assumes the clause failed.
+    pub unknown: bool,
+}
+
+#[derive(Serialize)]
+pub struct RuleTrace {
+    pub rule: usize,
+    pub permit: PermitLevel,
+    pub deny: bool,
+    /// The rule is attached to a path below the requested path (only deny rules are
+    /// considered there).
+    pub descendant: bool,
+    pub clauses: Vec<ClauseTrace>,
+    pub matched: bool,
+}
+
+#[derive(Serialize)]
+pub struct PathTrace {
+    pub path: SpecificPath,
+    pub rules: Vec<RuleTrace>,
+    /// The lowest level denied by matching deny rules, if any.
+    pub deny: Option<PermitLevel>,
+    pub perms: Perms,
+}
+
+/// Input for `--explain`: the requester to evaluate rules for and the paths to
+/// check.
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ExplainRequest {
+    pub principal: ExplainPrincipal,
+    pub paths: Vec<SpecificPath>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum ExplainPrincipal {
+    /// Scan a live process (and its ancestors) like a real request would.
+    Pid(i32),
+    /// A synthetic process chain, starting with the requester then its parent, etc.
+    Chain(Vec<PrincipalMetaProc>),
+}
+
+pub struct Evaluation {
+    /// Most restrictive permissions over all paths.
+    pub perms: Perms,
+    /// Rules that matched and require prompting before the permissions apply.
+    pub prompt_rules: HashMap<usize, (String, u64)>,
+    pub paths: Vec<PathTrace>,
+}
+
+fn match_clause(
+    principal: &PrincipalMeta,
+    clause: &'static str,
+    walk_ancestors: usize,
+    f: impl Fn(&PrincipalMetaProc) -> bool,
+) -> ClauseTrace {
+    let mut procs = vec![];
+    let mut matched = false;
+    for (depth, proc) in principal.chain.iter().enumerate() {
+        let proc_matched = f(proc);
+        procs.push(ClauseProcTrace {
+            pid: proc.pid,
+            matched: proc_matched,
+        });
+        if proc_matched {
+            matched = true;
+            break;
+        }
+        if depth >= walk_ancestors {
+            break;
+        }
+    }
+    return ClauseTrace {
+        clause: clause,
+        procs: procs,
+        matched: matched,
+        unknown: false,
+    };
+}
+
+fn rule_matches(log: &Log, rule: &Rule, principal: &PrincipalMeta) -> RuleTrace {
+    let mut clauses = vec![];
+    if let Some(match_binary) = &rule.match_binary {
+        let clause = match_clause(principal, "match_binary", match_binary.walk_ancestors, |proc| {
+            if let Some(match_path) = &match_binary.path {
+                if proc.binary.as_ref() != Some(match_path) {
+                    return false;
+                }
+                log.log(loga::DEBUG, format!("Permit: MATCHED binary path at [{}]", proc.pid));
+            }
+            if let Some(match_glob) = &match_binary.path_glob {
+                let options = glob::MatchOptions {
+                    require_literal_separator: true,
+                    ..glob::MatchOptions::new()
+                };
+                if !proc.binary.as_ref().map(|x| match_glob.matches_path_with(x, options)).unwrap_or(false) {
+                    return false;
+                }
+                log.log(loga::DEBUG, format!("Permit: MATCHED binary path glob [{}] at [{}]", match_glob, proc.pid));
+            }
+            if let Some(match_regex) = &match_binary.path_regex {
+                if !proc.binary.as_ref().map(|x| match_regex.is_match(&x.to_string_lossy())).unwrap_or(false) {
+                    return false;
+                }
+                log.log(
+                    loga::DEBUG,
+                    format!("Permit: MATCHED binary path regex [{}] at [{}]", match_regex, proc.pid),
+                );
+            }
+            if let Some(match_sha256) = &match_binary.sha256 {
+                if proc.binary_sha256.as_ref() != Some(match_sha256) {
+                    return false;
+                }
+                log.log(loga::DEBUG, format!("Permit: MATCHED binary sha256 at [{}]", proc.pid));
+            }
+            if let Some(match_first_arg) = &match_binary.first_arg_path {
+                if proc.first_arg_path.as_ref() != Some(match_first_arg) {
+                    return false;
+                }
+                log.log(loga::DEBUG, format!("Permit: MATCHED binary first arg at [{}]", proc.pid));
+            }
+            if let Some(match_first_arg_name) = &match_binary.first_arg_name {
+                if proc.first_arg_path.as_ref().and_then(|x| x.file_name()) != Some(OsStr::new(match_first_arg_name)) {
+                    return false;
+                }
+                log.log(loga::DEBUG, format!("Permit: MATCHED binary first arg name at [{}]", proc.pid));
+            }
+            return true;
+        });
+        log.log_with(
+            loga::DEBUG,
+            format!("Permit: Match binary result: {}", clause.matched),
+            ea!(match_ = serde_json::to_string_pretty(&match_binary.config).unwrap()),
+        );
+        clauses.push(clause);
+    }
+    if let Some(match_tag) = &rule.match_tag {
+        let clause = match_clause(principal, "match_tag", match_tag.walk_ancestors, |proc| {
+            let tags = proc.tags.as_ref();
+            if tags.map(|x| x.contains(&match_tag.tag)).unwrap_or(false) &&
+                proc.uid.as_ref() == Some(&match_tag.user_id) {
+                log.log(
+                    loga::DEBUG,
+                    format!(
+                        "Permit: MATCHED tag [{}] and user [{}] at [{}]",
+                        match_tag.tag,
+                        match_tag.user_id,
+                        proc.pid
+                    ),
+                );
+                return true;
+            }
+            return false;
+        });
+        log.log_with(
+            loga::DEBUG,
+            format!("Permit: Match tag result: {}", clause.matched),
+            ea!(match_ = serde_json::to_string_pretty(&match_tag).unwrap()),
+        );
+        clauses.push(clause);
+    }
+    if let Some(match_user) = &rule.match_user {
+        let clause = match_clause(principal, "match_user", match_user.walk_ancestors, |proc| {
+            if let Some(match_user_id) = &match_user.user_id {
+                if proc.uid.as_ref() != Some(match_user_id) {
+                    return false;
+                }
+                log.log(loga::DEBUG, format!("Permit: MATCHED UID at [{}]", proc.pid));
+            }
+            if let Some(match_group_id) = &match_user.group_id {
+                if proc.gid.as_ref() != Some(match_group_id) {
+                    return false;
+                }
+                log.log(loga::DEBUG, format!("Permit: MATCHED GID at [{}]", proc.pid));
+            }
+            return true;
+        });
+        log.log_with(
+            loga::DEBUG,
+            format!("Permit: Match user result: {}", clause.matched),
+            ea!(match_ = serde_json::to_string_pretty(&match_user).unwrap()),
+        );
+        clauses.push(clause);
+    }
+    if let Some(match_cgroup) = &rule.match_cgroup {
+        let clause = match_clause(principal, "match_cgroup", match_cgroup.walk_ancestors, |proc| {
+            let Some(cgroup) = &proc.cgroup else {
+                return false;
+            };
+            let want = match_cgroup.path.trim_end_matches('/');
+            if cgroup != want && !cgroup.strip_prefix(want).map(|x| x.starts_with('/')).unwrap_or(false) {
+                return false;
+            }
+            log.log(loga::DEBUG, format!("Permit: MATCHED cgroup [{}] at [{}]", cgroup, proc.pid));
+            return true;
+        });
+        log.log_with(
+            loga::DEBUG,
+            format!("Permit: Match cgroup result: {}", clause.matched),
+            ea!(match_ = serde_json::to_string_pretty(&match_cgroup).unwrap()),
+        );
+        clauses.push(clause);
+    }
+    if let Some(match_systemd_unit) = &rule.match_systemd_unit {
+        let clause = match_clause(principal, "match_systemd_unit", match_systemd_unit.walk_ancestors, |proc| {
+            let Some(cgroup) = &proc.cgroup else {
+                return false;
+            };
+
+            // Only system units: the first non-slice segment, so users can't create a
+            // matching unit or cgroup somewhere below their own service manager
+            let mut segs = cgroup.split('/').filter(|seg| !seg.is_empty());
+            if segs.clone().any(|seg| seg.starts_with("user@") && seg.ends_with(".service")) {
+                return false;
+            }
+            if segs.find(|seg| !seg.ends_with(".slice")) != Some(match_systemd_unit.unit.as_str()) {
+                return false;
+            }
+            log.log(
+                loga::DEBUG,
+                format!("Permit: MATCHED systemd unit [{}] at [{}]", match_systemd_unit.unit, proc.pid),
+            );
+            return true;
+        });
+        log.log_with(
+            loga::DEBUG,
+            format!("Permit: Match systemd unit result: {}", clause.matched),
+            ea!(match_ = serde_json::to_string_pretty(&match_systemd_unit).unwrap()),
+        );
+        clauses.push(clause);
+    }
+    if let Some(match_namespace) = &rule.match_namespace {
+        let clause = match_clause(principal, "match_namespace", match_namespace.walk_ancestors, |proc| {
+            if let Some(match_machine) = &match_namespace.machine {
+                if proc.machine.as_ref() != Some(match_machine) {
+                    return false;
+                }
+                log.log(loga::DEBUG, format!("Permit: MATCHED machine [{}] at [{}]", match_machine, proc.pid));
+            }
+            if let Some(match_manager) = &match_namespace.container_manager {
+                if proc.container_manager.as_ref() != Some(match_manager) {
+                    return false;
+                }
+                log.log(
+                    loga::DEBUG,
+                    format!("Permit: MATCHED container manager [{}] at [{}]", match_manager, proc.pid),
+                );
+            }
+            for (kind, want, have) in [
+                ("mnt", &match_namespace.mnt, &proc.mnt_ns),
+                ("pid", &match_namespace.pid, &proc.pid_ns),
+                ("user", &match_namespace.user, &proc.user_ns),
+            ] {
+                if let Some(want) = want {
+                    if have.as_ref() != Some(want) {
+                        return false;
+                    }
+                    log.log(loga::DEBUG, format!("Permit: MATCHED {} namespace [{}] at [{}]", kind, want, proc.pid));
+                }
+            }
+            return true;
+        });
+        log.log_with(
+            loga::DEBUG,
+            format!("Permit: Match namespace result: {}", clause.matched),
+            ea!(match_ = serde_json::to_string_pretty(&match_namespace).unwrap()),
+        );
+        clauses.push(clause);
+    }
+    let matched = clauses.iter().all(|x| x.matched);
+    log.log(loga::DEBUG, format!("Permit: Rule result: {}", matched));
+    return RuleTrace {
+        rule: rule.id,
+        permit: rule.permit,
+        deny: rule.deny,
+        descendant: false,
+        clauses: clauses,
+        matched: matched,
+    };
+}
+
+/// Apply matching deny rules anywhere below `tree`. Requests on a path also expose
+/// everything beneath it, so a denied descendant restricts the whole request.
+fn descendant_deny(
+    log: &Log,
+    tree: &RuleTree,
+    principal: &PrincipalMeta,
+    audit: &mut AuditDecision,
+    trace: &mut Vec<RuleTrace>,
+    deny: &mut Option<PermitLevel>,
+) {
+    for child in tree.wildcard.iter().map(|x| x.as_ref()).chain(tree.children.values()) {
+        for rule in &child.rules {
+            if !rule.deny {
+                continue;
+            }
+            let mut rule_trace = rule_matches(log, rule, principal);
+            rule_trace.descendant = true;
+            let matched = rule_trace.matched;
+            trace.push(rule_trace);
+            if !matched {
+                continue;
+            }
+            audit.rules.insert(rule.id);
+            log.log(
+                loga::DEBUG,
+                format!("Permit: Descendant rule [{}] denies [{:?}] and above", rule.id, rule.permit),
+            );
+            if deny.map(|x| (rule.permit as usize) < (x as usize)).unwrap_or(true) {
+                *deny = Some(rule.permit);
+            }
+        }
+        descendant_deny(log, child, principal, audit, trace, deny);
+    }
+}
+
+/// Determine permissions for the paths without prompting.
+pub fn evaluate(
     log: &Log,
-    fg_tx: tokio::sync::mpsc::Sender<B2F>,
     rules: &RuleTree,
     principal: &PrincipalMeta,
     paths: &[SpecificPath],
-) -> Result<Perms, loga::Error> {
+    audit: &mut AuditDecision,
+) -> Evaluation {
+    audit.decided = true;
+    audit.paths.extend(paths.iter().cloned());
     let mut total_lock = true;
     let mut total_meta = true;
     let mut total_derive = true;
     let mut total_read = true;
     let mut total_write = true;
-
-    struct TotalPrompt {
-        rules: HashMap<usize, (String, u64)>,
-    }
-
-    let mut total_prompt = None;
+    let mut total_prompt = HashMap::new();
+    let mut path_traces = vec![];
     for path in paths {
         log.log(loga::DEBUG, format!("Permit: Testing permissions for path {:?}", path.0));
         let mut new_tails = vec![];
@@ -483,112 +1188,30 @@ pub async fn permit(
         let mut path_derive = false;
         let mut path_read = false;
         let mut path_write = false;
+        let mut path_deny: Option<PermitLevel> = None;
+        let mut rule_traces = vec![];
         let mut segs = path.0.iter();
         loop {
             let seg = segs.next();
             for tail in tails.drain(..) {
                 for rule in &tail.rules {
-                    let mut rule_result = true;
-                    if let Some(match_binary) = &rule.match_binary {
-                        let match_result = shed!{
-                            'submatch _;
-                            for (depth, proc) in principal.chain.iter().enumerate() {
-                                shed!{
-                                    'fail _;
-                                    if proc.binary.as_ref() != Some(&match_binary.path) {
-                                        break 'fail;
-                                    }
-                                    log.log(loga::DEBUG, format!("Permit: MATCHED binary at [{}]", proc.pid));
-                                    if let Some(match_first_arg) = &match_binary.first_arg_path {
-                                        if proc.first_arg_path.as_ref() != Some(match_first_arg) {
-                                            break 'fail;
-                                        }
-                                        log.log(
-                                            loga::DEBUG,
-                                            format!("Permit: MATCHED binary first arg at [{}]", proc.pid),
-                                        );
-                                    }
-                                    break 'submatch true;
-                                }
-                                if depth >= match_binary.walk_ancestors {
-                                    break 'submatch false;
-                                }
-                            }
-                            break 'submatch false;
-                        };
-                        log.log_with(
-                            loga::DEBUG,
-                            format!("Permit: Match binary result: {}", match_result),
-                            ea!(match_ = serde_json::to_string_pretty(&match_binary).unwrap()),
-                        );
-                        rule_result = rule_result && match_result;
-                    }
-                    if let Some(match_tag) = &rule.match_tag {
-                        let match_result = shed!{
-                            'submatch _;
-                            for (depth, proc) in principal.chain.iter().enumerate() {
-                                let tags = proc.tags.as_ref();
-                                if tags.map(|x| x.contains(&match_tag.tag)).unwrap_or(false) &&
-                                    proc.uid.as_ref() == Some(&match_tag.user_id) {
-                                    log.log(
-                                        loga::DEBUG,
-                                        format!(
-                                            "Permit: MATCHED tag [{}] and user [{}] at [{}]",
-                                            match_tag.tag,
-                                            match_tag.user_id,
-                                            proc.pid
-                                        ),
-                                    );
-                                    break 'submatch true;
-                                }
-                                if depth >= match_tag.walk_ancestors {
-                                    break 'submatch false;
-                                }
-                            }
-                            break 'submatch false;
-                        };
-                        log.log_with(
-                            loga::DEBUG,
-                            format!("Permit: Match tag result: {}", match_result),
-                            ea!(match_ = serde_json::to_string_pretty(&match_tag).unwrap()),
-                        );
-                        rule_result = rule_result && match_result;
+                    let rule_trace = rule_matches(log, rule, principal);
+                    let matched = rule_trace.matched;
+                    rule_traces.push(rule_trace);
+                    if !matched {
+                        continue;
                     }
-                    if let Some(match_user) = &rule.match_user {
-                        let match_result = shed!{
-                            'submatch _;
-                            for (depth, proc) in principal.chain.iter().enumerate() {
-                                shed!{
-                                    if let Some(match_user_id) = &match_user.user_id {
-                                        if proc.uid.as_ref() != Some(match_user_id) {
-                                            break;
-                                        }
-                                        log.log(loga::DEBUG, format!("Permit: MATCHED UID at [{}]", proc.pid));
-                                    }
-                                    if let Some(match_group_id) = &match_user.group_id {
-                                        if proc.gid.as_ref() != Some(match_group_id) {
-                                            break;
-                                        }
-                                        log.log(loga::DEBUG, format!("Permit: MATCHED GID at [{}]", proc.pid));
-                                    }
-                                    break 'submatch true;
-                                }
-                                if depth >= match_user.walk_ancestors {
-                                    rule_result = false;
-                                    break;
-                                }
-                            }
-                            break 'submatch false;
-                        };
-                        log.log_with(
+                    audit.rules.insert(rule.id);
+
+                    // Denies override grants from any rule
+                    if rule.deny {
+                        log.log(
                             loga::DEBUG,
-                            format!("Permit: Match user result: {}", match_result),
-                            ea!(match_ = serde_json::to_string_pretty(&match_user).unwrap()),
+                            format!("Permit: Rule [{}] denies [{:?}] and above", rule.id, rule.permit),
                         );
-                        rule_result = rule_result && match_result;
-                    }
-                    log.log(loga::DEBUG, format!("Permit: Rule result: {}", rule_result));
-                    if !rule_result {
+                        if path_deny.map(|x| (rule.permit as usize) < (x as usize)).unwrap_or(true) {
+                            path_deny = Some(rule.permit);
+                        }
                         continue;
                     }
 
@@ -599,10 +1222,7 @@ pub async fn permit(
                     path_meta = path_meta || rule.permit as usize >= PermitLevel::Meta as usize;
                     path_lock = path_lock || rule.permit as usize >= PermitLevel::Lock as usize;
                     if let Some(rule_prompt) = &rule.prompt {
-                        let prompt = total_prompt.get_or_insert_with(|| TotalPrompt { rules: HashMap::new() });
-                        prompt
-                            .rules
-                            .insert(rule.id, (rule_prompt.description.clone(), rule_prompt.remember_seconds));
+                        total_prompt.insert(rule.id, (rule_prompt.description.clone(), rule_prompt.remember_seconds));
                     }
                 }
 
@@ -614,6 +1234,8 @@ pub async fn permit(
                     if let Some(child) = tail.children.get(seg) {
                         new_tails.push(child);
                     }
+                } else {
+                    descendant_deny(log, tail, principal, audit, &mut rule_traces, &mut path_deny);
                 }
             }
             swap(&mut tails, &mut new_tails);
@@ -621,6 +1243,26 @@ pub async fn permit(
                 break;
             }
         }
+        if let Some(deny) = path_deny {
+            log.log(loga::DEBUG, format!("Permit: Denying [{:?}] and above for path {:?}", deny, path.0));
+            path_write = path_write && (deny as usize) > PermitLevel::Write as usize;
+            path_read = path_read && (deny as usize) > PermitLevel::Read as usize;
+            path_derive = path_derive && (deny as usize) > PermitLevel::Derive as usize;
+            path_meta = path_meta && (deny as usize) > PermitLevel::Meta as usize;
+            path_lock = path_lock && (deny as usize) > PermitLevel::Lock as usize;
+        }
+        path_traces.push(PathTrace {
+            path: path.clone(),
+            rules: rule_traces,
+            deny: path_deny,
+            perms: Perms {
+                lock: path_lock,
+                meta: path_meta,
+                derive: path_derive,
+                read: path_read,
+                write: path_write,
+            },
+        });
 
         // Overall permissions are most restrictive of permissions for any path
         total_lock = total_lock && path_lock;
@@ -629,28 +1271,110 @@ pub async fn permit(
         total_read = total_read && path_read;
         total_write = total_write && path_write;
     }
-    if let Some(prompt) = total_prompt {
-        let (resp_tx, resp_rx) = oneshot::channel();
-        fg_tx.send(B2F::Prompt(B2FPrompt { prompt_rules: prompt.rules.clone() }, resp_tx)).await?;
-        match resp_rx.await.unwrap() {
-            Ok(Some(b)) => {
-                if !b {
-                    return Err(loga::err("User rejected access request"));
-                }
+    return Evaluation {
+        perms: Perms {
+            lock: total_lock,
+            meta: total_meta,
+            derive: total_derive,
+            read: total_read,
+            write: total_write,
+        },
+        prompt_rules: total_prompt,
+        paths: path_traces,
+    };
+}
+
+/// Whether the principal already has `meta` on the path, without prompting or
+/// recording a decision. Rules that require a prompt only count if approved earlier.
+pub fn has_meta(log: &Log, state: &State, rules: &RuleTree, principal: &PrincipalMeta, path: &SpecificPath) -> bool {
+    let evaluation = evaluate(log, rules, principal, &[path.clone()], &mut AuditDecision::default());
+    if !evaluation.perms.meta {
+        return false;
+    }
+    let mut grants = state.grants.lock().unwrap();
+    return evaluation.prompt_rules.keys().all(|rule_id| grants.get(principal, *rule_id) == Some(true));
+}
+
+/// Evaluate the rules, prompting the user if a matching rule requires it. If the
+/// user rejects the prompt (or rejected it earlier and that's remembered) no
+/// permissions are granted and `audit.prompt` is `Some(false)`.
+pub async fn permit(
+    log: &Log,
+    state: &State,
+    variant: &str,
+    rules: &RuleTree,
+    principal: &PrincipalMeta,
+    paths: &[SpecificPath],
+    audit: &mut AuditDecision,
+) -> Result<Perms, loga::Error> {
+    let evaluation = evaluate(log, rules, principal, paths, audit);
+
+    // Skip rules with decisions remembered from earlier prompts
+    let mut prompt_rules = HashMap::new();
+    for (rule_id, prompt) in evaluation.prompt_rules {
+        match state.grants.lock().unwrap().get(principal, rule_id) {
+            Some(true) => {
+                log.log(loga::DEBUG, format!("Permit: Rule [{}] already approved for requester", rule_id));
             },
-            Ok(None) => {
-                return Err(loga::err("User rejected access request"));
+            Some(false) => {
+                log.log(loga::DEBUG, format!("Permit: Rule [{}] temporarily denied for requester", rule_id));
+                audit.prompt = Some(false);
+                return Ok(Perms::none());
             },
+            None => {
+                prompt_rules.insert(rule_id, prompt);
+            },
+        }
+    }
+    if !prompt_rules.is_empty() {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        state.fg_tx.send(B2F::Prompt(B2FPrompt {
+            prompt_rules: prompt_rules.clone(),
+            variant: variant.to_string(),
+            paths: paths.to_vec(),
+            principal: principal.summary(),
+            chain: principal.chain.iter().filter_map(|x| x.pidfd_inode).collect(),
+        }, resp_tx)).await?;
+        let decision = match resp_rx.await.unwrap() {
+            Ok(Some(d)) => d,
+            Ok(None) => PromptDecision::Deny,
             Err(e) => {
                 return Err(e);
             },
+        };
+        let requester_pidfd = match (&decision, principal.chain.first()) {
+            (PromptDecision::AllowProcess, Some(requester)) => match pidfd(requester.pid).await {
+                // Make sure the pid wasn't reused since the scan
+                Ok((inode, fd)) if Some(inode) == requester.pidfd_inode => Some(fd.into_inner()),
+                Ok(_) => None,
+                Err(e) => {
+                    log.log_err(loga::WARN, e.context("Unable to open pidfd to remember approval for process"));
+                    None
+                },
+            },
+            _ => None,
+        };
+        {
+            let mut grants = state.grants.lock().unwrap();
+            for (rule_id, (_, remember_seconds)) in &prompt_rules {
+                grants.insert(
+                    principal,
+                    *rule_id,
+                    &decision,
+                    Duration::from_secs(*remember_seconds),
+                    requester_pidfd.as_ref().and_then(|x| x.try_clone().ok()),
+                );
+            }
+        }
+        match decision {
+            PromptDecision::AllowOnce | PromptDecision::AllowFor(_) | PromptDecision::AllowProcess => {
+                audit.prompt = Some(true);
+            },
+            PromptDecision::Deny | PromptDecision::DenyFor(_) => {
+                audit.prompt = Some(false);
+                return Ok(Perms::none());
+            },
         }
     }
-    return Ok(Perms {
-        lock: total_lock,
-        meta: total_meta,
-        derive: total_derive,
-        read: total_read,
-        write: total_write,
-    });
+    return Ok(evaluation.perms);
 }
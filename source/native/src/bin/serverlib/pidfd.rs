@@ -13,7 +13,7 @@ use {
     },
 };
 
-#[derive(Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+#[derive(Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
 pub struct Inode(pub u64);
 
 pub async fn pidfd(pid: i32) -> Result<(Inode, AsyncFd<OwnedFd>), loga::Error> {
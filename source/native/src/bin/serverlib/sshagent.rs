@@ -0,0 +1,441 @@
+//! A minimal `ssh-agent` protocol implementation (listing identities and signing)
+//! backed by keys stored in the database.
+use {
+    super::{
+        audit::{
+            self,
+            AuditDecision,
+            AuditResult,
+        },
+        dbutil::{
+            get,
+            tx,
+        },
+        permission::{
+            self,
+            scan_principal,
+            Perms,
+            PrincipalMeta,
+            RuleTree,
+        },
+        pidfd::Inode,
+        state::{
+            get_privdb,
+            State,
+        },
+    },
+    loga::{
+        ea,
+        ErrContext,
+        Log,
+        ResultContext,
+    },
+    passworth::datapath::SpecificPath,
+    passworth_native::config::latest::ConfigSshAgent,
+    signature::{
+        SignatureEncoding,
+        Signer,
+    },
+    std::{
+        collections::{
+            HashMap,
+            HashSet,
+        },
+        fs::Permissions,
+        io::ErrorKind,
+        os::unix::fs::PermissionsExt,
+        str::FromStr,
+        sync::{
+            Arc,
+            Mutex,
+        },
+    },
+    taskmanager::TaskManager,
+    tokio::{
+        fs,
+        io::{
+            AsyncReadExt,
+            AsyncWriteExt,
+        },
+        net::{
+            UnixListener,
+            UnixStream,
+        },
+        select,
+        spawn,
+        sync::Notify,
+    },
+};
+
+const SSH_AGENT_FAILURE: u8 = 5;
+const SSH_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+const SSH_AGENT_IDENTITIES_ANSWER: u8 = 12;
+const SSH_AGENTC_SIGN_REQUEST: u8 = 13;
+const SSH_AGENT_SIGN_RESPONSE: u8 = 14;
+const SSH_AGENT_RSA_SHA2_256: u32 = 2;
+const SSH_AGENT_RSA_SHA2_512: u32 = 4;
+
+/// Agent messages are tiny; refuse anything unreasonable rather than allocating it.
+const MAX_MESSAGE_LEN: usize = 256 * 1024;
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], loga::Error> {
+        if self.0.len() < len {
+            return Err(loga::err("Ssh agent message truncated"));
+        }
+        let (out, rest) = self.0.split_at(len);
+        self.0 = rest;
+        return Ok(out);
+    }
+
+    fn u8(&mut self) -> Result<u8, loga::Error> {
+        return Ok(self.take(1)?[0]);
+    }
+
+    fn u32(&mut self) -> Result<u32, loga::Error> {
+        return Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()));
+    }
+
+    fn string(&mut self) -> Result<&'a [u8], loga::Error> {
+        let len = self.u32()? as usize;
+        return Ok(self.take(len)?);
+    }
+}
+
+fn write_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend(value.to_be_bytes());
+}
+
+fn write_string(out: &mut Vec<u8>, value: &[u8]) {
+    write_u32(out, value.len() as u32);
+    out.extend(value);
+}
+
+struct AgentKeys {
+    paths: Vec<SpecificPath>,
+    /// Public key blobs of keys loaded so far, so listing doesn't unlock again and
+    /// signing requests can find their key without unlocking or reading keys the
+    /// caller can't use.
+    public: Mutex<HashMap<SpecificPath, Vec<u8>>>,
+}
+
+async fn get_key(log: &Log, state: &State, path: &SpecificPath) -> Result<ssh_key::PrivateKey, loga::Error> {
+    let privdbc = get_privdb(log, state).await?.context("Unlocking was cancelled or failed")?;
+    let db_key = tx(privdbc, {
+        let path = path.clone();
+        move |txn| {
+            return Ok(get(txn, &path, None)?);
+        }
+    }).await?;
+    let serde_json::Value::String(key) = db_key else {
+        return Err(loga::err("No value at path or value is not a string"));
+    };
+    return Ok(
+        ssh_key::PrivateKey::from_openssh(&key).context("Error reading data at path as openssh private key PEM")?,
+    );
+}
+
+/// Loads the key and remembers its public key. A key that fails to load is logged
+/// and skipped rather than failing the whole request.
+async fn load_key(log: &Log, state: &State, keys: &AgentKeys, path: &SpecificPath) -> Option<ssh_key::PrivateKey> {
+    let res = async {
+        let key = get_key(log, state, path).await?;
+        let public = key.public_key().to_bytes().context("Error encoding ssh public key")?;
+        keys.public.lock().unwrap().insert(path.clone(), public);
+        return Ok(key) as Result<_, loga::Error>;
+    }.await;
+    match res {
+        Ok(key) => return Some(key),
+        Err(e) => {
+            log.log_err(loga::WARN, e.context_with("Error loading ssh agent key", ea!(path = path.to_string())));
+            return None;
+        },
+    }
+}
+
+/// Checks the caller's permissions on the key. An error (ex: the prompt couldn't be
+/// shown) is logged and treated as no permissions so the other keys still work.
+async fn key_perms(
+    log: &Log,
+    state: &State,
+    variant: &str,
+    rules: &RuleTree,
+    principal: &PrincipalMeta,
+    path: &SpecificPath,
+    audit: &mut AuditDecision,
+) -> Option<Perms> {
+    match permission::permit(log, state, variant, rules, principal, &[path.clone()], audit).await {
+        Ok(perms) => return Some(perms),
+        Err(e) => {
+            log.log_err(
+                loga::WARN,
+                e.context_with("Error checking permissions for ssh agent key", ea!(path = path.to_string())),
+            );
+            return None;
+        },
+    }
+}
+
+/// Returns the signature algorithm name and signature. For rsa keys the flags pick
+/// the hash, with neither set being the legacy sha1 `ssh-rsa`.
+fn sign(key: &ssh_key::PrivateKey, data: &[u8], flags: u32) -> Result<(String, Vec<u8>), loga::Error> {
+    let ssh_key::private::KeypairData::Rsa(keypair) = key.key_data() else {
+        let signature = key.try_sign(data).map_err(|e| loga::err(e.to_string())).context("Error signing data")?;
+        return Ok((signature.algorithm().as_str().to_string(), signature.as_bytes().to_vec()));
+    };
+    let private = rsa::RsaPrivateKey::try_from(keypair).context("Error reading rsa key")?;
+    let (algorithm, signature) = if flags & SSH_AGENT_RSA_SHA2_512 != 0 {
+        ("rsa-sha2-512", rsa::pkcs1v15::SigningKey::<sha2::Sha512>::new(private).try_sign(data).map(|s| s.to_vec()))
+    } else if flags & SSH_AGENT_RSA_SHA2_256 != 0 {
+        ("rsa-sha2-256", rsa::pkcs1v15::SigningKey::<sha2::Sha256>::new(private).try_sign(data).map(|s| s.to_vec()))
+    } else {
+        ("ssh-rsa", rsa::pkcs1v15::SigningKey::<sha1::Sha1>::new(private).try_sign(data).map(|s| s.to_vec()))
+    };
+    let signature = signature.map_err(|e| loga::err(e.to_string())).context("Error signing data")?;
+    return Ok((algorithm.to_string(), signature));
+}
+
+async fn handle_message(
+    log: &Log,
+    state: &State,
+    activity: &Notify,
+    principal: &PrincipalMeta,
+    keys: &AgentKeys,
+    variant: &str,
+    audit: &mut AuditDecision,
+    message: &[u8],
+) -> Result<Vec<u8>, loga::Error> {
+    let rules = state.rules.lock().unwrap().clone();
+    let mut message = Reader(message);
+    match message.u8()? {
+        SSH_AGENTC_REQUEST_IDENTITIES => {
+            let mut identities = vec![];
+            for path in &keys.paths {
+                let Some(perms) = key_perms(log, state, variant, &rules.tree, principal, path, audit).await else {
+                    continue;
+                };
+                if !perms.meta {
+                    continue;
+                }
+
+                // Only load (and so maybe unlock for) keys not seen yet
+                let public = keys.public.lock().unwrap().get(path).cloned();
+                let public = match public {
+                    Some(public) => public,
+                    None => {
+                        if load_key(log, state, keys, path).await.is_none() {
+                            continue;
+                        }
+                        keys.public.lock().unwrap().get(path).cloned().unwrap()
+                    },
+                };
+                identities.push((public, path.to_string()));
+            }
+            let mut out = vec![SSH_AGENT_IDENTITIES_ANSWER];
+            write_u32(&mut out, identities.len() as u32);
+            for (blob, comment) in identities {
+                write_string(&mut out, &blob);
+                write_string(&mut out, comment.as_bytes());
+            }
+            activity.notify_one();
+            return Ok(out);
+        },
+        SSH_AGENTC_SIGN_REQUEST => {
+            let key_blob = message.string()?;
+            let data = message.string()?;
+            let flags = message.u32()?;
+            for path in &keys.paths {
+                // Find the key by public key before checking `derive` so callers can't
+                // trigger an unlock or prompt for keys they can't use. Keys not loaded yet
+                // need `meta`, same as listing them.
+                let public = keys.public.lock().unwrap().get(path).cloned();
+                let public = match public {
+                    Some(public) => public,
+                    None => {
+                        let Some(perms) =
+                            key_perms(log, state, variant, &rules.tree, principal, path, audit).await else {
+                                continue;
+                            };
+                        if !perms.meta {
+                            continue;
+                        }
+                        if load_key(log, state, keys, path).await.is_none() {
+                            continue;
+                        }
+                        keys.public.lock().unwrap().get(path).cloned().unwrap()
+                    },
+                };
+                if public != key_blob {
+                    continue;
+                }
+                let Some(perms) = key_perms(log, state, variant, &rules.tree, principal, path, audit).await else {
+                    return Ok(vec![SSH_AGENT_FAILURE]);
+                };
+                if !perms.derive {
+                    return Ok(vec![SSH_AGENT_FAILURE]);
+                }
+                let Some(key) = load_key(log, state, keys, path).await else {
+                    return Ok(vec![SSH_AGENT_FAILURE]);
+                };
+                if key.public_key().to_bytes().context("Error encoding ssh public key")? != key_blob {
+                    // Changed since the public key was remembered
+                    return Ok(vec![SSH_AGENT_FAILURE]);
+                }
+                let (algorithm, signature) = sign(&key, data, flags)?;
+                let mut signature_blob = vec![];
+                write_string(&mut signature_blob, algorithm.as_bytes());
+                write_string(&mut signature_blob, &signature);
+                let mut out = vec![SSH_AGENT_SIGN_RESPONSE];
+                write_string(&mut out, &signature_blob);
+                activity.notify_one();
+                return Ok(out);
+            }
+            return Ok(vec![SSH_AGENT_FAILURE]);
+        },
+        _ => {
+            return Ok(vec![SSH_AGENT_FAILURE]);
+        },
+    }
+}
+
+async fn handle_conn(
+    log: &Log,
+    state: &State,
+    tags: &Arc<Mutex<HashMap<Inode, HashSet<String>>>>,
+    activity: &Notify,
+    keys: &AgentKeys,
+    mut conn: UnixStream,
+) -> Result<(), loga::Error> {
+    let peer = conn.peer_cred()?;
+    let pid = peer.pid().context("OS didn't provide PID for peer")?;
+    let hash_walk_ancestors = state.rules.lock().unwrap().binary_sha256_walk_ancestors;
+    let principal = scan_principal(log, tags, &state.binary_hashes, hash_walk_ancestors, pid).await?;
+    loop {
+        let mut len = [0u8; 4];
+        match conn.read_exact(&mut len).await {
+            Ok(_) => { },
+            Err(e) => {
+                if e.kind() == ErrorKind::UnexpectedEof {
+                    return Ok(());
+                }
+                return Err(e.context("Error reading ssh agent message length"));
+            },
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_MESSAGE_LEN {
+            return Err(loga::err_with("Ssh agent message too large", ea!(len = len)));
+        }
+        let mut message = vec![];
+        message.resize(len, 0);
+        conn.read_exact(&mut message).await.context("Error reading ssh agent message body")?;
+        let variant = match message.first() {
+            Some(&SSH_AGENTC_REQUEST_IDENTITIES) => "ssh_agent_identities",
+            Some(&SSH_AGENTC_SIGN_REQUEST) => "ssh_agent_sign",
+            _ => "ssh_agent_other",
+        };
+        let mut audit = AuditDecision::default();
+        let res = handle_message(log, state, activity, &principal, keys, variant, &mut audit, &message).await;
+        let rejected = audit.prompt == Some(false);
+        match audit::record(state, variant, &principal, audit, match &res {
+            Ok(r) if r.first() == Some(&SSH_AGENT_FAILURE) && rejected => AuditResult::Rejected,
+            Ok(r) if r.first() == Some(&SSH_AGENT_FAILURE) => AuditResult::Unauthorized,
+            Ok(_) => AuditResult::Ok,
+            Err(_) => AuditResult::Error,
+        }).await {
+            Ok(_) => { },
+            Err(e) => {
+                log.log_err(loga::WARN, e.context("Error writing audit log entry"));
+            },
+        }
+        let resp = match res {
+            Ok(r) => r,
+            Err(e) => {
+                log.log_err(loga::WARN, e.context("Error processing ssh agent request"));
+                vec![SSH_AGENT_FAILURE]
+            },
+        };
+        let mut out = vec![];
+        write_string(&mut out, &resp);
+        conn.write_all(&out).await.context("Error writing ssh agent response")?;
+    }
+}
+
+pub async fn start(
+    tm: &TaskManager,
+    log: &Log,
+    state: Arc<State>,
+    tags: Arc<Mutex<HashMap<Inode, HashSet<String>>>>,
+    activity: Arc<Notify>,
+    config: &ConfigSshAgent,
+) -> Result<(), loga::Error> {
+    let log = log.fork(ea!(sys = "ssh_agent"));
+    let mut paths = vec![];
+    for key in &config.keys {
+        paths.push(
+            SpecificPath::from_str(
+                key,
+            ).map_err(loga::err).context_with("Invalid ssh agent key path in config", ea!(path = key))?,
+        );
+    }
+    let keys = Arc::new(AgentKeys {
+        paths: paths,
+        public: Mutex::new(HashMap::new()),
+    });
+    match fs::remove_file(&config.socket_path).await {
+        Ok(_) => { },
+        Err(e) => {
+            if e.kind() != ErrorKind::NotFound {
+                return Err(
+                    e.context_with(
+                        "Error removing old ssh agent socket",
+                        ea!(path = config.socket_path.to_string_lossy()),
+                    ),
+                );
+            }
+        },
+    }
+    let listener =
+        UnixListener::bind(
+            &config.socket_path,
+        ).context_with("Error binding ssh agent socket", ea!(path = config.socket_path.to_string_lossy()))?;
+    fs::set_permissions(&config.socket_path, Permissions::from_mode(0o777))
+        .await
+        .context("Error setting mode on ssh agent socket")?;
+    tm.critical_task("SSH agent", {
+        let tm = tm.clone();
+        async move {
+            loop {
+                let conn = select!{
+                    c = listener.accept() => c,
+                    _ = tm.until_terminate() => {
+                        break;
+                    }
+                };
+                let (conn, _) = match conn {
+                    Ok(c) => c,
+                    Err(e) => {
+                        log.log_err(loga::DEBUG, e.context("Error receiving ssh agent connection"));
+                        continue;
+                    },
+                };
+                let log = log.clone();
+                let state = state.clone();
+                let tags = tags.clone();
+                let activity = activity.clone();
+                let keys = keys.clone();
+                spawn(async move {
+                    match handle_conn(&log, &state, &tags, &activity, &keys, conn).await {
+                        Ok(_) => { },
+                        Err(e) => {
+                            log.log_err(loga::WARN, e.context("Error handling ssh agent connection"));
+                        },
+                    }
+                });
+            }
+            return Ok(()) as Result<_, loga::Error>;
+        }
+    });
+    return Ok(());
+}
@@ -0,0 +1,166 @@
+use {
+    super::{
+        dbutil::open_privdb,
+        factor::FactorTree,
+        permission::{
+            BinaryIdentity,
+            RuleTreeRoot,
+        },
+        fg::{
+            B2FUnlock,
+            B2F,
+        },
+        fgipc::UiDelegate,
+        grants::Grants,
+        pubdb,
+        watch::Watch,
+    },
+    loga::{
+        conversion::ResultIgnore,
+        Log,
+        ResultContext,
+    },
+    std::{
+        collections::HashMap,
+        path::PathBuf,
+        sync::{
+            Arc,
+            Mutex,
+        },
+    },
+    tokio::{
+        sync::{
+            broadcast,
+            oneshot,
+        },
+        time::Instant,
+    },
+};
+
+pub struct TokenState {
+    pub token: Option<String>,
+    pub wait_sub: Option<broadcast::Sender<String>>,
+}
+
+pub struct State {
+    pub pubdb_path: PathBuf,
+    pub privdb_path: PathBuf,
+    pub auditdb_path: PathBuf,
+    /// Replaced when the config is reloaded.
+    pub root_factor: Mutex<Arc<FactorTree>>,
+    /// Replaced when the config is reloaded. Requests should take a snapshot and use it
+    /// for the whole request.
+    pub rules: Mutex<RuleTreeRoot>,
+    pub token_state: Mutex<TokenState>,
+    /// Prompt decisions to remember.
+    pub grants: Mutex<Grants>,
+    /// Client registered over IPC to handle interactions instead of the frontend.
+    pub ui_delegate: Arc<UiDelegate>,
+    pub fg_tx: tokio::sync::mpsc::Sender<B2F>,
+    pub lock_timeout: u64,
+    /// When the inactivity timeout will lock, if it's running.
+    pub lock_at: Mutex<Option<Instant>>,
+    /// Clients watching for changes.
+    pub watch: Watch,
+    /// Executable hashes for principal scans.
+    pub binary_hashes: Arc<Mutex<HashMap<BinaryIdentity, String>>>,
+}
+
+/// Forget the database key, notifying watchers if it was unlocked.
+pub fn lock(state: &State) {
+    if state.token_state.lock().unwrap().token.take().is_some() {
+        state.watch.locked();
+    }
+}
+
+/// Get the database, unlocking it if necessary. `None` if unlocking was cancelled
+/// or failed (failures are logged).
+pub async fn get_privdb(log: &Log, state: &State) -> Result<Option<rusqlite::Connection>, loga::Error> {
+    enum Invert {
+        Ready {
+            token: String,
+        },
+        Waiting {
+            token_rx: broadcast::Receiver<String>,
+        },
+        Missing {
+            token_tx: broadcast::Sender<String>,
+        },
+    }
+
+    let privdbc = match {
+        let mut token_state = state.token_state.lock().unwrap();
+        match &token_state.token {
+            Some(t) => Invert::Ready { token: t.clone() },
+            None => {
+                match &token_state.wait_sub {
+                    Some(s) => {
+                        let token_rx = s.subscribe();
+                        Invert::Waiting { token_rx: token_rx }
+                    },
+                    None => {
+                        let (token_tx, _) = broadcast::channel(1);
+                        token_state.wait_sub = Some(token_tx.clone());
+                        Invert::Missing { token_tx: token_tx }
+                    },
+                }
+            },
+        }
+    } {
+        Invert::Ready { token } => {
+            open_privdb(&state.privdb_path, &token)?
+        },
+        Invert::Waiting { mut token_rx } => {
+            let Ok(token) = token_rx.recv().await else {
+                // The unlock this was waiting on was cancelled or failed
+                return Ok(None);
+            };
+            open_privdb(&state.privdb_path, &token)?
+        },
+        Invert::Missing { token_tx } => {
+            // Unlock
+            let mut pubdbc = rusqlite::Connection::open(&state.pubdb_path).unwrap();
+            let mut factor_state = HashMap::new();
+            for t in pubdb::factor_list(&mut pubdbc)? {
+                factor_state.insert(t.id, t.state);
+            }
+            let (fg_result_tx, fg_result_rx) = oneshot::channel();
+            state.fg_tx.send(B2F::Unlock(B2FUnlock {
+                pubdb_path: state.pubdb_path.clone(),
+                privdb_path: state.privdb_path.clone(),
+                root_factor: state.root_factor.lock().unwrap().clone(),
+                state: factor_state,
+            }, fg_result_tx)).await.ignore();
+            let res = fg_result_rx.await;
+
+            // Handle result
+            let mut token = state.token_state.lock().unwrap();
+            token.wait_sub = None;
+            let res = match res?.context("Error during fg unlock") {
+                Ok(Some(res)) => res,
+                Ok(None) => {
+                    // User closed unlock window
+                    return Ok(None);
+                },
+                Err(e) => {
+                    log.log_err(loga::WARN, e);
+                    return Ok(None);
+                },
+            };
+            token.token = Some(res.root_token.clone());
+            token_tx.send(res.root_token.clone()).ignore();
+            state.watch.unlocked();
+            res.privdbc
+        },
+    };
+    return Ok(Some(privdbc));
+}
+
+/// Get the database only if it's already unlocked, without starting or waiting for
+/// an unlock.
+pub fn try_get_privdb(state: &State) -> Result<Option<rusqlite::Connection>, loga::Error> {
+    let Some(token) = state.token_state.lock().unwrap().token.clone() else {
+        return Ok(None);
+    };
+    return Ok(Some(open_privdb(&state.privdb_path, &token)?));
+}
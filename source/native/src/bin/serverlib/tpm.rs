@@ -0,0 +1,258 @@
+//! Sealing factor tokens with a TPM2, bound to PCR values. All functions are
+//! blocking.
+use {
+    loga::{
+        ea,
+        ResultContext,
+    },
+    passworth_native::config::latest::ConfigCredTpm,
+    serde::{
+        Deserialize,
+        Serialize,
+    },
+    std::str::FromStr,
+    tss_esapi::{
+        attributes::ObjectAttributesBuilder,
+        constants::{
+            SessionType,
+            Tss2ResponseCodeKind,
+        },
+        handles::{
+            KeyHandle,
+            SessionHandle,
+        },
+        interface_types::{
+            algorithm::{
+                HashingAlgorithm,
+                PublicAlgorithm,
+            },
+            key_bits::RsaKeyBits,
+            resource_handles::Hierarchy,
+            session_handles::{
+                AuthSession,
+                PolicySession,
+            },
+        },
+        structures::{
+            Digest,
+            KeyedHashScheme,
+            PcrSelectionList,
+            PcrSelectionListBuilder,
+            PcrSlot,
+            Private,
+            Public,
+            PublicBuilder,
+            PublicKeyedHashParameters,
+            RsaExponent,
+            SensitiveData,
+            SymmetricDefinition,
+            SymmetricDefinitionObject,
+        },
+        traits::{
+            Marshall,
+            UnMarshall,
+        },
+        Context,
+        TctiNameConf,
+    },
+};
+
+pub const DEFAULT_TCTI: &str = "device:/dev/tpmrm0";
+pub const DEFAULT_PCRS: &[u8] = &[7];
+
+/// Stored in the factor state.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct TpmSealed {
+    /// Marshalled `TPM2B_PUBLIC` of the sealed object
+    pub public: Vec<u8>,
+    /// `TPM2B_PRIVATE` of the sealed object, encrypted by the TPM's storage key
+    pub private: Vec<u8>,
+}
+
+fn tpm_context(config: &ConfigCredTpm) -> Result<Context, loga::Error> {
+    let tcti = config.tcti.as_ref().map(|x| x.as_str()).unwrap_or(DEFAULT_TCTI);
+    let tcti_conf =
+        TctiNameConf::from_str(tcti)
+            .map_err(|e| loga::err(e.to_string()))
+            .context_with("Invalid TPM TCTI", ea!(tcti = tcti))?;
+    return Ok(
+        Context::new(tcti_conf)
+            .map_err(|e| loga::err(e.to_string()))
+            .context_with("Error connecting to TPM", ea!(tcti = tcti))?,
+    );
+}
+
+fn pcr_selection(config: &ConfigCredTpm) -> Result<PcrSelectionList, loga::Error> {
+    let mut slots = vec![];
+    for pcr in config.pcrs.as_ref().map(|x| x.as_slice()).unwrap_or(DEFAULT_PCRS) {
+        let Some(bit) = 1u32.checked_shl(*pcr as u32) else {
+            return Err(loga::err_with("PCR index out of range", ea!(pcr = pcr)));
+        };
+        slots.push(
+            PcrSlot::try_from(bit)
+                .map_err(|e| loga::err(e.to_string()))
+                .context_with("Invalid PCR index", ea!(pcr = pcr))?,
+        );
+    }
+    return Ok(
+        PcrSelectionListBuilder::new()
+            .with_selection(HashingAlgorithm::Sha256, &slots)
+            .build()
+            .map_err(|e| loga::err(e.to_string()))
+            .context("Error building PCR selection")?,
+    );
+}
+
+/// The storage primary key is derived from the owner hierarchy seed, so recreating
+/// it with the same template gives the same key.
+fn primary(context: &mut Context) -> Result<KeyHandle, loga::Error> {
+    let template =
+        tss_esapi::utils::create_restricted_decryption_rsa_public(
+            SymmetricDefinitionObject::AES_128_CFB,
+            RsaKeyBits::Rsa2048,
+            RsaExponent::default(),
+        ).map_err(|e| loga::err(e.to_string()))?;
+    return Ok(
+        context
+            .execute_with_nullauth_session(|ctx| ctx.create_primary(Hierarchy::Owner, template, None, None, None, None))
+            .map_err(|e| loga::err(e.to_string()))
+            .context("Error creating TPM storage primary key")?
+            .key_handle,
+    );
+}
+
+/// Start a policy session restricted to the current PCR values. Trial sessions are
+/// used to calculate the policy digest when sealing.
+fn pcr_policy_session(
+    context: &mut Context,
+    pcrs: PcrSelectionList,
+    session_type: SessionType,
+) -> Result<PolicySession, loga::Error> {
+    let session =
+        context
+            .start_auth_session(
+                None,
+                None,
+                None,
+                session_type,
+                SymmetricDefinition::AES_128_CFB,
+                HashingAlgorithm::Sha256,
+            )
+            .map_err(|e| loga::err(e.to_string()))
+            .context("Error starting TPM policy session")?
+            .context("TPM didn't return a session")?;
+    let session = PolicySession::try_from(session).map_err(|e| loga::err(e.to_string()))?;
+    context
+        .policy_pcr(session, Digest::default(), pcrs)
+        .map_err(|e| loga::err(e.to_string()))
+        .context("Error applying PCR policy")?;
+    return Ok(session);
+}
+
+fn flush_session(context: &mut Context, session: PolicySession) -> Result<(), loga::Error> {
+    context
+        .flush_context(SessionHandle::from(AuthSession::from(session)).into())
+        .map_err(|e| loga::err(e.to_string()))
+        .context("Error closing TPM session")?;
+    return Ok(());
+}
+
+pub fn seal(config: &ConfigCredTpm, token: &[u8]) -> Result<TpmSealed, loga::Error> {
+    let mut context = tpm_context(config)?;
+    let pcrs = pcr_selection(config)?;
+    let trial = pcr_policy_session(&mut context, pcrs, SessionType::Trial)?;
+    let policy_digest =
+        context.policy_get_digest(trial).map_err(|e| loga::err(e.to_string())).context("Error getting policy digest")?;
+    flush_session(&mut context, trial)?;
+    let primary = primary(&mut context)?;
+    let public =
+        PublicBuilder::new()
+            .with_public_algorithm(PublicAlgorithm::KeyedHash)
+            .with_name_hashing_algorithm(HashingAlgorithm::Sha256)
+            .with_object_attributes(
+                ObjectAttributesBuilder::new()
+                    .with_fixed_tpm(true)
+                    .with_fixed_parent(true)
+                    .with_no_da(true)
+                    .with_admin_with_policy(true)
+                    .with_user_with_auth(false)
+                    .build()
+                    .map_err(|e| loga::err(e.to_string()))?,
+            )
+            .with_auth_policy(policy_digest)
+            .with_keyed_hash_parameters(PublicKeyedHashParameters::new(KeyedHashScheme::Null))
+            .with_keyed_hash_unique_identifier(Digest::default())
+            .build()
+            .map_err(|e| loga::err(e.to_string()))
+            .context("Error building sealed object template")?;
+    let sensitive =
+        SensitiveData::try_from(token.to_vec())
+            .map_err(|e| loga::err(e.to_string()))
+            .context("Token too large to seal")?;
+    let created =
+        context
+            .execute_with_nullauth_session(|ctx| ctx.create(primary, public, None, Some(sensitive), None, None))
+            .map_err(|e| loga::err(e.to_string()))
+            .context("Error sealing token")?;
+    context.flush_context(primary.into()).map_err(|e| loga::err(e.to_string()))?;
+    return Ok(TpmSealed {
+        public: created.out_public.marshall().map_err(|e| loga::err(e.to_string()))?,
+        private: created.out_private.value().to_vec(),
+    });
+}
+
+/// Returns `None` if the TPM refused to unseal because the policy failed, i.e. the
+/// PCR values changed.
+pub fn unseal(config: &ConfigCredTpm, sealed: &TpmSealed) -> Result<Option<Vec<u8>>, loga::Error> {
+    let mut context = tpm_context(config)?;
+    let pcrs = pcr_selection(config)?;
+    let primary = primary(&mut context)?;
+    let public =
+        Public::unmarshall(&sealed.public)
+            .map_err(|e| loga::err(e.to_string()))
+            .context("Error parsing stored sealed object public area")?;
+    let private =
+        Private::try_from(sealed.private.clone())
+            .map_err(|e| loga::err(e.to_string()))
+            .context("Error parsing stored sealed object private area")?;
+    let object =
+        context
+            .execute_with_nullauth_session(|ctx| ctx.load(primary, private, public))
+            .map_err(|e| loga::err(e.to_string()))
+            .context("Error loading sealed object into TPM")?;
+    let policy = pcr_policy_session(&mut context, pcrs, SessionType::Policy)?;
+    let unsealed = context.execute_with_session(Some(policy.into()), |ctx| ctx.unseal(object.into()));
+    flush_session(&mut context, policy)?;
+    context.flush_context(object.into()).map_err(|e| loga::err(e.to_string()))?;
+    context.flush_context(primary.into()).map_err(|e| loga::err(e.to_string()))?;
+    match unsealed {
+        Ok(data) => return Ok(Some(data.value().to_vec())),
+        Err(tss_esapi::Error::Tss2Error(rc)) if matches!(
+            rc.kind(),
+            Some(Tss2ResponseCodeKind::PolicyFail) | Some(Tss2ResponseCodeKind::PcrChanged)
+        ) => {
+            return Ok(None);
+        },
+        Err(e) => return Err(loga::err(e.to_string()).context("Error unsealing token")),
+    }
+}
+
+#[test]
+#[ignore]
+fn test_seal_unseal() {
+    // Needs a TPM, ex: the simulator started with `swtpm socket --tpm2 --server
+    // type=tcp,port=2321 --ctrl type=tcp,port=2322 --flags startup-clear --tpmstate
+    // dir=/tmp/swtpm`. Run with `cargo test -- --ignored test_seal_unseal`, setting
+    // `PASSWORTH_TEST_TPM_TCTI` to use a different TPM.
+    let tcti =
+        std::env::var("PASSWORTH_TEST_TPM_TCTI").unwrap_or_else(|_| "swtpm:host=127.0.0.1,port=2321".to_string());
+    let config = ConfigCredTpm {
+        tcti: Some(tcti),
+        pcrs: None,
+    };
+    let token = "hello".as_bytes();
+    let sealed = seal(&config, token).unwrap();
+    let unsealed = unseal(&config, &sealed).expect("Must not have TPM errors").expect("Must unseal");
+    assert_eq!(token, unsealed.as_slice());
+}
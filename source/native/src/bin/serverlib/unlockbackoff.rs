@@ -0,0 +1,55 @@
+//! Delays between failed unlock attempts, persisted so restarting the server
+//! doesn't reset them. All functions are blocking.
+use {
+    super::pubdb,
+    chrono::Utc,
+    loga::ResultContext,
+    std::{
+        path::Path,
+        time::Duration,
+    },
+};
+
+/// Failed attempts allowed before delays start.
+pub const FREE_FAILURES: i32 = 3;
+pub const MAX_DELAY: Duration = Duration::from_secs(15 * 60);
+
+/// The delay after `failures` consecutive failed attempts, doubling each time.
+pub fn failure_delay(failures: i32) -> Duration {
+    if failures < FREE_FAILURES {
+        return Duration::ZERO;
+    }
+    return Duration::from_secs(1 << (failures - FREE_FAILURES).min(16)).min(MAX_DELAY);
+}
+
+/// How much longer to wait before the next attempt is allowed.
+pub fn remaining_delay(pubdb_path: &Path) -> Result<Duration, loga::Error> {
+    let mut pubdbc = rusqlite::Connection::open(pubdb_path).context("Error opening pub database")?;
+    let Some(failures) =
+        pubdb::unlock_failures_get(&mut pubdbc).context("Error reading failed unlock attempts")? else {
+            return Ok(Duration::ZERO);
+        };
+    let elapsed = (Utc::now() - failures.last).to_std().unwrap_or_default();
+    return Ok(failure_delay(failures.count).saturating_sub(elapsed));
+}
+
+pub fn record_failure(pubdb_path: &Path) -> Result<(), loga::Error> {
+    let mut pubdbc = rusqlite::Connection::open(pubdb_path).context("Error opening pub database")?;
+    let count =
+        pubdb::unlock_failures_get(&mut pubdbc)
+            .context("Error reading failed unlock attempts")?
+            .map(|x| x.count)
+            .unwrap_or(0);
+    pubdb::unlock_failures_set(
+        &mut pubdbc,
+        count.saturating_add(1),
+        Utc::now(),
+    ).context("Error recording failed unlock attempt")?;
+    return Ok(());
+}
+
+pub fn clear_failures(pubdb_path: &Path) -> Result<(), loga::Error> {
+    let mut pubdbc = rusqlite::Connection::open(pubdb_path).context("Error opening pub database")?;
+    pubdb::unlock_failures_clear(&mut pubdbc).context("Error clearing failed unlock attempts")?;
+    return Ok(());
+}
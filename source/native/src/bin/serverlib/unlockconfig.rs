@@ -0,0 +1,324 @@
+//! Applying the unlock (credential) portion of the config: compare with the
+//! previously applied config and re-initialize factors whose tokens or state
+//! changed.
+use {
+    super::{
+        dbutil::tx,
+        factor::{
+            build_factor_tree,
+            FactorTree,
+            FactorTreeVariant,
+        },
+        fg::{
+            B2FInitialize,
+            B2FUnlock,
+            B2F,
+        },
+        pubdb,
+        state::State,
+    },
+    libc::c_void,
+    loga::{
+        conversion::ResultIgnore,
+        ea,
+        ResultContext,
+    },
+    passworth_native::config::{
+        self,
+        latest::UnlockFactorsConfig,
+    },
+    std::{
+        collections::{
+            HashMap,
+            HashSet,
+        },
+        sync::Arc,
+    },
+    tokio::sync::oneshot,
+};
+
+/// Re-initializes credentials if they differ from what was last stored. Returns the
+/// new database key if it changed.
+pub async fn apply_unlock_config(
+    state: &State,
+    root_factor: &Arc<FactorTree>,
+    unlock_config: UnlockFactorsConfig,
+) -> Result<Option<String>, loga::Error> {
+    let mut pubdbc = rusqlite::Connection::open(&state.pubdb_path).context("Error opening pub database")?;
+    let mut prev_state = HashMap::new();
+    for t in pubdb::factor_list(&mut pubdbc)? {
+        prev_state.insert(t.id, t.state);
+    }
+    if let Some(previous_config) =
+        pubdb::config_get(&mut pubdbc).context("Error reading previous config for config migrations")? {
+        let previous_unlock_config = match previous_config {
+            config::UnlockConfig::V1(config) => config,
+        };
+
+        // Previous config existed
+        let prev_root_factor =
+            build_factor_tree(
+                &HashSet::new(),
+                &previous_unlock_config.auth_factors.iter().map(|f| (f.id.clone(), f)).collect(),
+                &mut HashMap::new(),
+                &previous_unlock_config.root_factor,
+            )?;
+
+        // Diff with old to check what tokens/state changed, get list of still-active
+        // factors
+        let factor_active: HashSet<String>;
+        let mut factor_token_changed = HashSet::new();
+        let mut factor_state_changed = HashSet::new();
+        {
+            let mut stack = vec![(root_factor, Some(&prev_root_factor), true)];
+            let mut seen = HashSet::new();
+            while let Some((new, old, descending)) = stack.pop() {
+                if !seen.insert(new.id.clone()) {
+                    continue;
+                }
+                let old_variant = old.map(|x| &x.variant);
+                match &new.variant {
+                    FactorTreeVariant::And(children) => {
+                        if descending {
+                            stack.push((new, old, false));
+                            let mut old_lookup = HashMap::new();
+                            if let Some(FactorTreeVariant::Or(old_children)) = old_variant {
+                                for old_child in old_children {
+                                    old_lookup.insert(old_child.id.clone(), old_child);
+                                }
+                            }
+                            for child in children {
+                                stack.push((child, old_lookup.get(&child.id).map(|x| *x), true));
+                            }
+                        } else {
+                            let mut changed = false;
+                            for child in children {
+                                if factor_token_changed.contains(&child.id) {
+                                    changed = true;
+                                }
+                            }
+                            if changed {
+                                factor_token_changed.insert(new.id.clone());
+                            }
+                        }
+                    },
+                    FactorTreeVariant::Or(children) => {
+                        if descending {
+                            stack.push((new, old, false));
+                            let mut old_lookup = HashMap::new();
+                            if let Some(FactorTreeVariant::Or(old_children)) = old_variant {
+                                for old_child in old_children {
+                                    old_lookup.insert(old_child.id.clone(), old_child);
+                                }
+                            }
+                            for child in children {
+                                stack.push((child, old_lookup.get(&child.id).map(|x| *x), true));
+                            }
+                        } else {
+                            if matches!(old_variant, Some(FactorTreeVariant::Or(_))) {
+                                // nop
+                            } else {
+                                factor_token_changed.insert(new.id.clone());
+                            }
+                            for child in children {
+                                if factor_token_changed.contains(&child.id) {
+                                    factor_state_changed.insert(new.id.clone());
+                                    break;
+                                }
+                            }
+                        }
+                    },
+                    FactorTreeVariant::Threshold(k, children) => {
+                        if descending {
+                            stack.push((new, old, false));
+                            let mut old_lookup = HashMap::new();
+                            if let Some(FactorTreeVariant::Threshold(_, old_children)) = old_variant {
+                                for old_child in old_children {
+                                    old_lookup.insert(old_child.id.clone(), old_child);
+                                }
+                            }
+                            for child in children {
+                                stack.push((child, old_lookup.get(&child.id).map(|x| *x), true));
+                            }
+                        } else {
+                            if let Some(FactorTreeVariant::Threshold(old_k, old_children)) = old_variant {
+                                // Same token, reshared
+                                if old_k != k ||
+                                    old_children.iter().map(|x| &x.id).collect::<HashSet<_>>() !=
+                                        children.iter().map(|x| &x.id).collect::<HashSet<_>>() {
+                                    factor_state_changed.insert(new.id.clone());
+                                }
+                            } else {
+                                factor_token_changed.insert(new.id.clone());
+                            }
+                            for child in children {
+                                if factor_token_changed.contains(&child.id) {
+                                    factor_state_changed.insert(new.id.clone());
+                                    break;
+                                }
+                            }
+                        }
+                    },
+                    FactorTreeVariant::Password => {
+                        if matches!(old_variant, Some(FactorTreeVariant::Password)) {
+                            // nop
+                        } else {
+                            factor_token_changed.insert(new.id.clone());
+                        }
+                    },
+                    FactorTreeVariant::Smartcards(config) => {
+                        let mut lookup_old_children = HashSet::new();
+                        if let Some(FactorTreeVariant::Smartcards(old_config)) = old_variant {
+                            for child in &old_config.smartcards {
+                                lookup_old_children.insert(child.fingerprint.clone());
+                            }
+                        } else {
+                            factor_token_changed.insert(new.id.clone());
+                        }
+                        for child in &config.smartcards {
+                            if !lookup_old_children.contains(&child.fingerprint) {
+                                factor_state_changed.insert(new.id.clone());
+                                break;
+                            }
+                        }
+                    },
+                    FactorTreeVariant::RecoveryPhrase => {
+                        if matches!(old_variant, Some(FactorTreeVariant::RecoveryPhrase)) {
+                            // nop
+                        } else {
+                            factor_token_changed.insert(new.id.clone());
+                        }
+                    },
+                    FactorTreeVariant::Tpm(config) => {
+                        if let Some(FactorTreeVariant::Tpm(old_config)) = old_variant {
+                            // Same token, resealed
+                            if config != old_config {
+                                factor_state_changed.insert(new.id.clone());
+                            }
+                        } else {
+                            factor_token_changed.insert(new.id.clone());
+                        }
+                    },
+                    FactorTreeVariant::Fido2(config) => {
+                        // Any change needs a new credential, which has a different secret
+                        if matches!(old_variant, Some(FactorTreeVariant::Fido2(old_config)) if old_config == config) {
+                            // nop
+                        } else {
+                            factor_token_changed.insert(new.id.clone());
+                        }
+                    },
+                    FactorTreeVariant::Keyfile(config) => {
+                        if matches!(old_variant, Some(FactorTreeVariant::Keyfile(old_config)) if old_config == config) {
+                            // nop
+                        } else {
+                            factor_token_changed.insert(new.id.clone());
+                        }
+                    },
+                }
+            }
+            factor_active = seen;
+        }
+
+        // Skip if nothing changed
+        let root_token_changed = factor_token_changed.contains(&root_factor.id);
+        if factor_state_changed.is_empty() && !root_token_changed {
+            return Ok(None);
+        }
+        let mut remove_state = prev_state.keys().cloned().collect::<Vec<_>>();
+        remove_state.retain(|x| !factor_active.contains(x));
+
+        // Otherwise first unlock
+        let unlock_result;
+        {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            state.fg_tx.send(B2F::Unlock(B2FUnlock {
+                pubdb_path: state.pubdb_path.clone(),
+                privdb_path: state.privdb_path.clone(),
+                root_factor: prev_root_factor.clone(),
+                state: prev_state.clone(),
+            }, resp_tx)).await.ignore();
+            unlock_result =
+                resp_rx
+                    .await?
+                    .context("Error doing fg unlock")?
+                    .context("Config update unlock aborted by user")?;
+        }
+
+        // Continue with init
+        let (resp_tx, resp_rx) = oneshot::channel();
+        state.fg_tx.send(B2F::Initialize(B2FInitialize {
+            privdbc: None,
+            root_factor: root_factor.clone(),
+            tokens_changed: factor_token_changed,
+            prev_tokens: unlock_result.tokens,
+            state_changed: factor_state_changed,
+            prev_state: prev_state,
+            prev_root_factor: Some(prev_root_factor),
+        }, resp_tx)).await.ignore();
+        let init_result =
+            resp_rx
+                .await?
+                .context("Error doing fg initialize")?
+                .context("Credential initialization aborted by user")?;
+
+        // Store the new config and tokens
+        return Ok(tx(pubdbc, move |txn| {
+            for k in &remove_state {
+                if !pubdb::factor_delete(txn, k)
+                    .context_with("Error removing obsolete factor data", ea!(factor = k))?
+                    .is_some() {
+                    panic!();
+                }
+            }
+            for (k, v) in &init_result.store_state {
+                pubdb::factor_add(txn, &k, &v).context_with("Error storing new factor data", ea!(factor = k))?;
+            }
+            pubdb::config_set(txn, &config::UnlockConfig::V1(unlock_config))?;
+            if root_token_changed {
+                let Some(root_token) = init_result.root_token else {
+                    panic!();
+                };
+                let root_token_bytes = root_token.as_bytes();
+                let res = unsafe {
+                    libsqlite3_sys::sqlite3_rekey(
+                        unlock_result.privdbc.handle(),
+                        root_token_bytes.as_ptr() as *const c_void,
+                        root_token_bytes.len() as i32,
+                    )
+                };
+                if res != 0 {
+                    return Err(loga::err_with("Sqlcipher rekey operation exited with code", ea!(code = res)));
+                }
+                return Ok(Some(root_token));
+            }
+            return Ok(None);
+        }).await.context("Error committing new unlock credentials")?);
+    }
+    else {
+        let all_factors = unlock_config.auth_factors.iter().map(|x| x.id.clone()).collect::<HashSet<_>>();
+        let (resp_tx, resp_rx) = oneshot::channel();
+        state.fg_tx.send(B2F::Initialize(B2FInitialize {
+            privdbc: None,
+            root_factor: root_factor.clone(),
+            tokens_changed: all_factors.clone(),
+            prev_tokens: HashMap::new(),
+            state_changed: all_factors.clone(),
+            prev_state: HashMap::new(),
+            prev_root_factor: None,
+        }, resp_tx)).await.ignore();
+        let init_result =
+            resp_rx
+                .await?
+                .context("Error doing fg initialize")?
+                .context("Credential initialization aborted by user")?;
+
+        // Store the new config and tokens
+        return Ok(tx(pubdbc, move |txn| {
+            for (k, v) in &init_result.store_state {
+                pubdb::factor_add(txn, &k, &v).context_with("Error storing new factor data", ea!(factor = k))?;
+            }
+            pubdb::config_set(txn, &config::UnlockConfig::V1(unlock_config))?;
+            return Ok(None);
+        }).await.context("Error committing new unlock credentials")?);
+    }
+}
@@ -0,0 +1,90 @@
+//! Notifying clients watching over IPC of value changes and lock state changes.
+use {
+    loga::conversion::ResultIgnore,
+    passworth::{
+        datapath::SpecificPath,
+        ipc,
+    },
+    tokio::sync::broadcast::{
+        self,
+        error::RecvError,
+    },
+};
+
+/// Events buffered per watcher before it's considered to have fallen behind.
+const BUFFER: usize = 256;
+
+#[derive(Clone)]
+enum Event {
+    Changed(Vec<SpecificPath>),
+    Locked,
+    Unlocked,
+}
+
+pub struct Watch {
+    tx: broadcast::Sender<Event>,
+}
+
+impl Watch {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(BUFFER);
+        return Self { tx: tx };
+    }
+
+    /// Paths whose values were written. Call after the transaction commits.
+    pub fn changed(&self, paths: Vec<SpecificPath>) {
+        // Errors if nobody is watching
+        self.tx.send(Event::Changed(paths)).ignore();
+    }
+
+    pub fn locked(&self) {
+        self.tx.send(Event::Locked).ignore();
+    }
+
+    pub fn unlocked(&self) {
+        self.tx.send(Event::Unlocked).ignore();
+    }
+
+    pub fn subscribe(&self, paths: Vec<SpecificPath>) -> Watcher {
+        return Watcher {
+            paths: paths,
+            rx: self.tx.subscribe(),
+        };
+    }
+}
+
+pub struct Watcher {
+    paths: Vec<SpecificPath>,
+    rx: broadcast::Receiver<Event>,
+}
+
+impl Watcher {
+    /// Wait for the next event relevant to the watched paths.
+    pub async fn next(&mut self) -> ipc::WatchEvent {
+        loop {
+            match self.rx.recv().await {
+                Ok(Event::Changed(paths)) => {
+                    // A write replaces everything below the path, and clears values above it
+                    let paths =
+                        paths
+                            .into_iter()
+                            .filter(
+                                |p| self.paths.iter().any(|w| p.0.starts_with(&w.0) || w.0.starts_with(&p.0)),
+                            )
+                            .collect::<Vec<_>>();
+                    if paths.is_empty() {
+                        continue;
+                    }
+                    return ipc::WatchEvent::Changed(paths);
+                },
+                Ok(Event::Locked) => return ipc::WatchEvent::Locked,
+                Ok(Event::Unlocked) => return ipc::WatchEvent::Unlocked,
+                Err(RecvError::Lagged(_)) => return ipc::WatchEvent::Missed,
+                Err(RecvError::Closed) => {
+                    // The sender lives in the state, which outlives connections
+                    unreachable!();
+                },
+            }
+        }
+    }
+}
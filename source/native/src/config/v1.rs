@@ -28,14 +28,66 @@ pub struct ConfigCredSmartcards {
     pub smartcards: Vec<ConfigCredSmartcard>,
 }
 
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ConfigCredTpm {
+    /// How to connect to the TPM, in TCTI format. Defaults to `device:/dev/tpmrm0`.
+    /// For the `swtpm` simulator use something like `swtpm:host=127.0.0.1,port=2321`.
+    #[serde(default)]
+    pub tcti: Option<String>,
+    /// PCRs (SHA-256 bank) that must have the same values as when the token was
+    /// sealed. Defaults to `[7]` (secure boot state).
+    #[serde(default)]
+    pub pcrs: Option<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ConfigCredFido2 {
+    /// Relying party id the credential is created under. Defaults to `passworth`.
+    #[serde(default)]
+    pub rp_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ConfigCredKeyfile {
+    /// The file to read. Relative paths are resolved in the systemd credentials
+    /// directory (`$CREDENTIALS_DIRECTORY`, for `LoadCredential=` and
+    /// `LoadCredentialEncrypted=`) if set.
+    pub path: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ConfigAuthFactorThreshold {
+    /// How many children are required to unlock.
+    pub k: usize,
+    /// At most 255.
+    pub children: Vec<String>,
+}
+
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, JsonSchema)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub enum ConfigAuthFactorVariant {
     And(Vec<String>),
     Or(Vec<String>),
+    /// Any `k` of the children. Setting up or changing this requires all the children.
+    Threshold(ConfigAuthFactorThreshold),
     Password,
     Smartcards(ConfigCredSmartcards),
     RecoveryCode,
+    /// A token sealed by this machine's TPM. Unlocks without user interaction as long
+    /// as the PCRs are unchanged, so combine it with another factor using `and` (ex: a
+    /// password as a PIN).
+    Tpm(ConfigCredTpm),
+    /// A token derived from a FIDO2 security key's hmac-secret extension, confirmed by
+    /// touching the key. The key PIN isn't used, so combine it with another factor
+    /// using `and` if the key could be stolen.
+    Fido2(ConfigCredFido2),
+    /// A token derived from the contents of a file. If the file is missing, unlocking
+    /// waits for it to appear (ex: a drive to be inserted).
+    Keyfile(ConfigCredKeyfile),
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, JsonSchema)]
@@ -57,6 +109,9 @@ pub struct ConfigAuthMethod {
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub struct ConfigPrompt {
     pub description: String,
+    /// When the user picks "allow once", don't prompt the same requester (same
+    /// binaries, users, etc. in the process chain) for this rule again for this many
+    /// seconds. The prompt also offers longer-lived choices.
     #[serde(default)]
     pub remember_seconds: u64,
 }
@@ -102,6 +157,9 @@ pub struct MatchUser {
 /// Match a single process running a specific binary. Note that this will match
 /// interpreters for scripts, not the script being run (even if the script is run
 /// directly via shebang).
+///
+/// At least one of `path`, `path_glob`, `path_regex`, or `sha256` must be
+/// specified. All specified fields must match.
 #[derive(Serialize, Deserialize, Clone, JsonSchema, Debug)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub struct MatchBinary {
@@ -110,10 +168,88 @@ pub struct MatchBinary {
     #[serde(default)]
     pub walk_ancestors: usize,
     /// The absolute path of an executable to match.
-    pub path: PathBuf,
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    /// A glob pattern (ex: `/nix/store/*-bash-*/bin/bash`) matching the absolute path
+    /// of the executable. Wildcards don't match `/`.
+    #[serde(default)]
+    pub path_glob: Option<String>,
+    /// A regex matching the whole absolute path of the executable.
+    #[serde(default)]
+    pub path_regex: Option<String>,
+    /// Hex SHA-256 of the executable's contents (as read via `/proc/<pid>/exe`).
+    /// This matches regardless of where the executable is installed.
+    #[serde(default)]
+    pub sha256: Option<String>,
     /// The first argument (excluding argument 0, the exe itself) is a path that's
     /// present in the root filesystem, the argument.
+    #[serde(default)]
     pub first_arg_path: Option<PathBuf>,
+    /// The final component (file name) of the first argument path, if it's a path
+    /// that's present in the root filesystem. Like `first_arg_path` but ignores the
+    /// containing directory.
+    #[serde(default)]
+    pub first_arg_name: Option<String>,
+}
+
+/// Match a single process in a cgroup (from `/proc/<pid>/cgroup`, the unified
+/// hierarchy). Processes can only move themselves into cgroups they can write to,
+/// so this is reliable for cgroups owned by root (ex: system services).
+#[derive(Serialize, Deserialize, Clone, JsonSchema, Debug)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct MatchCgroup {
+    /// Sufficient if any ancestor up to this number of steps away from the process
+    /// doing IPC matches (excluding the process itself). Defaults to 0.
+    #[serde(default)]
+    pub walk_ancestors: usize,
+    /// The cgroup path, like `/system.slice/backup.service`. Processes in this cgroup
+    /// or any cgroup below it match.
+    pub path: String,
+}
+
+/// Match a single process running as part of a systemd unit, based on its cgroup.
+#[derive(Serialize, Deserialize, Clone, JsonSchema, Debug)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct MatchSystemdUnit {
+    /// Sufficient if any ancestor up to this number of steps away from the process
+    /// doing IPC matches (excluding the process itself). Defaults to 0.
+    #[serde(default)]
+    pub walk_ancestors: usize,
+    /// The full unit name, like `backup.service`. Matches processes in the unit's
+    /// cgroup (or below it) when the unit is a system unit, directly under system
+    /// slices like `/system.slice/backup.service`. Units run by user service managers
+    /// (under `user@<uid>.service`) never match.
+    pub unit: String,
+}
+
+/// Match a single process by namespace or container. At least one field other than
+/// `walk_ancestors` must be specified, and all specified fields must match.
+#[derive(Serialize, Deserialize, Clone, JsonSchema, Debug)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct MatchNamespace {
+    /// Sufficient if any ancestor up to this number of steps away from the process
+    /// doing IPC matches (excluding the process itself). Defaults to 0.
+    #[serde(default)]
+    pub walk_ancestors: usize,
+    /// The container machine name, as registered with `systemd-machined` (the process
+    /// is in `/machine.slice/machine-<name>.scope`).
+    #[serde(default)]
+    pub machine: Option<String>,
+    /// The container manager, from the `container` environment variable of the process
+    /// (ex: `systemd-nspawn`, `podman`). Any process can set this, so it isn't a
+    /// security boundary on its own: it's only allowed together with `machine`, `mnt`,
+    /// or `pid`.
+    #[serde(default)]
+    pub container_manager: Option<String>,
+    /// Mount namespace inode (as in `/proc/<pid>/ns/mnt`).
+    #[serde(default)]
+    pub mnt: Option<u64>,
+    /// Pid namespace inode (as in `/proc/<pid>/ns/pid`).
+    #[serde(default)]
+    pub pid: Option<u64>,
+    /// User namespace inode (as in `/proc/<pid>/ns/user`).
+    #[serde(default)]
+    pub user: Option<u64>,
 }
 
 /// Actions permitted by a rule. Later levels include all prior levels.
@@ -152,13 +288,64 @@ pub struct ConfigPermissionRule {
     /// This only applies to binaries available in the root filesystem namespace.
     #[serde(default)]
     pub match_binary: Option<MatchBinary>,
+    /// Match requesting processes against the process or a parent process cgroup.
+    #[serde(default)]
+    pub match_cgroup: Option<MatchCgroup>,
+    /// Match requesting processes against the systemd unit of the process or a parent
+    /// process.
+    #[serde(default)]
+    pub match_systemd_unit: Option<MatchSystemdUnit>,
+    /// Match requesting processes against the namespaces or container of the process or
+    /// a parent process.
+    #[serde(default)]
+    pub match_namespace: Option<MatchNamespace>,
     /// Permission to explicitly lock or unlock.
     pub permit: PermitLevel,
-    /// Configure if access requires prompting.
+    /// Instead of granting `permit`, deny `permit` and all higher levels to matching
+    /// processes. Denies override grants from any other rule, regardless of order, and
+    /// apply to the rule's paths and everything below them. Since requesting a path
+    /// includes its descendants, a deny on a descendant also restricts requests on
+    /// its ancestors.
+    #[serde(default)]
+    pub deny: bool,
+    /// Configure if access requires prompting. Ignored for deny rules.
     #[serde(default)]
     pub prompt: Option<ConfigPrompt>,
 }
 
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ConfigFrontendTty {
+    /// The terminal device to use. Defaults to `/dev/tty`, the server's controlling
+    /// terminal.
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema, Default)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum ConfigFrontend {
+    /// Show windows (requires a Wayland session with layer shell support).
+    #[default]
+    Gtk,
+    /// Ask questions in a terminal, for headless machines or running over ssh (ex: in
+    /// `tmux`).
+    Tty(ConfigFrontendTty),
+}
+
+/// Serve an `ssh-agent` compatible socket that signs with ssh keys in the store,
+/// without handing out the private keys.
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ConfigSshAgent {
+    /// Where to create the agent socket. Point `SSH_AUTH_SOCK` here.
+    pub socket_path: PathBuf,
+    /// Paths of OpenSSH PEM private keys to offer, in the `/seg/seg/.../seg` format.
+    /// Listing a key requires `meta` permission on its path, signing with it requires
+    /// `derive`.
+    pub keys: Vec<String>,
+}
+
 #[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub struct UnlockFactorsConfig {
@@ -182,4 +369,10 @@ pub struct Config {
     pub lock_timeout: u64,
     /// Permissions for processes to access subtrees.
     pub access: Vec<ConfigPermissionRule>,
+    /// Optionally run an ssh agent.
+    #[serde(default)]
+    pub ssh_agent: Option<ConfigSshAgent>,
+    /// How to interact with the user for unlocking and prompts. Defaults to GTK.
+    #[serde(default)]
+    pub frontend: ConfigFrontend,
 }
@@ -3,6 +3,7 @@ use {
         ToUiErr,
         UiErr,
     },
+    argon2::Argon2,
     card_backend_pcsc::PcscBackend,
     chacha20poly1305::{
         aead::Aead,
@@ -19,6 +20,10 @@ use {
         state::Open,
         PublicKey,
     },
+    rand::{
+        rng,
+        RngCore,
+    },
     sequoia_openpgp::{
         parse::{
             stream::DecryptorBuilder,
@@ -106,6 +111,103 @@ fn test_local_crypt() {
     assert_eq!(String::from_utf8(body.to_vec()).unwrap(), String::from_utf8(decrypted).unwrap());
 }
 
+// Argon2id parameters for new (and upgraded) factors: 64MiB, 3 passes, 4 lanes
+pub const ARGON2_M_COST: u32 = 64 * 1024;
+pub const ARGON2_T_COST: u32 = 3;
+pub const ARGON2_P_COST: u32 = 4;
+
+/// Key derivation for low-entropy secrets (passwords, recovery phrases).
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Kdf {
+    Argon2id {
+        salt: Vec<u8>,
+        m_cost: u32,
+        t_cost: u32,
+        p_cost: u32,
+    },
+}
+
+impl Kdf {
+    /// Current parameters, with a new random salt.
+    pub fn new() -> Kdf {
+        let mut salt = vec![0u8; 16];
+        rng().fill_bytes(&mut salt);
+        return Kdf::Argon2id {
+            salt: salt,
+            m_cost: ARGON2_M_COST,
+            t_cost: ARGON2_T_COST,
+            p_cost: ARGON2_P_COST,
+        };
+    }
+
+    /// False if the parameters are weaker than those used by `new`.
+    pub fn is_current(&self) -> bool {
+        match self {
+            Kdf::Argon2id { salt: _, m_cost, t_cost, p_cost } => {
+                return *m_cost >= ARGON2_M_COST && *t_cost >= ARGON2_T_COST && *p_cost >= ARGON2_P_COST;
+            },
+        }
+    }
+
+    /// Blocking, intentionally slow.
+    pub fn derive(&self, secret: &[u8]) -> Result<Vec<u8>, loga::Error> {
+        match self {
+            Kdf::Argon2id { salt, m_cost, t_cost, p_cost } => {
+                let params =
+                    argon2::Params::new(*m_cost, *t_cost, *p_cost, Some(32))
+                        .map_err(|e| loga::err(e.to_string()))
+                        .context("Invalid Argon2 parameters")?;
+                let mut out = vec![0u8; 32];
+                Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+                    .hash_password_into(secret, salt, &mut out)
+                    .map_err(|e| loga::err(e.to_string()))
+                    .context("Error deriving key from secret")?;
+                return Ok(out);
+            },
+        }
+    }
+}
+
+/// Factor state for secrets the user enters. The factor token is encrypted with a key
+/// derived from the secret, so the KDF parameters can be changed without changing
+/// the token.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct WrappedToken {
+    pub kdf: Kdf,
+    /// `local_encrypt` output
+    pub token: Vec<u8>,
+}
+
+/// Blocking.
+pub fn wrap_token(kdf: Kdf, secret: &[u8], token: &[u8]) -> Result<WrappedToken, loga::Error> {
+    let key = kdf.derive(secret)?;
+    return Ok(WrappedToken {
+        kdf: kdf,
+        token: local_encrypt(&key, token),
+    });
+}
+
+/// Blocking. Returns `None` if the secret is wrong.
+pub fn unwrap_token(wrapped: &WrappedToken, secret: &[u8]) -> Result<Option<Vec<u8>>, loga::Error> {
+    return local_decrypt(&wrapped.kdf.derive(secret)?, &wrapped.token);
+}
+
+#[test]
+fn test_wrap_token() {
+    let kdf = Kdf::Argon2id {
+        salt: vec![7u8; 16],
+        m_cost: 64,
+        t_cost: 1,
+        p_cost: 1,
+    };
+    assert!(!kdf.is_current());
+    let wrapped = wrap_token(kdf, b"hunter2", b"token").unwrap();
+    assert_eq!(unwrap_token(&wrapped, b"hunter2").unwrap(), Some(b"token".to_vec()));
+    assert_eq!(unwrap_token(&wrapped, b"hunter3").unwrap(), None);
+}
+
 pub struct CardStream {
     alive: Arc<AtomicBool>,
     rx: mpsc::Receiver<openpgp_card_sequoia::Card<Open>>,
@@ -5,6 +5,9 @@ pub enum UiErr {
     Internal(loga::Error),
     InternalUnresolvable(loga::Error),
     External(String, Option<loga::Error>),
+    /// The user entered something wrong (password, recovery phrase). Shown like
+    /// `External`, and counted as a failed unlock attempt.
+    Incorrect(String, Option<loga::Error>),
 }
 
 impl UiErr {
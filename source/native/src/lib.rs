@@ -2,3 +2,5 @@ pub mod config;
 pub mod generate;
 pub mod crypto;
 pub mod error;
+pub mod otp;
+pub mod tty;
@@ -0,0 +1,86 @@
+use {
+    loga::ResultContext,
+    totp_rs::{
+        Algorithm,
+        TOTP,
+    },
+    url::Url,
+};
+
+pub enum Otp {
+    /// Time based (including Steam), `otpauth://totp/...` or `otpauth://steam/...`.
+    Totp(TOTP),
+    /// Counter based, `otpauth://hotp/...`. The counter is the one to use for the next
+    /// token.
+    Hotp {
+        otp: TOTP,
+        counter: u64,
+    },
+}
+
+/// Parse an `otpauth://` url. `totp_rs` only understands time based urls, so hotp
+/// urls are parsed as totp with the counter pulled out; generating with a step of 1
+/// makes the "time" the counter.
+pub fn parse_otp_url(url: &str) -> Result<Otp, loga::Error> {
+    let mut parsed = Url::parse(url).context("Invalid url")?;
+    if parsed.host_str() == Some("hotp") {
+        let mut counter = None;
+        let mut query = vec![];
+        for (k, v) in parsed.query_pairs() {
+            if k == "counter" {
+                counter = Some(v.parse::<u64>().context("Invalid hotp counter")?);
+            } else {
+                query.push((k.into_owned(), v.into_owned()));
+            }
+        }
+        let counter = counter.context("Hotp url is missing counter")?;
+        parsed.set_host(Some("totp")).context("Error rewriting hotp url")?;
+        parsed.query_pairs_mut().clear().extend_pairs(query);
+        let mut otp = TOTP::from_url_unchecked(parsed.as_str()).context("Invalid hotp url")?;
+        otp.step = 1;
+        return Ok(Otp::Hotp {
+            otp: otp,
+            counter: counter,
+        });
+    } else {
+        let mut otp = TOTP::from_url_unchecked(url).context("Invalid totp url")?;
+        if otp.algorithm == Algorithm::Steam {
+            // Steam codes are always 5 characters, but `otpauth://steam/` urls don't set
+            // `digits`
+            otp.digits = 5;
+        }
+        return Ok(Otp::Totp(otp));
+    }
+}
+
+/// Replace the counter in an `otpauth://hotp/` url, preserving everything else.
+pub fn set_hotp_counter(url: &str, counter: u64) -> Result<String, loga::Error> {
+    let mut parsed = Url::parse(url).context("Invalid url")?;
+    let mut query = vec![];
+    for (k, v) in parsed.query_pairs() {
+        if k == "counter" {
+            query.push((k.into_owned(), counter.to_string()));
+        } else {
+            query.push((k.into_owned(), v.into_owned()));
+        }
+    }
+    parsed.query_pairs_mut().clear().extend_pairs(query);
+    return Ok(parsed.to_string());
+}
+
+#[test]
+fn test_hotp() {
+    // RFC 4226 appendix D, secret "12345678901234567890"
+    let url = "otpauth://hotp/Example:alice?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&counter=1&issuer=Example";
+    let Otp::Hotp { otp, counter } = parse_otp_url(url).unwrap() else {
+        panic!();
+    };
+    assert_eq!(counter, 1);
+    assert_eq!(otp.generate(counter), "287082");
+    let url = set_hotp_counter(url, counter + 1).unwrap();
+    let Otp::Hotp { otp, counter } = parse_otp_url(&url).unwrap() else {
+        panic!();
+    };
+    assert_eq!(counter, 2);
+    assert_eq!(otp.generate(counter), "359152");
+}
@@ -0,0 +1,58 @@
+//! Line-based terminal interaction, shared by the server's terminal frontend and
+//! the interactive CLI.
+use {
+    loga::{
+        ea,
+        DebugDisplay,
+        ResultContext,
+    },
+    std::{
+        fs::OpenOptions,
+        io::{
+            BufRead,
+            BufReader,
+            Write,
+        },
+        path::Path,
+    },
+};
+
+/// The controlling terminal of the current process.
+pub const DEFAULT_TTY: &str = "/dev/tty";
+
+/// Write `output` then, if `input` is `Some`, read a line (without echo if
+/// `Some(true)`). Returns `None` at end of input. Blocking.
+pub fn tty_io(path: &Path, output: &str, input: Option<bool>) -> Result<Option<String>, loga::Error> {
+    let mut tty =
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .context_with("Error opening terminal", ea!(path = path.dbg_str()))?;
+    tty.write_all(output.as_bytes()).context("Error writing to terminal")?;
+    tty.flush().context("Error writing to terminal")?;
+    let Some(secret) = input else {
+        return Ok(None);
+    };
+    let restore = if secret {
+        let termios = rustix::termios::tcgetattr(&tty).context("Error getting terminal attributes")?;
+        let mut no_echo = termios.clone();
+        no_echo.local_modes.remove(rustix::termios::LocalModes::ECHO);
+        rustix::termios::tcsetattr(&tty, rustix::termios::OptionalActions::Flush, &no_echo)
+            .context("Error disabling terminal echo")?;
+        Some(termios)
+    } else {
+        None
+    };
+    let mut line = String::new();
+    let res = BufReader::new(&tty).read_line(&mut line);
+    if let Some(termios) = restore {
+        rustix::termios::tcsetattr(&tty, rustix::termios::OptionalActions::Flush, &termios)
+            .context("Error restoring terminal echo")?;
+        tty.write_all(b"\n").context("Error writing to terminal")?;
+    }
+    if res.context("Error reading from terminal")? == 0 {
+        return Ok(None);
+    }
+    return Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()));
+}
@@ -49,6 +49,8 @@ rust.naersk.buildPackage {
     pkgs.nettle
     pkgs.pcsclite
     pkgs.openssl
+    pkgs.tpm2-tss
+    pkgs.udev
     layershell
   ];
   postInstall =
@@ -59,6 +61,8 @@ rust.naersk.buildPackage {
         pkgs.gmp
         pkgs.bzip2
         pkgs.pcsclite
+        pkgs.tpm2-tss
+        pkgs.udev
         pkgs.gtk4
         pkgs.pango
         pkgs.glib
@@ -1,4 +1,9 @@
 use {
+    loga::{
+        ea,
+        DebugDisplay,
+        ResultContext,
+    },
     passworth::ipc,
     std::{
         env,
@@ -17,8 +22,34 @@ pub fn ipc_path() -> PathBuf {
     }
 }
 
-pub async fn req<T: ipc::msg::ReqTrait>(body: T) -> Result<T::Resp, loga::Error> {
+/// If set (to anything), requests fail if the server is locked instead of unlocking
+/// it.
+pub const ENV_NO_UNLOCK: &str = "PASSWORTH_NO_UNLOCK";
+
+/// Send a request on an open connection. Errors reported by the server are returned
+/// separately so callers can react to the error code.
+pub async fn try_client_req<
+    T: ipc::msg::ReqTrait,
+>(client: &mut ipc::msg::Client, body: T) -> Result<Result<T::Resp, ipc::RespError>, loga::Error> {
+    let resp = client.send_req_enum(&body.to_enum()).await.map_err(loga::err)?;
     return Ok(
-        ipc::msg::Client::new(ipc_path()).await.map_err(loga::err)?.send_req(body).await.map_err(loga::err)?,
+        ipc::decode_resp(
+            &resp,
+        ).context_with("Received invalid JSON response", ea!(resp = String::from_utf8_lossy(&resp)))?,
     );
 }
+
+pub async fn client_req<T: ipc::msg::ReqTrait>(client: &mut ipc::msg::Client, body: T) -> Result<T::Resp, loga::Error> {
+    match try_client_req(client, body).await? {
+        Ok(v) => return Ok(v),
+        Err(e) => return Err(loga::err_with(e.message, ea!(code = e.code.dbg_str()))),
+    }
+}
+
+pub async fn req<T: ipc::msg::ReqTrait>(body: T) -> Result<T::Resp, loga::Error> {
+    let mut client = ipc::msg::Client::new(ipc_path()).await.map_err(loga::err)?;
+    if env::var_os(ENV_NO_UNLOCK).is_some() {
+        client_req(&mut client, ipc::ReqNoUnlock).await?;
+    }
+    return client_req(&mut client, body).await;
+}
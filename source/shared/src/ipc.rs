@@ -3,9 +3,11 @@ use {
     glove::reqresp,
     schemars::JsonSchema,
     serde::{
+        de::DeserializeOwned,
         Deserialize,
         Serialize,
     },
+    std::fmt::Display,
 };
 
 /// Register a process with the demon with the listed tags. The tags can be matched
@@ -25,6 +27,59 @@ pub enum LockAction {
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub struct ReqLock(pub LockAction);
 
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum FactorChange {
+    /// Replace the password of a `password` factor. The new password is asked for by
+    /// the unlock frontend.
+    Password,
+    /// Generate and show a new phrase for a `recovery_code` factor, replacing the old
+    /// one.
+    RecoveryPhrase,
+    /// Allow another smartcard, by GPG fingerprint, to unlock a `smartcards` factor.
+    /// The card must be inserted during the change.
+    AddSmartcard(String),
+    /// Stop allowing a smartcard that was added with `add_smartcard` to unlock a
+    /// `smartcards` factor. Cards listed in the config must be removed from the config
+    /// instead.
+    RemoveSmartcard(String),
+}
+
+/// Change the credentials of an unlock factor without editing the config. The
+/// factor must be unlocked again first, even if the database is unlocked. The
+/// factor's token stays the same so the database isn't rekeyed. Requires `lock`
+/// permission on the root.
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ReqFactorChange {
+    /// The factor id, as in the config.
+    pub factor: String,
+    pub change: FactorChange,
+}
+
+/// For the rest of the connection, fail requests that need the database with a
+/// `Locked` error if it's locked, rather than starting an unlock or waiting for one
+/// in progress. Explicit unlock requests still unlock. Any client can send this.
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ReqNoUnlock;
+
+/// Get whether the server is locked without unlocking it. Requires `meta`
+/// permission on the root.
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ReqMetaStatus;
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct RespMetaStatus {
+    pub locked: bool,
+    /// An unlock was started and is waiting for the user.
+    pub unlocking: bool,
+    /// If unlocked, the number of seconds until the server locks due to inactivity.
+    pub lock_timeout_seconds: Option<u64>,
+}
+
 #[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub struct ReqRead {
@@ -60,9 +115,81 @@ pub struct ReqMetaSshPubkey {
     pub at: Option<i64>,
 }
 
+/// Query the audit log of permission decisions. Requires `meta` permission on the
+/// queried paths (or the root, if no paths are specified).
+/// Entries only list paths the requester has `meta` on; entries touching none are
+/// left out, and entries touching some hidden paths omit the principal.
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ReqMetaAudit {
+    /// Only return entries touching these paths or their descendants. All entries if
+    /// empty.
+    pub paths: Vec<SpecificPath>,
+    /// Only return entries at or after this time (RFC 3339).
+    pub start: Option<String>,
+    /// Only return entries at or before this time (RFC 3339).
+    pub end: Option<String>,
+    /// Only return entries where a process in the requester's process chain was
+    /// running this binary.
+    pub binary: Option<String>,
+}
+
+/// A condition on the current data, checked in the same transaction as the write.
+/// If it doesn't hold the request fails with a conflict error and nothing is
+/// written.
 #[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
-pub struct ReqWrite(pub Vec<(SpecificPath, serde_json::Value)>);
+pub enum WritePrecondition {
+    /// Nothing is stored at, under, or above the path.
+    Absent,
+    /// The latest revision of anything at or under the path has this `rev_stamp`
+    /// (RFC 3339, as returned by `ReqMetaRevisions`).
+    RevStamp(String),
+}
+
+/// The bare list of values (the format before preconditions were added) is also
+/// accepted.
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields, from = "ReqWriteCompat")]
+pub struct ReqWrite {
+    pub values: Vec<(SpecificPath, serde_json::Value)>,
+    #[serde(default)]
+    pub preconditions: Vec<(SpecificPath, WritePrecondition)>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+struct ReqWriteFields {
+    values: Vec<(SpecificPath, serde_json::Value)>,
+    #[serde(default)]
+    preconditions: Vec<(SpecificPath, WritePrecondition)>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ReqWriteCompat {
+    Values(Vec<(SpecificPath, serde_json::Value)>),
+    Fields(ReqWriteFields),
+}
+
+impl From<ReqWriteCompat> for ReqWrite {
+    fn from(value: ReqWriteCompat) -> Self {
+        match value {
+            ReqWriteCompat::Values(values) => {
+                return ReqWrite {
+                    values: values,
+                    preconditions: vec![],
+                };
+            },
+            ReqWriteCompat::Fields(fields) => {
+                return ReqWrite {
+                    values: fields.values,
+                    preconditions: fields.preconditions,
+                };
+            },
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
@@ -70,6 +197,8 @@ pub struct ReqWriteMove {
     pub from: SpecificPath,
     pub to: SpecificPath,
     pub overwrite: bool,
+    #[serde(default)]
+    pub preconditions: Vec<(SpecificPath, WritePrecondition)>,
 }
 
 #[derive(Serialize, Deserialize, Clone, JsonSchema)]
@@ -113,6 +242,8 @@ pub struct ReqWriteGenerate {
     pub path: SpecificPath,
     pub variant: C2SGenerateVariant,
     pub overwrite: bool,
+    #[serde(default)]
+    pub preconditions: Vec<(SpecificPath, WritePrecondition)>,
 }
 
 #[derive(Serialize, Deserialize, Clone, JsonSchema)]
@@ -122,6 +253,35 @@ pub struct ReqWriteRevert {
     pub at: i64,
 }
 
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum BatchOp {
+    Read(ReqRead),
+    Write(ReqWrite),
+    WriteMove(ReqWriteMove),
+    WriteGenerate(ReqWriteGenerate),
+    WriteRevert(ReqWriteRevert),
+}
+
+/// Run several read and write operations in order in a single transaction. If any
+/// operation fails none of the writes are applied. Permissions are checked for all
+/// paths up front with a single prompt: `write` if any operation writes, otherwise
+/// `read`.
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ReqBatch(pub Vec<BatchOp>);
+
+/// Per-operation results of a batch, in the same order as the operations.
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum BatchOpResult {
+    Read(serde_json::Value),
+    Write,
+    WriteMove,
+    WriteGenerate,
+    WriteRevert,
+}
+
 #[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub struct ReqDerivePgpSign {
@@ -136,28 +296,264 @@ pub struct ReqDerivePgpDecrypt {
     pub data: Vec<u8>,
 }
 
+/// Produce an armored `SSHSIG` signature (as used by `ssh-keygen -Y sign`) on data
+/// with an OpenSSH private key.
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ReqDeriveSshSign {
+    pub key: SpecificPath,
+    /// Signature domain, like `git` or `file`. Verifiers must use the same namespace.
+    pub namespace: String,
+    pub data: Vec<u8>,
+}
+
 #[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub struct ReqDeriveOtp {
     pub key: SpecificPath,
 }
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct RespDeriveOtp {
+    pub token: String,
+    /// For time based (totp, steam) tokens, the number of seconds until the token
+    /// expires.
+    pub remaining_seconds: Option<u64>,
+    /// For counter based (hotp) tokens, the counter used to generate the token. The
+    /// stored counter is incremented past this.
+    pub counter: Option<u64>,
+}
+
+/// Make this connection the handler for user interactions (unlocking, access
+/// prompts) instead of the server's own frontend, until the connection closes.
+/// Requires `lock` permission on the root from rules without prompts. Only one
+/// connection can be registered at a time. Access prompts for the handler's own
+/// process (or processes it started) still go to the frontend.
 #[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
-pub struct ReqWatch;
+pub struct ReqUiRegister;
+
+/// Wait for the next interaction. The connection must be registered with
+/// `ReqUiRegister`.
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ReqUiNext;
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct UiQuestionPrompt {
+    /// Descriptions of the rules requiring confirmation.
+    pub rules: Vec<String>,
+    /// The request type (ex: `read`)
+    pub variant: String,
+    pub paths: Vec<SpecificPath>,
+    /// Summary of the requesting process chain
+    pub principal: String,
+    /// The duration of the `allow_for` and `deny_for` answers.
+    pub grant_seconds: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum UiQuestionVariant {
+    /// Answer with `choice`, the index of a method.
+    Choose(Vec<String>),
+    /// Answer with `secret`.
+    Password(String),
+    /// Answer with `secret`. The client should have the user confirm the password.
+    NewPassword(String),
+    /// Show a newly generated recovery phrase for the user to write down. Answer with
+    /// `done`.
+    ShowRecoveryPhrase(Vec<String>),
+    /// Answer with `secret`, the words separated by spaces.
+    RecoveryPhrase(String),
+    /// Answer with `secret`.
+    SmartcardPin,
+    /// Ask the user to insert or swipe a smartcard with one of these fingerprints.
+    /// Informational; the card is read by the server. Answering (with anything)
+    /// cancels.
+    GetSmartcard(Vec<String>),
+    /// Ask the user to confirm the action on the smartcard with this fingerprint.
+    /// Informational, answering cancels.
+    SmartcardTouch(String),
+    /// Ask the user to touch the FIDO2 security key for the factor with this
+    /// description. Informational, answering cancels.
+    Fido2Touch(String),
+    /// Ask the user to make the keyfile at this path available (ex: by inserting the
+    /// drive it's on). Informational; the file is read by the server. Answering
+    /// cancels.
+    GetKeyfile(String),
+    /// Tell the user unlocking is delayed this many seconds due to repeated failed
+    /// attempts. Informational, answering cancels.
+    Backoff(u64),
+    /// Confirm a request. Answer with `prompt`.
+    Prompt(UiQuestionPrompt),
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct UiQuestion {
+    pub id: u64,
+    pub title: String,
+    /// The error from the previous answer, if any.
+    pub warning: Option<String>,
+    pub variant: UiQuestionVariant,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum UiPromptDecision {
+    AllowOnce,
+    /// Allow for `grant_seconds`.
+    AllowFor,
+    /// Allow until the requesting process exits.
+    AllowProcess,
+    Deny,
+    /// Deny for `grant_seconds`.
+    DenyFor,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum UiAnswer {
+    Choice(usize),
+    Secret(String),
+    Done,
+    Prompt(UiPromptDecision),
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ReqUiAnswer {
+    pub id: u64,
+    /// `None` cancels the interaction.
+    pub answer: Option<UiAnswer>,
+}
+
+/// Start watching for changes to values under (or above) these paths, and for the
+/// server locking and unlocking. Events are queued from this point on; use
+/// `ReqWatchNext` to get them. Watching again replaces the paths. Requires `meta`
+/// permission on the paths, or on the root if no paths are specified (only lock
+/// and unlock events). The watch ends when the connection closes.
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ReqWatch {
+    pub paths: Vec<SpecificPath>,
+}
+
+/// Wait for the next event. The connection must be watching with `ReqWatch`.
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ReqWatchNext;
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum WatchEvent {
+    /// Values at these paths were written, moved, or reverted. Only paths overlapping
+    /// the watched paths are included.
+    Changed(Vec<SpecificPath>),
+    Locked,
+    Unlocked,
+    /// The client fell too far behind and some events were dropped. Re-read
+    /// everything being watched.
+    Missed,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum ErrorCode {
+    /// No rule grants the permission the request needs.
+    Unauthorized,
+    /// The server is locked and the connection asked not to unlock it, or unlocking
+    /// was cancelled or failed.
+    Locked,
+    /// The user was prompted to allow the request and rejected it.
+    UserRejectedPrompt,
+    /// There's no value at the path, or no factor etc. with the id.
+    NotFound,
+    /// The value at the path isn't what the request needs (ex: not a key).
+    WrongType,
+    /// A write precondition didn't hold, or the write would replace existing data.
+    Conflict,
+    /// The request doesn't make sense, regardless of the stored data.
+    InvalidArgument,
+    /// Anything else. Details are in the server log.
+    Internal,
+}
+
+/// Failed responses carry this, JSON encoded, as their error text.
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct RespError {
+    pub code: ErrorCode,
+    /// A human readable description, safe to show to the client.
+    pub message: String,
+}
+
+impl RespError {
+    pub fn new(code: ErrorCode, message: impl ToString) -> Self {
+        return Self {
+            code: code,
+            message: message.to_string(),
+        };
+    }
+
+    /// Encode as response error text.
+    pub fn to_resp(&self) -> String {
+        return serde_json::to_string(self).unwrap();
+    }
+
+    /// Decode response error text. Unstructured errors (from older servers, or
+    /// proxies) are treated as internal errors.
+    pub fn from_resp(text: &str) -> Self {
+        match serde_json::from_str(text) {
+            Ok(e) => return e,
+            Err(_) => return Self::new(ErrorCode::Internal, text),
+        }
+    }
+}
+
+impl Display for RespError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return write!(f, "{}", self.message);
+    }
+}
+
+/// Decode a raw response (as returned by `send_req_enum`), separating errors
+/// reported by the server.
+pub fn decode_resp<T: DeserializeOwned>(resp: &[u8]) -> Result<Result<T, RespError>, serde_json::Error> {
+    match serde_json::from_slice::<glove::Resp<T>>(resp)? {
+        glove::Resp::Ok(v) => return Ok(Ok(v)),
+        glove::Resp::Err(e) => return Ok(Err(RespError::from_resp(&e))),
+    }
+}
 
 reqresp!(pub msg {
     Tag(ReqTag) =>(),
     Lock(ReqLock) =>(),
+    FactorChange(ReqFactorChange) =>(),
+    NoUnlock(ReqNoUnlock) =>(),
+    MetaStatus(ReqMetaStatus) => RespMetaStatus,
     MetaKeys(ReqMetaKeys) => serde_json:: Value,
     MetaRevisions(ReqMetaRevisions) => serde_json:: Value,
     MetaPgpPubkey(ReqMetaPgpPubkey) => String,
     MetaSshPubkey(ReqMetaSshPubkey) => String,
+    MetaAudit(ReqMetaAudit) => serde_json:: Value,
     Read(ReqRead) => serde_json:: Value,
     Write(ReqWrite) =>(),
     WriteMove(ReqWriteMove) =>(),
     WriteGenerate(ReqWriteGenerate) =>(),
     WriteRevert(ReqWriteRevert) =>(),
+    Batch(ReqBatch) => Vec < BatchOpResult >,
     DerivePgpSign(ReqDerivePgpSign) => String,
     DerivePgpDecrypt(ReqDerivePgpDecrypt) => Vec < u8 >,
-    DeriveOtp(ReqDeriveOtp) => String,
+    DeriveSshSign(ReqDeriveSshSign) => String,
+    DeriveOtp(ReqDeriveOtp) => RespDeriveOtp,
+    UiRegister(ReqUiRegister) =>(),
+    UiNext(ReqUiNext) => UiQuestion,
+    UiAnswer(ReqUiAnswer) =>(),
+    Watch(ReqWatch) =>(),
+    WatchNext(ReqWatchNext) => WatchEvent,
 });
+
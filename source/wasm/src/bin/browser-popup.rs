@@ -231,7 +231,7 @@ async fn send_to_native<T: ipc::msg::ReqTrait>(message: T) -> Result<T::Resp, St
             format!(
                 "Error making request to native messaging host [{}]: {}",
                 serde_json::to_string(&message).unwrap(),
-                v
+                ipc::RespError::from_resp(&v)
             ),
         ),
     }
@@ -357,7 +357,7 @@ fn update(state: &Rc<State>, tree_root_el: &El, messages_el: &El, raw_path: Stri
                                     async move {
                                         save_search_path(&state);
                                         let resp = send_to_native(ipc::ReqDeriveOtp { key: path }).await?;
-                                        send_to_content(ToContent::FillField(ToContentField { text: resp })).await?;
+                                        send_to_content(ToContent::FillField(ToContentField { text: resp.token })).await?;
                                         window().close().unwrap();
                                         return Ok(());
                                     }